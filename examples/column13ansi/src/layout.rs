@@ -5,13 +5,15 @@
 extern crate xiao_m0 as hal;
 extern crate paste;
 
-use makbe_ff::key_switch::KeySwitch;
+use makbe_ff::key_switch::{KeySwitch, KeySwitches};
 use makbe_ff::switch_pool;
 use makbe_ff::device::{Device, DeviceHolder};
+use makbe_ff::keymap::{Keymap, VirtualMatrix};
+use cortex_m::singleton;
 use makbe_ff::devices::tca9555::TCA9555;
 use keyberon::key_code::KeyCode::*;
-use keyberon::action::{k, l, Action};
-use keyberon::action::Action::HoldTap;
+use makbe_ff::action::{k, l, Action, HoldTapConfig};
+use makbe_ff::action::Action::HoldTap;
 use xiao_m0::sercom::{I2CError, I2CMaster2, Sercom2Pad0, Sercom2Pad1};
 use xiao_m0::gpio::{Pa8, Pa9, PfD};
 
@@ -26,30 +28,40 @@ const OPT_EISU: Action = HoldTap {
     timeout: 200,
     hold: &k(LAlt),
     tap: &k(Lang2),
+    config: HoldTapConfig::PermissiveHold,
+    tap_hold_interval: 150,
 };
 
 const LOWER_SPACE: Action = HoldTap {
     timeout: 200,
     hold: &l(LOWER),
     tap: &k(Space),
+    config: HoldTapConfig::PermissiveHold,
+    tap_hold_interval: 150,
 };
 
 const SHIFT_SPACE: Action = HoldTap {
     timeout: 200,
     hold: &k(RShift),
     tap: &k(Space),
+    config: HoldTapConfig::PermissiveHold,
+    tap_hold_interval: 150,
 };
 
 const RAISE_KANA: Action = HoldTap {
     timeout: 200,
     hold: &l(RAISE),
     tap: &k(Lang1),
+    config: HoldTapConfig::PermissiveHold,
+    tap_hold_interval: 150,
 };
 
 const FUNCS_TAB: Action = HoldTap {
     timeout: 200,
     hold: &l(FUNCS),
     tap: &k(Tab),
+    config: HoldTapConfig::TapPreferred,
+    tap_hold_interval: 0,
 };
 
 switch_pool!(
@@ -110,39 +122,25 @@ switch_pool!(
 
 type I2CMaster = I2CMaster2<Sercom2Pad0<Pa8<PfD>>, Sercom2Pad1<Pa9<PfD>>>;
 
-static mut SWITCH_POOL: Option<SwitchPool> = None;
-static mut DEVICE0: Option<TCA9555<I2CMaster, I2CError>> = None;
-static mut DEVICE1: Option<TCA9555<I2CMaster, I2CError>> = None;
-static mut DEVICE2: Option<TCA9555<I2CMaster, I2CError>> = None;
-static mut DEVICE3: Option<TCA9555<I2CMaster, I2CError>> = None;
-
-
 pub struct Layout {
     pub device0: &'static TCA9555<I2CMaster, I2CError>,
     pub device1: &'static TCA9555<I2CMaster, I2CError>,
     pub device2: &'static TCA9555<I2CMaster, I2CError>,
     pub device3: &'static TCA9555<I2CMaster, I2CError>,
+    switches: KeySwitches
 }
 
-static mut LAYOUT: Option<Layout> = None;
-
 impl Layout {
 
     pub fn new() -> Self {
-        unsafe {
-            SWITCH_POOL = Some(SwitchPool::new());
-            let switches = SWITCH_POOL.as_ref().unwrap();
-            DEVICE0 = Some(Self::dev0(switches));
-            DEVICE1 = Some(Self::dev1(switches));
-            DEVICE2 = Some(Self::dev2(switches));
-            DEVICE3 = Some(Self::dev3(switches));
-            let layout = Self {
-                device0: DEVICE0.as_ref().unwrap(),
-                device1: DEVICE1.as_ref().unwrap(),
-                device2: DEVICE2.as_ref().unwrap(),
-                device3: DEVICE3.as_ref().unwrap(),
-            };
-            layout
+        let mut switches = KeySwitches::new();
+        let pool = SwitchPool::new(&mut switches).unwrap();
+        Self {
+            device0: singleton!(: TCA9555<I2CMaster, I2CError> = Self::dev0(&pool)).unwrap(),
+            device1: singleton!(: TCA9555<I2CMaster, I2CError> = Self::dev1(&pool)).unwrap(),
+            device2: singleton!(: TCA9555<I2CMaster, I2CError> = Self::dev2(&pool)).unwrap(),
+            device3: singleton!(: TCA9555<I2CMaster, I2CError> = Self::dev3(&pool)).unwrap(),
+            switches
         }
     }
 
@@ -154,82 +152,82 @@ impl Layout {
         switch
     }
 
-    unsafe fn dev0(switches: &'static SwitchPool) -> TCA9555<I2CMaster, I2CError> {
-        let mut device = TCA9555::new(0x0, 200);
+    fn dev0(switches: &SwitchPool) -> TCA9555<I2CMaster, I2CError> {
+        let mut device = TCA9555::new(0x0, 5);
 
-        device.assign(0, &switches.escape);
-        device.assign(1, &switches.q);
-        device.assign(2, &switches.w);
-        device.assign(3, &switches.e);
-        device.assign(4, &switches.r);
-        device.assign(5, &switches.t);
+        device.assign(0, switches.escape);
+        device.assign(1, switches.q);
+        device.assign(2, switches.w);
+        device.assign(3, switches.e);
+        device.assign(4, switches.r);
+        device.assign(5, switches.t);
 
-        device.assign(8, &switches.tab);
-        device.assign(9, &switches.a);
-        device.assign(10, &switches.s);
-        device.assign(11, &switches.d);
-        device.assign(12, &switches.f);
-        device.assign(13, &switches.g);
+        device.assign(8, switches.tab);
+        device.assign(9, switches.a);
+        device.assign(10, switches.s);
+        device.assign(11, switches.d);
+        device.assign(12, switches.f);
+        device.assign(13, switches.g);
 
         device
     }
 
-    unsafe fn dev1(switches: &'static SwitchPool) -> TCA9555<I2CMaster, I2CError> {
-        let mut device = TCA9555::new(0x1, 200);
+    fn dev1(switches: &SwitchPool) -> TCA9555<I2CMaster, I2CError> {
+        let mut device = TCA9555::new(0x1, 5);
 
-        device.assign(0, &switches.y);
-        device.assign(1, &switches.u);
-        device.assign(2, &switches.i);
-        device.assign(3, &switches.o);
-        device.assign(4, &switches.p);
-        device.assign(5, &switches.minus);
-        device.assign(6, &switches.b_space);
+        device.assign(0, switches.y);
+        device.assign(1, switches.u);
+        device.assign(2, switches.i);
+        device.assign(3, switches.o);
+        device.assign(4, switches.p);
+        device.assign(5, switches.minus);
+        device.assign(6, switches.b_space);
 
-        device.assign(8, &switches.h);
-        device.assign(9, &switches.j);
-        device.assign(10, &switches.k);
-        device.assign(11, &switches.l);
-        device.assign(12, &switches.s_colon);
-        device.assign(13, &switches.enter);
+        device.assign(8, switches.h);
+        device.assign(9, switches.j);
+        device.assign(10, switches.k);
+        device.assign(11, switches.l);
+        device.assign(12, switches.s_colon);
+        device.assign(13, switches.enter);
 
         device
     }
 
-    unsafe fn dev2(switches: &'static SwitchPool) -> TCA9555<I2CMaster, I2CError> {
-        let mut device = TCA9555::new(0x2, 200);
+    fn dev2(switches: &SwitchPool) -> TCA9555<I2CMaster, I2CError> {
+        let mut device = TCA9555::new(0x2, 5);
 
-        device.assign(0, &switches.l_shift);
-        device.assign(1, &switches.z);
-        device.assign(2, &switches.x);
-        device.assign(3, &switches.c);
-        device.assign(4, &switches.v);
-        device.assign(5, &switches.b);
+        device.assign(0, switches.l_shift);
+        device.assign(1, switches.z);
+        device.assign(2, switches.x);
+        device.assign(3, switches.c);
+        device.assign(4, switches.v);
+        device.assign(5, switches.b);
 
-        device.assign(8, &switches.l_ctrl);
-        device.assign(9, &switches.l_cmd);
-        device.assign(10, &switches.delete);
-        device.assign(11, &switches.l_opt);
-        device.assign(12, &switches.l_space);
+        device.assign(8, switches.l_ctrl);
+        device.assign(9, switches.l_cmd);
+        device.assign(10, switches.delete);
+        device.assign(11, switches.l_opt);
+        device.assign(12, switches.l_space);
 
         device
     }
 
-    unsafe fn dev3(switches: &'static SwitchPool) -> TCA9555<I2CMaster, I2CError> {
-        let mut device = TCA9555::new(0x3, 200);
+    fn dev3(switches: &SwitchPool) -> TCA9555<I2CMaster, I2CError> {
+        let mut device = TCA9555::new(0x3, 5);
 
-        device.assign(0, &switches.n);
-        device.assign(1, &switches.m);
-        device.assign(2, &switches.comma);
-        device.assign(3, &switches.dot);
-        device.assign(4, &switches.up);
-        device.assign(5, &switches.slash);
+        device.assign(0, switches.n);
+        device.assign(1, switches.m);
+        device.assign(2, switches.comma);
+        device.assign(3, switches.dot);
+        device.assign(4, switches.up);
+        device.assign(5, switches.slash);
 
-        device.assign(8, &switches.r_space);
-        device.assign(9, &switches.r_opt);
-        device.assign(10, &switches.app);
-        device.assign(11, &switches.left);
-        device.assign(12, &switches.down);
-        device.assign(13, &switches.right);
+        device.assign(8, switches.r_space);
+        device.assign(9, switches.r_opt);
+        device.assign(10, switches.app);
+        device.assign(11, switches.left);
+        device.assign(12, switches.down);
+        device.assign(13, switches.right);
 
         device
     }
@@ -244,13 +242,19 @@ impl Layout {
     pub fn device_holder(&self) -> DeviceHolder<I2CMaster, I2CError> {
         let mut holder = DeviceHolder::new();
 
-        unsafe {
-            holder.devices.push(DEVICE0.as_ref().unwrap());
-            holder.devices.push(DEVICE1.as_ref().unwrap());
-            holder.devices.push(DEVICE2.as_ref().unwrap());
-            holder.devices.push(DEVICE3.as_ref().unwrap());
-        }
+        holder.devices.push(self.device0);
+        holder.devices.push(self.device1);
+        holder.devices.push(self.device2);
+        holder.devices.push(self.device3);
 
         holder
     }
+
+    /// スイッチの表と、デバイスから作った仮想マトリクス（`Evaluator`に渡す）
+    ///
+    /// スイッチの表は1つしかないので、`Layout`はここで使い切る
+    pub fn keymap(self) -> Keymap {
+        let matrix = VirtualMatrix::from_devices(&self.device_holder());
+        Keymap::new(self.switches, matrix)
+    }
 }
@@ -1,8 +1,8 @@
-
 use keyberon::key_code::{KeyCode, KbHidReport};
-use makbe_ff::reporter::Reporter;
+use makbe_ff::reporter::{Reporter, ReportError};
 use xiao_m0::UsbBus;
 use usb_device::device::UsbDevice;
+use usb_device::UsbError;
 use keyberon::Class;
 use keyberon::keyboard::Leds;
 
@@ -15,13 +15,17 @@ pub struct UsbReporter<'a, L: Leds> {
 
 impl<L: Leds> Reporter for UsbReporter<'_, L> {
 
-    fn send_codes(&mut self, codes: &[KeyCode]) {
-        let mut report: KbHidReport = KbHidReport::default();   //codes.collect();
+    fn send_codes(&mut self, codes: &[KeyCode]) -> nb::Result<(), ReportError> {
+        let mut report: KbHidReport = KbHidReport::default();
         for kc in codes {
-            report.pressed(kc.clone());
+            report.pressed(*kc);
         }
-        if self.usb_class.device_mut().set_keyboard_report(report.clone()) {
-            while let Ok(0) = self.usb_class.write(report.as_bytes()) {}
+        self.usb_class.device_mut().set_keyboard_report(report.clone());
+        // 送れなかったら、次のtickでもう一度呼ばれる
+        match self.usb_class.write(report.as_bytes()) {
+            Ok(0) | Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
+            Ok(_) => Ok(()),
+            Err(_) => Err(nb::Error::Other(ReportError::Failed))
         }
     }
 }
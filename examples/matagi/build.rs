@@ -0,0 +1,7 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+fn main() {
+    makbe_ff::codegen::build("layout.json").unwrap();
+}
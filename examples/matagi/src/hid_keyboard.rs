@@ -0,0 +1,180 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # キーボードのHIDクラス
+//!
+//! ブートインターフェースで、レポートプロトコルのときはNKRO（ビットマップ）のレポートを送る。
+//! どちらのプロトコルにするかはホストが`SET_PROTOCOL`で決める
+
+use makbe_ff::keyboard::{KeyboardProtocol, NKRO_BITMAP_BYTES};
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+use usb_device::Result;
+
+const INTERFACE_CLASS_HID: u8 = 0x03;
+const SUBCLASS_BOOT: u8 = 0x01;
+const PROTOCOL_KEYBOARD: u8 = 0x01;
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+const REQUEST_GET_REPORT: u8 = 0x01;
+const REQUEST_GET_IDLE: u8 = 0x02;
+const REQUEST_GET_PROTOCOL: u8 = 0x03;
+const REQUEST_SET_REPORT: u8 = 0x09;
+const REQUEST_SET_IDLE: u8 = 0x0a;
+const REQUEST_SET_PROTOCOL: u8 = 0x0b;
+
+/// NKROのレポート（修飾キー、LED、0x00〜0xEFのビットマップ）
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,         // Usage Page (Generic Desktop)
+    0x09, 0x06,         // Usage (Keyboard)
+    0xA1, 0x01,         // Collection (Application)
+    0x05, 0x07,         //   Usage Page (Key Codes)
+    0x19, 0xE0,         //   Usage Minimum (224)
+    0x29, 0xE7,         //   Usage Maximum (231)
+    0x15, 0x00,         //   Logical Minimum (0)
+    0x25, 0x01,         //   Logical Maximum (1)
+    0x75, 0x01,         //   Report Size (1)
+    0x95, 0x08,         //   Report Count (8)
+    0x81, 0x02,         //   Input (Data, Variable, Absolute)
+    0x05, 0x08,         //   Usage Page (LEDs)
+    0x19, 0x01,         //   Usage Minimum (1)
+    0x29, 0x05,         //   Usage Maximum (5)
+    0x95, 0x05,         //   Report Count (5)
+    0x91, 0x02,         //   Output (Data, Variable, Absolute)
+    0x95, 0x01,         //   Report Count (1)
+    0x75, 0x03,         //   Report Size (3)
+    0x91, 0x01,         //   Output (Constant)
+    0x05, 0x07,         //   Usage Page (Key Codes)
+    0x19, 0x00,         //   Usage Minimum (0)
+    0x29, 0xEF,         //   Usage Maximum (239)
+    0x75, 0x01,         //   Report Size (1)
+    0x96, 0xF0, 0x00,   //   Report Count (240)
+    0x81, 0x02,         //   Input (Data, Variable, Absolute)
+    0xC0                // End Collection
+];
+
+pub struct KeyboardClass<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    endpoint: EndpointIn<'a, B>,
+    protocol: KeyboardProtocol,
+    protocol_changed: bool,
+    idle: u8,
+    /// 最後に送ったレポート（GET_REPORTで返す）
+    report: [u8; NKRO_BITMAP_BYTES + 1],
+    report_len: usize
+}
+
+impl<B: UsbBus> KeyboardClass<'_, B> {
+
+    pub fn new(alloc: &UsbBusAllocator<B>) -> KeyboardClass<'_, B> {
+        KeyboardClass {
+            interface: alloc.interface(),
+            endpoint: alloc.interrupt(32, 1),
+            protocol: KeyboardProtocol::Nkro,
+            protocol_changed: false,
+            idle: 0,
+            report: [0; NKRO_BITMAP_BYTES + 1],
+            report_len: 8
+        }
+    }
+
+    /// ホストが決めたプロトコル
+    pub fn protocol(&self) -> KeyboardProtocol {
+        self.protocol
+    }
+
+    /// 前に呼んだときからプロトコルが変わったか
+    pub fn take_protocol_change(&mut self) -> bool {
+        core::mem::replace(&mut self.protocol_changed, false)
+    }
+
+    /// レポートを送る（送れなければ`UsbError::WouldBlock`）
+    pub fn write(&mut self, report: &[u8]) -> Result<usize> {
+        let len = report.len().min(self.report.len());
+        self.report[..len].copy_from_slice(&report[..len]);
+        self.report_len = len;
+        self.endpoint.write(report)
+    }
+
+    fn set_protocol(&mut self, protocol: KeyboardProtocol) {
+        if self.protocol != protocol {
+            self.protocol = protocol;
+            self.protocol_changed = true;
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for KeyboardClass<'_, B> {
+
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.interface, INTERFACE_CLASS_HID, SUBCLASS_BOOT, PROTOCOL_KEYBOARD)?;
+        let len = (REPORT_DESCRIPTOR.len() as u16).to_le_bytes();
+        writer.write(DESCRIPTOR_TYPE_HID, &[
+            0x11, 0x01,                 // bcdHID 1.11
+            0,                          // bCountryCode
+            1,                          // bNumDescriptors
+            DESCRIPTOR_TYPE_REPORT,
+            len[0], len[1]
+        ])?;
+        writer.endpoint(&self.endpoint)
+    }
+
+    fn reset(&mut self) {
+        // リセットされたらレポートプロトコルに戻る（HIDの仕様）
+        self.set_protocol(KeyboardProtocol::Nkro);
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.index != u8::from(self.interface) as u16 {
+            return;
+        }
+        match (req.request_type, req.recipient, req.request) {
+            (RequestType::Standard, Recipient::Interface, control::Request::GET_DESCRIPTOR)
+                if req.descriptor_type_index() == (DESCRIPTOR_TYPE_REPORT, 0) => {
+                let _ = xfer.accept_with_static(REPORT_DESCRIPTOR);
+            }
+            (RequestType::Class, Recipient::Interface, REQUEST_GET_REPORT) => {
+                let len = self.report_len;
+                let _ = xfer.accept_with(&self.report[..len]);
+            }
+            (RequestType::Class, Recipient::Interface, REQUEST_GET_IDLE) => {
+                let _ = xfer.accept_with(&[self.idle]);
+            }
+            (RequestType::Class, Recipient::Interface, REQUEST_GET_PROTOCOL) => {
+                let protocol = match self.protocol {
+                    KeyboardProtocol::Boot => 0,
+                    KeyboardProtocol::Nkro => 1
+                };
+                let _ = xfer.accept_with(&[protocol]);
+            }
+            _ => {}
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != u8::from(self.interface) as u16 {
+            return;
+        }
+        match req.request {
+            REQUEST_SET_PROTOCOL => {
+                self.set_protocol(if req.value == 0 { KeyboardProtocol::Boot } else { KeyboardProtocol::Nkro });
+                let _ = xfer.accept();
+            }
+            REQUEST_SET_IDLE => {
+                self.idle = (req.value >> 8) as u8;
+                let _ = xfer.accept();
+            }
+            // LEDは使わない
+            REQUEST_SET_REPORT => {
+                let _ = xfer.accept();
+            }
+            _ => {}
+        }
+    }
+}
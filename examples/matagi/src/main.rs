@@ -2,6 +2,7 @@
 #![no_std]
 
 mod layout;
+mod hid_keyboard;
 mod usb_reporter;
 
 const VENDOR_ID:  u16 = 0xFEED;
@@ -21,15 +22,18 @@ use xiao_m0::gpio::{PfD, Pa8, Pa9, Pb8, Pb9};
 use usb_device::prelude::*;
 use makbe_ff::evaluator::Evaluator;
 use makbe_ff::scanner::Scanner;
+use makbe_ff::probe::EXPANDER_ADDRESSES;
 use crate::layout::Layout;
+use crate::hid_keyboard::KeyboardClass;
 use crate::usb_reporter::UsbReporter;
-use keyberon::keyboard::Leds;
 use xiao_m0::time::U32Ext;
 use xiao_m0::prelude::*;
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m_rt::exception;
+use core::sync::atomic::{AtomicU32, Ordering};
 
-struct NoLeds {}
-
-impl Leds for NoLeds {}
+/// SysTickの割り込みで数えるミリ秒
+static NOW: AtomicU32 = AtomicU32::new(0);
 
 type UART = UART4<Sercom4Pad1<Pb9<PfD>>, Sercom4Pad0<Pb8<PfD>>, (), ()>;
 
@@ -85,11 +89,10 @@ fn main() -> ! {
         nb::block!(uart.write(*c)).unwrap();
     }
 
-    let mut layout = Layout::new();
+    let layout = Layout::new();
 
-    let leds = NoLeds{};
     let mut reporter = UsbReporter {
-        usb_class: keyberon::new_class(&bus_allocator, leds),
+        usb_class: KeyboardClass::new(&bus_allocator),
         usb_dev: UsbDeviceBuilder::new(&bus_allocator, UsbVidPid(VENDOR_ID, PRODUCT_ID))
             .manufacturer(MANUFACTURER)
             .product(PRODUCT)
@@ -97,18 +100,47 @@ fn main() -> ! {
             .build()
     };
 
-    let evaluator = Evaluator::new();
+    let _ = layout.init_devices(&mut i2c);
+    let device_holder = layout.device_holder();
+
+    let mut evaluator = Evaluator::new();
+    evaluator.set_keymap(layout.keymap());
     let mut scanner = Scanner::new(evaluator);
 
-    layout.init_devices(&mut i2c);
+    // 1msのタイマー（48MHz）
+    core.SYST.set_clock_source(SystClkSource::Core);
+    core.SYST.set_reload(48_000 - 1);
+    core.SYST.clear_current();
+    core.SYST.enable_counter();
+    core.SYST.enable_interrupt();
+    let mut ticked = NOW.load(Ordering::Relaxed);
 
-    let device_holder = layout.device_holder();
+    scanner.enumerate(&mut i2c, &device_holder, &[EXPANDER_ADDRESSES], &mut reporter);
     loop {
-        scanner.scan(&mut i2c, &device_holder, &mut reporter);
-        dprint(&mut uart, "hello, world\n");
+        // ホストがブート/NKROを切り替えたら、今の状態を新しい形式で送り直す
+        if reporter.poll() {
+            let _ = scanner.evaluator_mut().resend(&mut reporter);
+        }
+        let now = NOW.load(Ordering::Relaxed);
+        scanner.scan(&mut i2c, &device_holder, now, &mut reporter);
+        // ループが1msより長くかかっても遅れないように、進んだ分だけtickする
+        while ticked != now {
+            ticked = ticked.wrapping_add(1);
+            scanner.tick(&mut reporter);
+            // モジュールの抜き差しを見る
+            if ticked % 1000 == 0 {
+                scanner.enumerate(&mut i2c, &device_holder, &[EXPANDER_ADDRESSES], &mut reporter);
+            }
+        }
     }
 }
 
+#[exception]
+fn SysTick() {
+    // 書くのはここだけなので、読んでから書いてもよい（thumbv6mにはfetch_addがない）
+    NOW.store(NOW.load(Ordering::Relaxed).wrapping_add(1), Ordering::Relaxed);
+}
+
 fn dprint(uart: &mut UART, message: &str) {
     let buffer = message.as_bytes();
     uart.bwrite_all(buffer);
@@ -1,27 +1,42 @@
-
-use keyberon::key_code::{KeyCode, KbHidReport};
-use makbe_ff::reporter::Reporter;
+use keyberon::key_code::KeyCode;
+use makbe_ff::keyboard::KeyboardReport;
+use makbe_ff::reporter::{Reporter, ReportError};
 use xiao_m0::UsbBus;
-use usb_device::device::UsbDevice;
-use keyberon::Class;
-use keyberon::keyboard::Leds;
+use usb_device::device::{UsbDevice, UsbDeviceState};
+use usb_device::UsbError;
+use crate::hid_keyboard::KeyboardClass;
 
 
-pub struct UsbReporter<'a, L: Leds> {
-    pub usb_class: Class<'a, UsbBus, L>,
+pub struct UsbReporter<'a> {
+    pub usb_class: KeyboardClass<'a, UsbBus>,
     pub usb_dev: UsbDevice<'a, UsbBus>
 }
 
+impl UsbReporter<'_> {
+
+    /// USBの処理をして、ホストがプロトコルを切り替えたらtrue
+    ///
+    /// trueのときは`Evaluator::resend`で今のレポートを送り直す
+    pub fn poll(&mut self) -> bool {
+        self.usb_dev.poll(&mut [&mut self.usb_class]);
+        self.usb_class.take_protocol_change()
+    }
+}
+
 
-impl<L: Leds> Reporter for UsbReporter<'_, L> {
+impl Reporter for UsbReporter<'_> {
 
-    fn send_codes(&mut self, codes: &[KeyCode]) {
-        let mut report: KbHidReport = KbHidReport::default();   //codes.collect();
-        for kc in codes {
-            report.pressed(kc.clone());
+    fn send_codes(&mut self, codes: &[KeyCode]) -> nb::Result<(), ReportError> {
+        if self.usb_dev.state() != UsbDeviceState::Configured {
+            return Err(nb::Error::Other(ReportError::Disconnected));
         }
-        if self.usb_class.device_mut().set_keyboard_report(report.clone()) {
-            while let Ok(0) = self.usb_class.write(report.as_bytes()) {}
+        // ブートプロトコルなら6KRO、レポートプロトコルならNKRO
+        let report = KeyboardReport::new(self.usb_class.protocol(), codes);
+        // 送れなかったら、次のtickでもう一度呼ばれる
+        match self.usb_class.write(report.as_bytes()) {
+            Ok(0) | Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
+            Ok(_) => Ok(()),
+            Err(_) => Err(nb::Error::Other(ReportError::Failed))
         }
     }
 }
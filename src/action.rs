@@ -0,0 +1,149 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # アクション
+//!
+//! keyberonの`Action`をベースに、makbe-ffで拡張したもの。
+//! `k`, `l`, `d`, `m`はkeyberonと同じように使える
+
+use keyberon::key_code::KeyCode;
+use crate::macros::MacroStep;
+use crate::consumer::{ConsumerCode, SystemCode};
+use crate::mouse::{MouseButton, MouseDirection};
+
+/// キーを押したときの動作
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Action {
+    /// 何もしない
+    NoOp,
+    /// 透過（デフォルトレイヤのアクションを使う）
+    Trans,
+    /// 普通のキー
+    KeyCode(KeyCode),
+    /// コンシューマコントロール（メディアキー）
+    Consumer(ConsumerCode),
+    /// システムコントロール（スリープなど）
+    System(SystemCode),
+    /// マウスのボタン
+    MouseButton(MouseButton),
+    /// マウスの移動・ホイール（押している間、`tick`毎に動かす）
+    MouseMove(MouseDirection),
+    /// 複数のキーを同時に押す
+    MultipleKeyCodes(&'static [KeyCode]),
+    /// 複数のアクションを同時に実行する
+    MultipleActions(&'static [Action]),
+    /// 押している間だけレイヤを切り替える
+    Layer(usize),
+    /// デフォルトレイヤを変える
+    DefaultLayer(usize),
+    /// レイヤの有効・無効を切り替える（TG）
+    ///
+    /// `hold`を`Layer`、`tap`を`ToggleLayer`にした`HoldTap`にすれば、押している間だけのレイヤとしても使える
+    ToggleLayer(usize),
+    /// デフォルトレイヤ以外を無効にして、レイヤを有効にする（TO）
+    ToLayer(usize),
+    /// `timeout`より長く押したら`hold`、それより短ければ`tap`
+    ///
+    /// 他のキーとの兼ね合いは`config`で決める。
+    /// `tap`の後`tap_hold_interval`（tick）以内にもう一度押したら、長押ししても`tap`になる（0なら使わない）
+    HoldTap {
+        timeout: u16,
+        hold: &'static Action,
+        tap: &'static Action,
+        config: HoldTapConfig,
+        tap_hold_interval: u16,
+    },
+    /// # ワンショット
+    ///
+    /// タップすると、次に押した（修飾キー以外の）キーにだけ`action`（修飾キーやレイヤ）が効く。
+    /// `timeout`（tick）以内に次のキーが押されなければ解除される。
+    /// 同じキーをもう一度タップしても解除される
+    OneShot {
+        action: &'static Action,
+        timeout: u16,
+        hold: OneShotHold,
+    },
+    /// # タップダンス
+    ///
+    /// `timeout`（tick）以内に続けてタップした回数で、`taps`のどれかを実行する（1回なら`taps[0]`）。
+    /// 最後のタップの後、押したまま`timeout`が過ぎたら`holds`の方を実行する（なければ`taps`）。
+    /// 途中で他のキーが押されたら、その時点の回数で決まる
+    TapDance {
+        timeout: u16,
+        taps: &'static [Action],
+        holds: &'static [Action],
+    },
+    /// マクロ（`tick`毎に1手順ずつ実行する。キーを離しても最後まで続く）
+    Macro(&'static [MacroStep]),
+    /// リーダーキー（続けてタップしたキーを、`Evaluator`に登録したシーケンスと照らし合わせる）
+    Leader,
+}
+
+/// ホールドタップを押している間に、他のキーが押されたときの扱い
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HoldTapConfig {
+    /// タイムアウトするまで`hold`にしない（他のキーは関係ない）
+    TapPreferred,
+    /// 他のキーが押されたら`hold`
+    HoldOnOtherKeyPress,
+    /// 他のキーが押されて離されたら（その間ずっと押していたら）`hold`
+    PermissiveHold,
+    /// `PermissiveHold`に加えて、`timeout`の半分を過ぎてから他のキーが押されたら`hold`
+    Balanced
+}
+
+/// ワンショットのキーを押したまま、他のキーを押したときの扱い
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OneShotHold {
+    /// 普通の修飾キー（レイヤ）として扱い、離したら解除する
+    Momentary,
+    /// 押したままでも、離したらワンショットとして次のキーを待つ
+    Sticky
+}
+
+impl Action {
+
+    /// 修飾キーのように、他のキーと組み合わせて使うものか
+    ///
+    /// ワンショットは、これがfalseのキーが押されたら使われたことになる
+    pub fn is_modifier(&self) -> bool {
+        match self {
+            Action::KeyCode(kc) => kc.is_modifier(),
+            Action::MultipleKeyCodes(kcs) => kcs.iter().all(|kc| kc.is_modifier()),
+            Action::MultipleActions(actions) => actions.iter().all(|a| a.is_modifier()),
+            Action::Layer(_) | Action::DefaultLayer(_) | Action::ToggleLayer(_) | Action::ToLayer(_) => true,
+            Action::OneShot { .. } => true,
+            _ => false
+        }
+    }
+}
+
+/// `Action::KeyCode`
+pub const fn k(kc: KeyCode) -> Action {
+    Action::KeyCode(kc)
+}
+
+/// `Action::Layer`
+pub const fn l(layer: usize) -> Action {
+    Action::Layer(layer)
+}
+
+/// `Action::DefaultLayer`
+pub const fn d(layer: usize) -> Action {
+    Action::DefaultLayer(layer)
+}
+
+/// `Action::MultipleKeyCodes`
+pub const fn m(kcs: &'static [KeyCode]) -> Action {
+    Action::MultipleKeyCodes(kcs)
+}
+
+/// `Action::OneShot`（押したまま他のキーを押したら、普通の修飾キーとして扱う）
+pub const fn os(action: &'static Action, timeout: u16) -> Action {
+    Action::OneShot {
+        action,
+        timeout,
+        hold: OneShotHold::Momentary
+    }
+}
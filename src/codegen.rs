@@ -0,0 +1,399 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # レイアウトのコード生成
+//!
+//! レイアウトのファイル（ゆるいJSON）から、`SwitchPool`とデバイス、`DeviceHolder`を作る
+//! `Layout`のコードを生成する（`std`フィーチャが必要）。build.rsから使うことを想定している。
+//!
+//! ```text
+//! {
+//!     i2c: "I2CMaster",       // I2Cの型
+//!     error: "I2CError",      // I2Cのエラーの型
+//!     layers: ["BASE", "LOWER"],
+//!     kle: [["escape", "kb1"]],   // 位置を書いていないスイッチは、ラベルが同じKLEのキーの位置にする
+//!     switches: [
+//!         {name: "escape", actions: ["k(Escape)", "l(LOWER)"]},
+//!         {name: "kb1", x: 1.5, y: 0.5, w: 1.0, actions: ["k(Kb1)", "k(F1)"]},
+//!     ],
+//!     devices: [
+//!         {type: "TCA9555", address: 0, debounce: 5, pins: {0: "escape", 1: "kb1"}},
+//!     ],
+//! }
+//! ```
+//!
+//! アクションはRustの式としてそのまま埋め込むので、`k`や`KeyCode`などは`include!`する側で`use`しておく。
+//! デバイスは`cortex_m::singleton!`で作るので、`cortex_m`と（`switch_pool!`が使う）`paste`にも依存しておく
+
+use std::fmt;
+use std::fmt::Write;
+use std::string::String;
+use std::vec::Vec;
+use crate::json::{self, ParseError, Value};
+use crate::key_switch::{Position, Shape};
+use crate::kle::{self, KleError, KleKey};
+
+/// `KeySwitches`に入るスイッチの数
+const MAX_SWITCHES: usize = 128;
+
+/// 読めなかった理由
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutError {
+    /// JSONとして読めない
+    Parse(ParseError),
+    /// KLEのraw-dataとして読めない
+    Kle(KleError),
+    /// 必要な項目がない、型が違うなど
+    Format(&'static str),
+    /// Rustの識別子として使えない名前
+    InvalidName(String),
+    /// 同じ名前のスイッチが2つある
+    DuplicateSwitch(String),
+    /// 定義していないスイッチをピンに割り当てている
+    UnknownSwitch(String),
+    /// 位置が書いてなくて、KLEにもない
+    NoPosition(String),
+    /// 知らないデバイス
+    UnknownDevice(String),
+    /// デバイスにないピン
+    PinOutOfRange { device: usize, pin: usize },
+    /// I2Cアドレス（A2〜A0の値）が0〜7ではない
+    AddressOutOfRange { device: usize, address: f64 },
+    /// デバウンスの時間が0〜65535ミリ秒ではない
+    DebounceOutOfRange { device: usize, debounce: f64 },
+    /// スイッチが多すぎる
+    TooManySwitches(usize)
+}
+
+impl From<ParseError> for LayoutError {
+    fn from(e: ParseError) -> Self { LayoutError::Parse(e) }
+}
+
+impl From<KleError> for LayoutError {
+    fn from(e: KleError) -> Self { LayoutError::Kle(e) }
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::Parse(e) => write!(f, "{}", e),
+            LayoutError::Kle(e) => write!(f, "kle: {}", e),
+            LayoutError::Format(message) => write!(f, "{}", message),
+            LayoutError::InvalidName(name) => write!(f, "invalid name: {}", name),
+            LayoutError::DuplicateSwitch(name) => write!(f, "duplicate switch: {}", name),
+            LayoutError::UnknownSwitch(name) => write!(f, "unknown switch: {}", name),
+            LayoutError::NoPosition(name) => write!(f, "no position for switch: {}", name),
+            LayoutError::UnknownDevice(name) => write!(f, "unknown device: {}", name),
+            LayoutError::PinOutOfRange { device, pin } => write!(f, "device{} has no pin {}", device, pin),
+            LayoutError::AddressOutOfRange { device, address } => write!(f, "device{} has invalid address {}", device, address),
+            LayoutError::DebounceOutOfRange { device, debounce } => write!(f, "device{} has invalid debounce {}", device, debounce),
+            LayoutError::TooManySwitches(count) => write!(f, "too many switches: {}", count)
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+/// 生成するスイッチ
+struct SwitchSpec {
+    name: String,
+    shape: Shape,
+    position: Position,
+    actions: Vec<String>,
+    default_action: Option<String>
+}
+
+/// 生成するデバイス
+struct DeviceSpec {
+    type_name: &'static str,
+    address: u8,
+    debounce: u16,
+    pins: Vec<(usize, String)>
+}
+
+/// レイアウトのファイルから、`include!`するコードを生成する
+pub fn generate(text: &str) -> Result<String, LayoutError> {
+    let value = json::parse(text)?;
+    let i2c = value.get("i2c").and_then(Value::as_str).ok_or(LayoutError::Format("expected i2c type"))?;
+    let error = value.get("error").and_then(Value::as_str).ok_or(LayoutError::Format("expected error type"))?;
+    let layers = layers(&value)?;
+    let switches = switches(&value)?;
+    let devices = devices(&value, &switches)?;
+
+    let mut code = String::new();
+    emit(&mut code, i2c, error, &layers, &switches, &devices).map_err(|_| LayoutError::Format("write error"))?;
+    Ok(code)
+}
+
+/// build.rsから呼ぶ
+///
+/// `CARGO_MANIFEST_DIR`からの相対パスのファイルを読んで、`OUT_DIR`の`layout.rs`に書き出す。
+/// 使う側では`include!(concat!(env!("OUT_DIR"), "/layout.rs"));`とする
+pub fn build(path: &str) -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR")?;
+    let out_dir = std::env::var("OUT_DIR")?;
+    let input = std::path::Path::new(&manifest_dir).join(path);
+    println!("cargo:rerun-if-changed={}", input.display());
+    let code = generate(&std::fs::read_to_string(&input)?)?;
+    std::fs::write(std::path::Path::new(&out_dir).join("layout.rs"), code)?;
+    Ok(())
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false
+    }
+}
+
+fn identifier(value: Option<&Value>) -> Result<String, LayoutError> {
+    let name = value.and_then(Value::as_str).ok_or(LayoutError::Format("expected name"))?;
+    if is_identifier(name) {
+        Ok(String::from(name))
+    } else {
+        Err(LayoutError::InvalidName(String::from(name)))
+    }
+}
+
+fn layers(value: &Value) -> Result<Vec<String>, LayoutError> {
+    match value.get("layers") {
+        None => Ok(Vec::new()),
+        Some(Value::Array(items)) => items.iter().map(|item| identifier(Some(item))).collect(),
+        Some(_) => Err(LayoutError::Format("expected array of layer names"))
+    }
+}
+
+fn switches(value: &Value) -> Result<Vec<SwitchSpec>, LayoutError> {
+    let keys = match value.get("kle") {
+        None => Vec::new(),
+        Some(Value::String(raw)) => kle::parse(raw)?,
+        Some(raw) => kle::from_value(raw)?
+    };
+    let items = value.get("switches").and_then(Value::as_array).ok_or(LayoutError::Format("expected array of switches"))?;
+    if items.len() > MAX_SWITCHES {
+        return Err(LayoutError::TooManySwitches(items.len()));
+    }
+    let mut switches: Vec<SwitchSpec> = Vec::new();
+    for item in items.iter() {
+        let name = identifier(item.get("name"))?;
+        if switches.iter().any(|s| s.name == name) {
+            return Err(LayoutError::DuplicateSwitch(name));
+        }
+        let (shape, position) = position(item, &name, &keys)?;
+        let actions = match item.get("actions") {
+            None => Vec::new(),
+            Some(Value::Array(actions)) => actions.iter()
+                .map(|a| a.as_str().map(String::from).ok_or(LayoutError::Format("expected action expression")))
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(LayoutError::Format("expected array of actions"))
+        };
+        let default_action = match item.get("default") {
+            None => None,
+            Some(a) => Some(a.as_str().map(String::from).ok_or(LayoutError::Format("expected action expression"))?)
+        };
+        switches.push(SwitchSpec { name, shape, position, actions, default_action });
+    }
+    Ok(switches)
+}
+
+/// 書いてある位置か、ラベルが同じKLEのキーの位置
+fn position(item: &Value, name: &str, keys: &[KleKey]) -> Result<(Shape, Position), LayoutError> {
+    let get = |key: &str| item.get(key).and_then(Value::as_f64).map(|v| v as f32);
+    let shape = match item.get("shape").and_then(Value::as_str) {
+        None | Some("rect") => Shape::Rectangle,
+        Some("iso") => Shape::IsoEnter,
+        Some(_) => return Err(LayoutError::Format("expected shape \"rect\" or \"iso\""))
+    };
+    if let (Some(x), Some(y)) = (get("x"), get("y")) {
+        let (w, h) = match shape {
+            Shape::IsoEnter => (1.25, 2.0),
+            Shape::Rectangle => (1.0, 1.0)
+        };
+        let position = Position::new(
+            x,
+            y,
+            get("w").unwrap_or(w),
+            get("h").unwrap_or(h),
+            get("r").unwrap_or(0.0),
+            get("rx").unwrap_or(0.0),
+            get("ry").unwrap_or(0.0)
+        );
+        return Ok((shape, position));
+    }
+    let label = item.get("label").and_then(Value::as_str).unwrap_or(name);
+    keys.iter()
+        .find(|key| key.labels().next() == Some(label))
+        .map(|key| (key.shape, key.position))
+        .ok_or_else(|| LayoutError::NoPosition(String::from(name)))
+}
+
+fn devices(value: &Value, switches: &[SwitchSpec]) -> Result<Vec<DeviceSpec>, LayoutError> {
+    let items = value.get("devices").and_then(Value::as_array).ok_or(LayoutError::Format("expected array of devices"))?;
+    let mut devices = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        let type_name = item.get("type").and_then(Value::as_str).ok_or(LayoutError::Format("expected device type"))?;
+        let (type_name, pin_count) = match type_name {
+            "TCA9555" => ("TCA9555", 16),
+            "TCA9554" => ("TCA9554", 8),
+            _ => return Err(LayoutError::UnknownDevice(String::from(type_name)))
+        };
+        let address = item.get("address").and_then(Value::as_f64).ok_or(LayoutError::Format("expected device address"))?;
+        let debounce = item.get("debounce").and_then(Value::as_f64).unwrap_or(5.0);
+        if !integer_in(address, 7) {
+            return Err(LayoutError::AddressOutOfRange { device: index, address });
+        }
+        if !integer_in(debounce, u16::MAX) {
+            return Err(LayoutError::DebounceOutOfRange { device: index, debounce });
+        }
+
+        // ピン番号をキーにしたオブジェクトか、ピン順の配列（nullは割り当てなし）
+        let assigned: Vec<(usize, &Value)> = match item.get("pins") {
+            Some(Value::Object(members)) => members.iter()
+                .map(|(pin, name)| pin.parse::<usize>().map(|pin| (pin, name)).map_err(|_| LayoutError::Format("expected pin number")))
+                .collect::<Result<_, _>>()?,
+            Some(Value::Array(names)) => names.iter().enumerate().filter(|(_, name)| **name != Value::Null).collect(),
+            _ => return Err(LayoutError::Format("expected pins"))
+        };
+        let mut pins = Vec::new();
+        for (pin, name) in assigned.into_iter() {
+            if pin >= pin_count {
+                return Err(LayoutError::PinOutOfRange { device: index, pin });
+            }
+            let name = identifier(Some(name))?;
+            if !switches.iter().any(|s| s.name == name) {
+                return Err(LayoutError::UnknownSwitch(name));
+            }
+            pins.push((pin, name));
+        }
+        devices.push(DeviceSpec { type_name, address: address as u8, debounce: debounce as u16, pins });
+    }
+    Ok(devices)
+}
+
+/// 0〜maxの整数か
+fn integer_in(value: f64, max: u16) -> bool {
+    value >= 0.0 && value <= max as f64 && value.fract() == 0.0
+}
+
+fn value(v: i32) -> f32 {
+    v as f32 / 256.0
+}
+
+fn emit(
+    code: &mut String,
+    i2c: &str,
+    error: &str,
+    layers: &[String],
+    switches: &[SwitchSpec],
+    devices: &[DeviceSpec]
+) -> fmt::Result {
+    writeln!(code, "// makbe_ff::codegen が生成したコード")?;
+    writeln!(code)?;
+    writeln!(code, "use makbe_ff::device::Device as _;")?;
+    writeln!(code)?;
+    for (i, layer) in layers.iter().enumerate() {
+        writeln!(code, "pub const {}: usize = {};", layer, i)?;
+    }
+    if !layers.is_empty() {
+        writeln!(code)?;
+    }
+
+    writeln!(code, "makbe_ff::switch_pool!(")?;
+    writeln!(code, "    struct SwitchPool,")?;
+    for switch in switches.iter() {
+        let p = &switch.position;
+        let new = match switch.shape {
+            Shape::IsoEnter => format!("makbe_ff::key_switch::KeySwitch::new_with_shape(makbe_ff::key_switch::Shape::IsoEnter, {:?}, {:?})", value(p.x), value(p.y)),
+            Shape::Rectangle => format!("makbe_ff::key_switch::KeySwitch::new_with_size({:?}, {:?}, {:?}, {:?})", value(p.x), value(p.y), value(p.w), value(p.h))
+        };
+        let mut calls = String::new();
+        if p.r != 0 {
+            write!(calls, ".rotate_at({:?}, {:?}, {:?})", value(p.r), value(p.rx), value(p.ry))?;
+        }
+        for action in switch.actions.iter() {
+            write!(calls, ".append_action({})", action)?;
+        }
+        if let Some(action) = &switch.default_action {
+            write!(calls, ".default_action({})", action)?;
+        }
+        if calls.is_empty() {
+            writeln!(code, "    switch {} = {},", switch.name, new)?;
+        } else {
+            writeln!(code, "    switch {} = {}.apply(|s| s{}),", switch.name, new, calls)?;
+        }
+    }
+    writeln!(code, ");")?;
+    writeln!(code)?;
+
+    writeln!(code, "pub struct Layout {{")?;
+    for (i, device) in devices.iter().enumerate() {
+        writeln!(code, "    pub device{}: &'static {}<{}, {}>,", i, device_path(device), i2c, error)?;
+    }
+    writeln!(code, "    switches: makbe_ff::key_switch::KeySwitches")?;
+    writeln!(code, "}}")?;
+    writeln!(code)?;
+
+    writeln!(code, "impl Layout {{")?;
+    writeln!(code)?;
+    writeln!(code, "    pub fn new() -> Self {{")?;
+    writeln!(code, "        let mut switches = makbe_ff::key_switch::KeySwitches::new();")?;
+    writeln!(code, "        let pool = SwitchPool::new(&mut switches).unwrap();")?;
+    writeln!(code, "        Self {{")?;
+    for (i, device) in devices.iter().enumerate() {
+        writeln!(
+            code,
+            "            device{}: cortex_m::singleton!(: {}<{}, {}> = Self::dev{}(&pool)).unwrap(),",
+            i, device_path(device), i2c, error, i
+        )?;
+    }
+    writeln!(code, "            switches")?;
+    writeln!(code, "        }}")?;
+    writeln!(code, "    }}")?;
+
+    for (i, device) in devices.iter().enumerate() {
+        writeln!(code)?;
+        writeln!(code, "    fn dev{}(switches: &SwitchPool) -> {}<{}, {}> {{", i, device_path(device), i2c, error)?;
+        writeln!(code, "        let mut device = {}::new({:#x}, {});", device_path(device), device.address, device.debounce)?;
+        for (pin, name) in device.pins.iter() {
+            writeln!(code, "        let _ = device.assign({}, switches.{});", pin, name)?;
+        }
+        writeln!(code, "        device")?;
+        writeln!(code, "    }}")?;
+    }
+
+    writeln!(code)?;
+    writeln!(code, "    pub fn init_devices(&self, i2c: &mut {}) -> Result<(), {}> {{", i2c, error)?;
+    for i in 0..devices.len() {
+        writeln!(code, "        self.device{}.init_device(i2c)?;", i)?;
+    }
+    writeln!(code, "        Ok(())")?;
+    writeln!(code, "    }}")?;
+    writeln!(code)?;
+    writeln!(code, "    pub fn device_holder(&self) -> makbe_ff::device::DeviceHolder<{}, {}> {{", i2c, error)?;
+    writeln!(code, "        let mut holder = makbe_ff::device::DeviceHolder::new();")?;
+    for i in 0..devices.len() {
+        writeln!(code, "        let _ = holder.devices.push(self.device{});", i)?;
+    }
+    writeln!(code, "        holder")?;
+    writeln!(code, "    }}")?;
+    writeln!(code)?;
+    writeln!(code, "    /// スイッチの表と、デバイスから作った仮想マトリクス（`Evaluator`に渡す）")?;
+    writeln!(code, "    ///")?;
+    writeln!(code, "    /// スイッチの表は1つしかないので、`Layout`はここで使い切る")?;
+    writeln!(code, "    pub fn keymap(self) -> makbe_ff::keymap::Keymap {{")?;
+    writeln!(code, "        let matrix = makbe_ff::keymap::VirtualMatrix::from_devices(&self.device_holder());")?;
+    writeln!(code, "        makbe_ff::keymap::Keymap::new(self.switches, matrix)")?;
+    writeln!(code, "    }}")?;
+    writeln!(code, "}}")?;
+    writeln!(code)?;
+    writeln!(code, "impl Default for Layout {{")?;
+    writeln!(code, "    fn default() -> Self {{ Layout::new() }}")?;
+    writeln!(code, "}}")?;
+    Ok(())
+}
+
+fn device_path(device: &DeviceSpec) -> String {
+    format!("makbe_ff::devices::{}::{}", device.type_name.to_lowercase(), device.type_name)
+}
@@ -0,0 +1,56 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # コンシューマ・システムコントロール
+//!
+//! メディアキーやスリープなど、キーボードのレポートでは送れないもの。
+//! 値はHID Usage Tablesのコンシューマページ（0x0C）、汎用デスクトップページ（0x01）のUsage ID
+
+/// コンシューマページ（0x0C）のUsage
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u16)]
+pub enum ConsumerCode {
+    BrightnessUp = 0x006F,
+    BrightnessDown = 0x0070,
+    NextTrack = 0x00B5,
+    PrevTrack = 0x00B6,
+    Stop = 0x00B7,
+    Eject = 0x00B8,
+    PlayPause = 0x00CD,
+    Mute = 0x00E2,
+    VolumeUp = 0x00E9,
+    VolumeDown = 0x00EA,
+    MediaSelect = 0x0183,
+    Mail = 0x018A,
+    Calculator = 0x0192,
+    MyComputer = 0x0194,
+    WwwSearch = 0x0221,
+    WwwHome = 0x0223,
+    WwwBack = 0x0224,
+    WwwForward = 0x0225,
+    WwwRefresh = 0x0227,
+}
+
+impl ConsumerCode {
+
+    pub fn usage(&self) -> u16 {
+        *self as u16
+    }
+}
+
+/// 汎用デスクトップページ（0x01）のシステムコントロールのUsage
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u16)]
+pub enum SystemCode {
+    PowerDown = 0x0081,
+    Sleep = 0x0082,
+    WakeUp = 0x0083,
+}
+
+impl SystemCode {
+
+    pub fn usage(&self) -> u16 {
+        *self as u16
+    }
+}
@@ -24,55 +24,194 @@ impl<NumPins> Keys<NumPins>
             pressed: Vec::from_slice(keys).unwrap()
         }
     }
+
+    /// 全てのキーが離されている状態
+    pub fn released() -> Self {
+        let mut pressed = Vec::new();
+        let _ = pressed.resize(pressed.capacity(), false);
+        Self {
+            pressed
+        }
+    }
 }
 
+/// ピン毎のタイマーの数（I/Oエクスパンダのピン数の最大）
+const MAX_PINS: usize = 16;
+
+/// # チャタリング除去の方式
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DebounceStrategy {
+    /// 全てのピンの状態が`window`ミリ秒変わらなかったら確定する
+    ///
+    /// どれか1つでもチャタリングしていると、同じデバイスの他のキーも待たされる
+    Deferred,
+    /// ピン毎に、新しい状態が`window`ミリ秒続いたら確定する
+    PerKeyDeferred,
+    /// ピン毎に、押したらすぐに確定して、離したときは`window`ミリ秒続いたら確定する
+    EagerPressDeferredRelease,
+    /// ピン毎に、変化したらすぐに確定して、その後`window`ミリ秒は変化を無視する
+    SymmetricEager
+}
+
+impl Default for DebounceStrategy {
+    fn default() -> Self { DebounceStrategy::Deferred }
+}
+
+/// # チャタリング除去
+///
+/// 時刻は呼び出し側が渡す単調増加のミリ秒（一周してもよい）なので、スキャンの頻度には依存しない
 pub struct Debouncer<NumPins>
     where
         NumPins: ArrayLength<bool> + ArrayLength<KeyEvent> + core::cmp::PartialEq
 {
     cur: Keys<NumPins>,
     new: Keys<NumPins>,
-    count: u16,
-    limit: u16
+    /// newを最初に読んだ時刻（`Deferred`のとき）
+    since: Option<u32>,
+    /// ピン毎のタイマー（`Deferred`以外のとき）
+    ///
+    /// 遅延確定なら変化を最初に読んだ時刻、即時確定なら確定した時刻
+    timers: [Option<u32>; MAX_PINS],
+    window: u16,
+    strategy: DebounceStrategy
 }
 
 impl <NumPins> Debouncer<NumPins>
     where
         NumPins: ArrayLength<bool> + ArrayLength<KeyEvent> + core::cmp::PartialEq
 {
-    pub fn new(limit: u16) -> Self {
+    /// windowの単位はミリ秒
+    pub fn new(window: u16) -> Self {
+        Self::with_strategy(window, DebounceStrategy::default())
+    }
+
+    pub fn with_strategy(window: u16, strategy: DebounceStrategy) -> Self {
         Self {
-            cur: Keys::default(),
-            new: Keys::default(),
-            count: 0,
-            limit
+            cur: Keys::released(),
+            new: Keys::released(),
+            since: None,
+            timers: [None; MAX_PINS],
+            window,
+            strategy
         }
     }
 
-    pub fn update(&mut self, new: &Keys<NumPins>) ->bool {
+    pub fn strategy(&self) -> DebounceStrategy {
+        self.strategy
+    }
+
+    /// 方式を変える（途中の状態は捨てる）
+    pub fn set_strategy(&mut self, strategy: DebounceStrategy) {
+        self.strategy = strategy;
+        self.since = None;
+        self.timers = [None; MAX_PINS];
+    }
+
+    pub fn update(&mut self, new: &Keys<NumPins>, now: u32) -> bool {
         if self.cur == *new {
-            self.count = 0;
+            self.since = None;
             false
         } else {
-            if self.new == *new {
-                self.count += 1;
+            let since = match self.since {
+                Some(since) if self.new == *new => since,
+                _ => {
+                    self.new.pressed = new.pressed.clone();
+                    self.since = Some(now);
+                    now
+                }
+            };
+            if now.wrapping_sub(since) < self.window as u32 {
+                false
             } else {
-                self.new.pressed = new.pressed.clone();
-                self.count = 1;
+                core::mem::swap(&mut self.cur, &mut self.new);
+                self.since = None;
+                true
+            }
+        }
+    }
+
+    /// 確定待ちの変化がないか
+    ///
+    /// 即時確定の方式では、変化を無視している間も落ち着いていないとみなす
+    pub fn is_settled(&self) -> bool {
+        self.since.is_none() && self.timers.iter().all(|t| t.is_none())
+    }
+
+    /// 状態を初期化する
+    ///
+    /// 押されたままになっていたキーは、離されたイベントとして返す
+    pub fn reset(&mut self) -> IndexEvents {
+        let mut result = IndexEvents::new();
+        for (i, pressed) in self.cur.pressed.iter().enumerate() {
+            if *pressed {
+                let _ = result.buffer.push(ReleasedAt(i));
             }
-            if self.count <= self.limit {
+        }
+        self.cur = Keys::released();
+        self.new = Keys::released();
+        self.since = None;
+        self.timers = [None; MAX_PINS];
+        result
+    }
+
+    /// ピン毎の状態の更新
+    ///
+    /// 確定した状態が変わったらtrue
+    fn update_pin(&mut self, i: usize, pressed: bool, now: u32) -> bool {
+        let cur = self.cur.pressed[i];
+        let window = self.window as u32;
+        let timer = &mut self.timers[i];
+        match self.strategy {
+            DebounceStrategy::SymmetricEager => {
+                // 確定してから時間が経つまでは、何があっても無視する
+                if timer.map(|t| now.wrapping_sub(t) < window).unwrap_or(false) {
+                    return false;
+                }
+                *timer = None;
+                if pressed == cur {
+                    return false;
+                }
+                self.cur.pressed[i] = pressed;
+                if window > 0 {
+                    *timer = Some(now);
+                }
+                return true;
+            }
+            DebounceStrategy::EagerPressDeferredRelease if pressed && !cur => {
+                // 離すのは遅延確定なので、押した直後のチャタリングは離すほうで吸収される
+                self.cur.pressed[i] = true;
+                *timer = None;
+                return true;
+            }
+            _ => {}
+        }
+        if pressed == cur {
+            *timer = None;
+            false
+        } else {
+            let since = *timer.get_or_insert(now);
+            if now.wrapping_sub(since) < window {
                 false
             } else {
-                core::mem::swap(&mut self.cur, &mut self.new);
-                self.count = 0;
+                self.cur.pressed[i] = pressed;
+                *timer = None;
                 true
             }
         }
     }
 
-    pub fn events(&mut self, new: &[bool]) -> IndexEvents {
+    pub fn events(&mut self, new: &[bool], now: u32) -> IndexEvents {
         let mut result = IndexEvents::new();
-        if self.update(&Keys::from(new)) {
+        if self.strategy != DebounceStrategy::Deferred {
+            let len = new.len().min(self.cur.pressed.len()).min(MAX_PINS);
+            for (i, pressed) in new.iter().take(len).enumerate() {
+                if self.update_pin(i, *pressed, now) {
+                    let _ = result.buffer.push(if *pressed { PressedAt(i) } else { ReleasedAt(i) });
+                }
+            }
+            return result;
+        }
+        if self.update(&Keys::from(new), now) {
             let zipped = self.new.pressed.iter().zip(self.cur.pressed.iter());
             let mapped = zipped.enumerate().map(
                 move | (i, (o, n)) | {
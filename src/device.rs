@@ -3,7 +3,7 @@
 //
 
 
-use crate::key_switch::KeySwitch;
+use crate::key_switch::SwitchId;
 use crate::event::EventBuffer;
 use heapless::Vec;
 use heapless::consts::U128;
@@ -33,6 +33,9 @@ pub trait Device<I2C, E>
         I2C: Write<Error = E>,
         I2C: WriteRead<Error = E>
 {
+    /// # I2Cのアドレス（7bit）
+    fn address(&self) -> u8;
+
     /// # デバイスの初期化
     ///
     /// I/Oエクスパンダ上のピンの設定とか
@@ -45,24 +48,72 @@ pub trait Device<I2C, E>
 
 
     /// # キーの割付
-    fn assign(&mut self, pin: usize, switch: &'static KeySwitch) -> Result<usize, usize>;
+    fn assign(&mut self, pin: usize, switch: SwitchId) -> Result<usize, usize>;
 
     /// # キーが割り付けられているか
     fn has_assigned(&self) -> bool;
 
+    /// # ピンに割り付けられているスイッチ
+    fn switch_at(&self, _pin: usize) -> Option<SwitchId> {
+        None
+    }
+
     /// # イベントの検出
-    fn pick_events(&self, pins: &[bool]) -> EventBuffer;
+    ///
+    /// nowは単調増加のミリ秒（デバウンスに使う）
+    fn pick_events(&self, pins: &[bool], now: u32) -> EventBuffer;
+
+    /// # 押されているキーを全て離す
+    ///
+    /// デバイスがバスから外れたときに使う。キーを押していない状態に戻して、
+    /// 押されたままになっていたキーの離されたイベントを返す
+    fn release_all(&self) -> EventBuffer {
+        EventBuffer::new()
+    }
+
+    /// # デバウンスなどが落ち着いているか
+    ///
+    /// falseの間は、割込みがなくても読み続ける必要がある
+    fn is_settled(&self) -> bool {
+        true
+    }
+
+    /// # ロータリーエンコーダのイベントの検出
+    ///
+    /// valueはカウンタ値、bitsはそのビット幅。
+    /// ステップ毎に、時計回りなら`encoder::CLOCKWISE`、反時計回りなら`encoder::COUNTER_CLOCKWISE`の
+    /// ピンに割り付けられたスイッチのタップとして返す
+    fn pick_value(&self, _value: u32, _bits: u8) -> EventBuffer {
+        EventBuffer::new()
+    }
 }
 
 pub struct DeviceHolder<I2C: 'static, E: 'static> {
     pub devices: Vec<&'static dyn Device<I2C, E>, U128>
 }
 
-impl<I2C, E: 'static> DeviceHolder<I2C, E> {
+impl<I2C, E: 'static> DeviceHolder<I2C, E>
+    where
+        I2C: Write<Error = E>,
+        I2C: WriteRead<Error = E>
+{
 
     pub fn new() -> Self {
         Self {
             devices: Vec::new()
         }
     }
+
+    /// アドレスからデバイスのインデックスを探す
+    pub fn index_of(&self, addr: u8) -> Option<usize> {
+        self.devices.iter().position(|d| d.address() == addr)
+    }
+}
+
+impl<I2C, E: 'static> Default for DeviceHolder<I2C, E>
+    where
+        I2C: Write<Error = E>,
+        I2C: WriteRead<Error = E>
+{
+    fn default() -> Self { DeviceHolder::new() }
 }
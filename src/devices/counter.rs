@@ -0,0 +1,116 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use crate::key_switch::SwitchId;
+use crate::device::{Device, DeviceState};
+use crate::encoder::{Rotation, CLOCKWISE, COUNTER_CLOCKWISE};
+use crate::event::EventBuffer;
+use core::cell::RefCell;
+use crate::device::DeviceState::{Value8, Value16, Value32};
+use core::marker::PhantomData;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+/// カウンタのビット幅
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CounterWidth {
+    Bits8,
+    Bits16,
+    Bits32
+}
+
+impl CounterWidth {
+
+    fn bytes(&self) -> usize {
+        match self {
+            CounterWidth::Bits8 => 1,
+            CounterWidth::Bits16 => 2,
+            CounterWidth::Bits32 => 4
+        }
+    }
+}
+
+/// カウンタ値を返すロータリーエンコーダ・モジュール
+///
+/// レジスタ0x00からビッグエンディアンでカウンタ値を読む。
+/// ピン0（`encoder::CLOCKWISE`）に時計回り、ピン1（`encoder::COUNTER_CLOCKWISE`）に反時計回りのスイッチを割り付ける
+pub struct Counter<I2C, E> {
+    dev_addr: u8,
+    width: CounterWidth,
+    rotation: RefCell<Rotation>,
+    switches: [Option<SwitchId>; 2],
+    phantom0: PhantomData<I2C>,
+    phantom1: PhantomData<E>
+}
+
+impl<I2C, E> Counter<I2C, E> {
+
+    /// addrは7bitのアドレスそのもの、resolutionは1ステップあたりのカウント数
+    pub fn new(addr: u8, width: CounterWidth, resolution: u8) -> Self {
+        Self {
+            dev_addr: addr,
+            width,
+            rotation: RefCell::new(Rotation::new(resolution)),
+            switches: [None; 2],
+            phantom0: Default::default(),
+            phantom1: Default::default()
+        }
+    }
+}
+
+impl<I2C, E> Device<I2C, E> for Counter<I2C, E>
+    where
+        I2C: Write<Error = E>,
+        I2C: WriteRead<Error = E>
+{
+
+    fn address(&self) -> u8 {
+        self.dev_addr
+    }
+
+    fn init_device(&self, _i2c: &mut I2C) -> Result<(), E> {
+        // 次に読んだ値を基準にする
+        self.rotation.borrow_mut().reset();
+        Ok(())
+    }
+
+    fn read_device(&self, i2c: &mut I2C) -> Result<DeviceState, E> {
+        let reg_addr = &[0x00_u8];
+        let data = &mut [0x00_u8; 4];
+        let len = self.width.bytes();
+        i2c.write_read(self.dev_addr, reg_addr, &mut data[..len])?;
+
+        let value = data[..len].iter().fold(0_u32, |v, d| (v << 8) | *d as u32);
+        Ok(match self.width {
+            CounterWidth::Bits8 => Value8(value as u8),
+            CounterWidth::Bits16 => Value16(value as u16),
+            CounterWidth::Bits32 => Value32(value)
+        })
+    }
+
+    fn assign(&mut self, pin: usize, switch: SwitchId) -> Result<usize, usize> {
+        if pin < 2 {
+            self.switches[pin] = Some(switch);
+            Ok(pin)
+        } else {
+            Err(pin)
+        }
+    }
+
+    fn has_assigned(&self) -> bool {
+        self.switches.iter().any(|s| s.is_some())
+    }
+
+    fn switch_at(&self, pin: usize) -> Option<SwitchId> {
+        self.switches.get(pin).copied().flatten()
+    }
+
+    fn pick_events(&self, _pins: &[bool], _now: u32) -> EventBuffer {
+        EventBuffer::new()
+    }
+
+    fn pick_value(&self, value: u32, bits: u8) -> EventBuffer {
+        let steps = self.rotation.borrow_mut().update(value, bits);
+        Rotation::events(steps, self.switches[CLOCKWISE], self.switches[COUNTER_CLOCKWISE])
+    }
+}
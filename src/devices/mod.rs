@@ -4,3 +4,5 @@
 
 pub mod tca9555;
 pub mod tca9554;
+pub mod counter;
+pub mod quadrature;
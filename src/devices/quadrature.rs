@@ -0,0 +1,153 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use crate::key_switch::SwitchId;
+use crate::device::{Device, DeviceState};
+use crate::encoder::{QuadratureDecoder, Rotation};
+use crate::event::EventBuffer;
+use heapless::Vec;
+use heapless::consts::{U4, U16};
+use core::cell::RefCell;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+/// I/Oエクスパンダの2ピンにつないだロータリーエンコーダ
+struct QuadraturePins {
+    a: usize,
+    b: usize,
+    decoder: RefCell<QuadratureDecoder>,
+    clockwise: Option<SwitchId>,
+    counter_clockwise: Option<SwitchId>
+}
+
+/// # ロータリーエンコーダ付きのI/Oエクスパンダ
+///
+/// TCA9555やTCA9554をラップして、指定したピンのペアをA/B相の入力として扱う。
+/// エンコーダのピンはデバウンスせずにデコードして、ステップ毎のタップとして返す。
+/// それ以外のピンは、今まで通りスイッチとして使える。
+///
+/// A相のピンに`assign`したスイッチが時計回り、B相のピンに`assign`したスイッチが反時計回りになる。
+pub struct Quadrature<D> {
+    device: D,
+    encoders: Vec<QuadraturePins, U4>
+}
+
+impl<D> Quadrature<D> {
+
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            encoders: Vec::new()
+        }
+    }
+
+    /// ピンのペアをエンコーダとして使う
+    ///
+    /// resolutionは1ステップあたりのカウント数。返値はエンコーダの番号
+    pub fn assign_encoder(&mut self, a: usize, b: usize, resolution: u8) -> Result<usize, usize> {
+        let in_use = self.encoders.iter().any(|e| e.a == a || e.b == a || e.a == b || e.b == b);
+        if a == b || a >= 16 || b >= 16 || in_use {
+            return Err(a);
+        }
+        let pins = QuadraturePins {
+            a,
+            b,
+            decoder: RefCell::new(QuadratureDecoder::new(resolution)),
+            clockwise: None,
+            counter_clockwise: None
+        };
+        self.encoders.push(pins).map_err(|_| a)?;
+        Ok(self.encoders.len() - 1)
+    }
+}
+
+impl<I2C, E, D> Device<I2C, E> for Quadrature<D>
+    where
+        I2C: Write<Error = E>,
+        I2C: WriteRead<Error = E>,
+        D: Device<I2C, E>
+{
+
+    fn address(&self) -> u8 {
+        self.device.address()
+    }
+
+    fn init_device(&self, i2c: &mut I2C) -> Result<(), E> {
+        for e in self.encoders.iter() {
+            e.decoder.borrow_mut().reset();
+        }
+        self.device.init_device(i2c)
+    }
+
+    fn read_device(&self, i2c: &mut I2C) -> Result<DeviceState, E> {
+        self.device.read_device(i2c)
+    }
+
+    fn assign(&mut self, pin: usize, switch: SwitchId) -> Result<usize, usize> {
+        for e in self.encoders.iter_mut() {
+            if e.a == pin {
+                e.clockwise = Some(switch);
+                return Ok(pin);
+            }
+            if e.b == pin {
+                e.counter_clockwise = Some(switch);
+                return Ok(pin);
+            }
+        }
+        self.device.assign(pin, switch)
+    }
+
+    fn has_assigned(&self) -> bool {
+        self.device.has_assigned() || self.encoders.iter().any(|e| e.clockwise.is_some() || e.counter_clockwise.is_some())
+    }
+
+    fn switch_at(&self, pin: usize) -> Option<SwitchId> {
+        for e in self.encoders.iter() {
+            if e.a == pin {
+                return e.clockwise;
+            }
+            if e.b == pin {
+                return e.counter_clockwise;
+            }
+        }
+        self.device.switch_at(pin)
+    }
+
+    fn pick_events(&self, pins: &[bool], now: u32) -> EventBuffer {
+        let mut switches: Vec<bool, U16> = Vec::from_slice(pins).unwrap_or_default();
+        let mut rotations = EventBuffer::new();
+        for e in self.encoders.iter() {
+            if e.a >= switches.len() || e.b >= switches.len() {
+                continue;
+            }
+            let steps = e.decoder.borrow_mut().update(pins[e.a], pins[e.b]);
+            for event in Rotation::events(steps, e.clockwise, e.counter_clockwise).buffer {
+                let _ = rotations.buffer.push(event);
+            }
+            // スイッチ側には、ずっと離されているように見せる
+            switches[e.a] = false;
+            switches[e.b] = false;
+        }
+
+        let mut event_buffer = self.device.pick_events(&switches, now);
+        for event in rotations.buffer {
+            let _ = event_buffer.buffer.push(event);
+        }
+        event_buffer
+    }
+
+    fn release_all(&self) -> EventBuffer {
+        for e in self.encoders.iter() {
+            e.decoder.borrow_mut().reset();
+        }
+        self.device.release_all()
+    }
+
+    fn is_settled(&self) -> bool {
+        self.device.is_settled()
+    }
+
+    fn pick_value(&self, value: u32, bits: u8) -> EventBuffer {
+        self.device.pick_value(value, bits)
+    }
+}
@@ -2,10 +2,10 @@
 // All right reserved.
 //
 
-use crate::key_switch::KeySwitch;
-use crate::debouncer::{Debouncer};
+use crate::key_switch::SwitchId;
+use crate::debouncer::{Debouncer, DebounceStrategy};
 use crate::device::{Device, DeviceState};
-use crate::event::EventBuffer;
+use crate::event::{EventBuffer, IndexEvents};
 use heapless::Vec;
 use heapless::consts::U8;
 use core::cell::RefCell;
@@ -20,22 +20,43 @@ use embedded_hal::blocking::i2c::{Write, WriteRead};
 pub struct TCA9554<I2C, E> {
     dev_addr: u8,
     debouncer: RefCell<Debouncer<U8>>,
-    switches: Vec<&'static KeySwitch, U8>,
+    switches: Vec<Option<SwitchId>, U8>,
     phantom0: PhantomData<I2C>,
     phantom1: PhantomData<E>
 }
 
 impl<I2C, E> TCA9554<I2C, E> {
 
+    /// debounceの単位はミリ秒
     pub fn new(addr: u8, debounce: u16) -> Self {
         Self {
             dev_addr: 0x20u8 + addr,
             debouncer: RefCell::new(Debouncer::new(debounce)),
-            switches: Vec::default(),
+            switches: Vec::from_slice(&[None; 8]).unwrap(),
             phantom0: Default::default(),
             phantom1: Default::default()
         }
     }
+
+    /// チャタリング除去の方式を変える
+    pub fn set_debounce_strategy(&mut self, strategy: DebounceStrategy) -> &mut Self {
+        self.debouncer.get_mut().set_strategy(strategy);
+        self
+    }
+
+    fn to_events(&self, indexes: IndexEvents) -> EventBuffer {
+        let mut event_buffer = EventBuffer::new();
+        for idx in indexes.buffer {
+            let event = match idx {
+                PressedAt(i) => self.switches[i].map(Pressed),
+                ReleasedAt(i) => self.switches[i].map(Released)
+            };
+            if let Some(e) = event {
+                let _ = event_buffer.buffer.push(e);
+            }
+        }
+        event_buffer
+    }
 }
 
 /// I2Cの実装がMCU（チップセット）毎にバラバラなので、エラーの型をジェネリクスのパラメータで渡す形になってしまう
@@ -46,10 +67,13 @@ impl<I2C, E> Device<I2C, E> for TCA9554<I2C, E>
 {
 
 
+    fn address(&self) -> u8 {
+        self.dev_addr
+    }
+
     fn init_device(&self, i2c: &mut I2C) -> Result<(), E> {
-        // All input
-        i2c.write(self.dev_addr, &[0x06_u8, 0xFF_u8])?;
-        i2c.write(self.dev_addr, &[0x07_u8, 0xFF_u8])
+        // All input（TCA9554のConfigurationレジスタは0x03）
+        i2c.write(self.dev_addr, &[0x03_u8, 0xFF_u8])
     }
 
     fn read_device(&self, i2c: &mut I2C) -> Result<DeviceState, E> {
@@ -59,16 +83,16 @@ impl<I2C, E> Device<I2C, E> for TCA9554<I2C, E>
 
         let mut pressed = [false;  8];
         let mut mask = 0x01_u8;
-        for j in 0..8 {
-            pressed[j] = mask & data[0] == 0;    // スイッチが押されていたらLowなので0
+        for p in pressed.iter_mut() {
+            *p = mask & data[0] == 0;    // スイッチが押されていたらLowなので0
             mask <<= 1;
         }
         Ok(Pins8(pressed))
     }
 
-    fn assign(&mut self, pin: usize, switch: &'static KeySwitch) -> Result<usize, usize> {
+    fn assign(&mut self, pin: usize, switch: SwitchId) -> Result<usize, usize> {
         if pin < 8 {
-            self.switches[pin] = switch;
+            self.switches[pin] = Some(switch);
             Ok(pin)
         } else {
             Err(pin)
@@ -76,19 +100,24 @@ impl<I2C, E> Device<I2C, E> for TCA9554<I2C, E>
     }
 
     fn has_assigned(&self) -> bool {
-        self.switches.iter().any(|s| s.actions.len() > 0)
+        self.switches.iter().any(|s| s.is_some())
     }
 
-    fn pick_events(&self, pins: &[bool]) -> EventBuffer {
-        let mut event_buffer = EventBuffer::new();
-        let indexes = self.debouncer.borrow_mut().events(pins);
-        for idx in indexes.buffer {
-            let event = match idx {
-                PressedAt(i) => Pressed(&self.switches.get(i).unwrap()),
-                ReleasedAt(i) => Released(&self.switches.get(i).unwrap())
-            };
-            let _ = event_buffer.buffer.push(event);
-        }
-        event_buffer
+    fn switch_at(&self, pin: usize) -> Option<SwitchId> {
+        self.switches.get(pin).copied().flatten()
+    }
+
+    fn pick_events(&self, pins: &[bool], now: u32) -> EventBuffer {
+        let indexes = self.debouncer.borrow_mut().events(pins, now);
+        self.to_events(indexes)
+    }
+
+    fn is_settled(&self) -> bool {
+        self.debouncer.borrow().is_settled()
+    }
+
+    fn release_all(&self) -> EventBuffer {
+        let indexes = self.debouncer.borrow_mut().reset();
+        self.to_events(indexes)
     }
 }
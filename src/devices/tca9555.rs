@@ -2,10 +2,10 @@
 // All right reserved.
 //
 
-use crate::key_switch::KeySwitch;
-use crate::debouncer::{Debouncer};
+use crate::key_switch::SwitchId;
+use crate::debouncer::{Debouncer, DebounceStrategy};
 use crate::device::{Device, DeviceState};
-use crate::event::EventBuffer;
+use crate::event::{EventBuffer, IndexEvents};
 use heapless::Vec;
 use heapless::consts::U16;
 use core::cell::RefCell;
@@ -20,22 +20,43 @@ use embedded_hal::blocking::i2c::{Write, WriteRead};
 pub struct TCA9555<I2C, E> {
     dev_addr: u8,
     debouncer: RefCell<Debouncer<U16>>,
-    switches: Vec<&'static KeySwitch, U16>,
+    switches: Vec<Option<SwitchId>, U16>,
     phantom0: PhantomData<I2C>,
     phantom1: PhantomData<E>
 }
 
 impl<I2C, E> TCA9555<I2C, E> {
 
+    /// debounceの単位はミリ秒
     pub fn new(addr: u8, debounce: u16) -> Self {
         Self {
             dev_addr: 0x20_u8 + addr,
             debouncer: RefCell::new(Debouncer::new(debounce)),
-            switches: Vec::default(),
+            switches: Vec::from_slice(&[None; 16]).unwrap(),
             phantom0: Default::default(),
             phantom1: Default::default()
         }
     }
+
+    /// チャタリング除去の方式を変える
+    pub fn set_debounce_strategy(&mut self, strategy: DebounceStrategy) -> &mut Self {
+        self.debouncer.get_mut().set_strategy(strategy);
+        self
+    }
+
+    fn to_events(&self, indexes: IndexEvents) -> EventBuffer {
+        let mut event_buffer = EventBuffer::new();
+        for idx in indexes.buffer {
+            let event = match idx {
+                PressedAt(i) => self.switches[i].map(Pressed),
+                ReleasedAt(i) => self.switches[i].map(Released)
+            };
+            if let Some(e) = event {
+                let _ = event_buffer.buffer.push(e);
+            }
+        }
+        event_buffer
+    }
 }
 
 /// I2Cの実装がMCU（チップセット）毎にバラバラなので、エラーの型をジェネリクスのパラメータで渡す形になってしまう
@@ -45,6 +66,10 @@ impl<I2C, E> Device<I2C, E> for TCA9555<I2C, E>
         I2C: WriteRead<Error = E>
 {
 
+    fn address(&self) -> u8 {
+        self.dev_addr
+    }
+
     fn init_device(&self, i2c: &mut I2C) -> Result<(), E> {
         // All input
         i2c.write(self.dev_addr, &[0x06_u8, 0xFF_u8])?;
@@ -57,20 +82,20 @@ impl<I2C, E> Device<I2C, E> for TCA9555<I2C, E>
         i2c.write_read(self.dev_addr, reg_addr, data)?;
 
         let mut pressed = [false;  16];
-        for i in 0..2 {
+        for (i, d) in data.iter().enumerate() {
             let mut mask = 0x01_u8;
             for j in 0..8 {
                 let index = i * 8 + j;
-                pressed[index] = mask & data[i] == 0;    // スイッチが押されていたらLowなので0
+                pressed[index] = mask & d == 0;    // スイッチが押されていたらLowなので0
                 mask <<= 1;
             }
         }
         Ok(Pins16(pressed))
     }
 
-    fn assign(&mut self, pin: usize, switch: &'static KeySwitch) -> Result<usize, usize> {
+    fn assign(&mut self, pin: usize, switch: SwitchId) -> Result<usize, usize> {
         if pin < 16 {
-            self.switches[pin] = switch;
+            self.switches[pin] = Some(switch);
             Ok(pin)
         } else {
             Err(pin)
@@ -78,19 +103,24 @@ impl<I2C, E> Device<I2C, E> for TCA9555<I2C, E>
     }
 
     fn has_assigned(&self) -> bool {
-        self.switches.iter().any(|s| s.actions.len() > 0)
+        self.switches.iter().any(|s| s.is_some())
     }
 
-    fn pick_events(&self, pins: &[bool]) -> EventBuffer {
-        let mut event_buffer = EventBuffer::new();
-        let indexes = self.debouncer.borrow_mut().events(pins);
-        for idx in indexes.buffer {
-            let event = match idx {
-                PressedAt(i) => Pressed(self.switches.get(i).unwrap().clone()),
-                ReleasedAt(i) => Released(self.switches.get(i).unwrap().clone())
-            };
-            let _ = event_buffer.buffer.push(event);
-        }
-        event_buffer
+    fn switch_at(&self, pin: usize) -> Option<SwitchId> {
+        self.switches.get(pin).copied().flatten()
+    }
+
+    fn pick_events(&self, pins: &[bool], now: u32) -> EventBuffer {
+        let indexes = self.debouncer.borrow_mut().events(pins, now);
+        self.to_events(indexes)
+    }
+
+    fn is_settled(&self) -> bool {
+        self.debouncer.borrow().is_settled()
+    }
+
+    fn release_all(&self) -> EventBuffer {
+        let indexes = self.debouncer.borrow_mut().reset();
+        self.to_events(indexes)
     }
 }
@@ -0,0 +1,141 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use crate::event::EventBuffer;
+use crate::event::KeyEvent::{Pressed, Released};
+use crate::key_switch::SwitchId;
+
+/// 時計回りのステップを割り付けるピン番号
+pub const CLOCKWISE: usize = 0;
+/// 反時計回りのステップを割り付けるピン番号
+pub const COUNTER_CLOCKWISE: usize = 1;
+
+/// # ロータリーエンコーダのカウンタ値の追跡
+///
+/// デバイスが返すカウンタ値（8/16/32bit）の差分を取って、ステップ数に変換する。
+/// カウンタは一周して0に戻ることがあるので、半周未満の変化は近い方向に回ったものとみなす。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    last: Option<u32>,
+    residue: i32,
+    resolution: u8
+}
+
+impl Rotation {
+
+    /// resolutionは1ステップあたりのカウント数（0は1とみなす）
+    pub fn new(resolution: u8) -> Self {
+        Self {
+            last: None,
+            residue: 0,
+            resolution
+        }
+    }
+
+    /// 基準値を忘れる（次の値が新しい基準になる）
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.residue = 0;
+    }
+
+    /// カウンタ値の差分（bitsはカウンタのビット幅）
+    pub fn delta(last: u32, value: u32, bits: u8) -> i32 {
+        let modulo = 1_i64 << bits;
+        let diff = (value as i64 - last as i64).rem_euclid(modulo);
+        if diff >= modulo / 2 {
+            (diff - modulo) as i32
+        } else {
+            diff as i32
+        }
+    }
+
+    /// 新しいカウンタ値からステップ数を求める（正なら時計回り）
+    ///
+    /// 最初の値は基準とするだけで、ステップは発生しない
+    pub fn update(&mut self, value: u32, bits: u8) -> i32 {
+        let steps = match self.last {
+            None => 0,
+            Some(last) => {
+                let resolution = self.resolution.max(1) as i32;
+                self.residue += Self::delta(last, value, bits);
+                let steps = self.residue / resolution;
+                self.residue -= steps * resolution;
+                steps
+            }
+        };
+        self.last = Some(value);
+        steps
+    }
+
+    /// ステップ数をイベントに変換する
+    ///
+    /// 1ステップにつき、押して離す（タップ）のイベントを生成する
+    pub fn events(steps: i32, clockwise: Option<SwitchId>, counter_clockwise: Option<SwitchId>) -> EventBuffer {
+        let mut event_buffer = EventBuffer::new();
+        let switch = if steps > 0 { clockwise } else { counter_clockwise };
+        if let Some(s) = switch {
+            for _ in 0..steps.abs() {
+                if event_buffer.buffer.push(Pressed(s)).is_err() || event_buffer.buffer.push(Released(s)).is_err() {
+                    break;
+                }
+            }
+        }
+        event_buffer
+    }
+}
+
+/// # A/B相（クアドラチャ）信号のデコーダ
+///
+/// 状態遷移表で1カウントずつ数えて、`resolution`カウント毎に1ステップとする。
+/// 2ビット同時に変化するような、ありえない遷移はカウントしない（チャタリング対策）。
+/// A相が先に変化する方向を時計回りとする。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuadratureDecoder {
+    state: Option<u8>,
+    count: i8,
+    resolution: u8
+}
+
+impl QuadratureDecoder {
+
+    /// (前の状態 << 2) | 新しい状態 での変化量（状態は (A << 1) | B）
+    const TRANSITIONS: [i8; 16] = [
+         0, -1,  1,  0,
+         1,  0,  0, -1,
+        -1,  0,  0,  1,
+         0,  1, -1,  0
+    ];
+
+    /// resolutionは1ステップあたりのカウント数（EC11なら大抵4、0は1とみなす）
+    pub fn new(resolution: u8) -> Self {
+        Self {
+            state: None,
+            count: 0,
+            resolution
+        }
+    }
+
+    /// 状態を忘れる（次の状態が新しい基準になる）
+    pub fn reset(&mut self) {
+        self.state = None;
+        self.count = 0;
+    }
+
+    /// ピンの状態からステップ数を求める（正なら時計回り）
+    pub fn update(&mut self, a: bool, b: bool) -> i32 {
+        let new = ((a as u8) << 1) | b as u8;
+        let steps = match self.state {
+            None => 0,
+            Some(old) => {
+                let resolution = self.resolution.clamp(1, 64) as i8;
+                self.count += Self::TRANSITIONS[((old << 2) | new) as usize];
+                let steps = self.count / resolution;
+                self.count -= steps * resolution;
+                steps as i32
+            }
+        };
+        self.state = Some(new);
+        steps
+    }
+}
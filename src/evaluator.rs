@@ -3,74 +3,504 @@
 //
 
 
-use keyberon::action::Action;
-use keyberon::action::Action::*;
+use crate::action::{Action, HoldTapConfig, OneShotHold};
+use crate::action::Action::*;
 use keyberon::key_code::KeyCode;
 use crate::event::KeyEvent;
 use crate::event::KeyEvent::{Released, Pressed};
-use crate::key_switch::KeySwitch;
-use crate::reporter::Reporter;
+use crate::key_switch::{KeySwitches, SwitchId};
+use crate::layers::{LayerState, TriLayer};
+use crate::macros::{MacroPlayer, TextLayout};
+use crate::reporter::{Report, ReportError, ReportQueue, Reporter};
+use crate::keyboard::{KeyboardProtocol, KeyboardReport};
+use crate::consumer::{ConsumerCode, SystemCode};
+use crate::mouse::{MouseButton, MouseConfig, MouseDirection, MouseKeys, MouseReport};
+use crate::keymap::Keymap;
 use heapless::Vec;
-use heapless::consts::U64;
+use heapless::consts::{U4, U8, U16, U64};
 use arraydeque::{ArrayDeque, Wrapping};
 use KeyState::*;
 
+/// コンボの判定を待つ時間（tick）のデフォルト
+pub const DEFAULT_COMBO_TIMEOUT: u16 = 50;
+
+/// # キーリピート
+///
+/// 押してから`delay`（tick）経ったら、`rate`（tick）毎に離して押し直す
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RepeatConfig {
+    pub delay: u16,
+    pub rate: u16
+}
+
+impl RepeatConfig {
+
+    pub const fn new(delay: u16, rate: u16) -> Self {
+        Self {
+            delay,
+            rate
+        }
+    }
+}
+
+/// キーリピートの設定を、どのキーに適用するか
+#[derive(Debug, Clone, Copy)]
+pub enum RepeatTarget {
+    Key(KeyCode),
+    Switch(SwitchId)
+}
+
+/// リーダーキーの後、次のキーを待つ時間（tick）のデフォルト
+pub const DEFAULT_LEADER_TIMEOUT: u16 = 300;
+
+/// # リーダーキーのシーケンス
+///
+/// リーダーキーの後に`keys`の順でタップしたら`action`をタップする。キーは4つまで
+#[derive(Debug, Clone, Copy)]
+pub struct LeaderSequence {
+    pub keys: &'static [KeyCode],
+    pub action: &'static Action
+}
+
+impl LeaderSequence {
+
+    pub const fn new(keys: &'static [KeyCode], action: &'static Action) -> Self {
+        Self {
+            keys,
+            action
+        }
+    }
+}
+
+/// # コンボ（同時押し）
+///
+/// `switches`が全て（順不同で）`timeout`以内に押されたら、それぞれのキーの代わりに`action`を実行する。
+/// どれか1つを離したら`action`も離したことになる。スイッチは8個まで
+#[derive(Debug, Clone, Copy)]
+pub struct Combo {
+    pub switches: &'static [SwitchId],
+    pub action: &'static Action
+}
+
+impl Combo {
+
+    pub const fn new(switches: &'static [SwitchId], action: &'static Action) -> Self {
+        Self {
+            switches,
+            action
+        }
+    }
+
+    fn index_of(&self, switch: SwitchId) -> Option<usize> {
+        self.switches.iter().position(|s| *s == switch)
+    }
+
+    fn contains_all(&self, switches: &[SwitchId]) -> bool {
+        switches.iter().all(|s| self.index_of(*s).is_some())
+    }
+}
+
 pub struct Evaluator {
-    default_layer: usize,
+    layers: LayerState,
     states: Vec<KeyState, U64>,
     waiting: Option<WaitingState>,
-    stacked: ArrayDeque<[Stacked; 16], Wrapping>
+    dancing: Option<DanceState>,
+    stacked: ArrayDeque<[Stacked; 16], Wrapping>,
+    combos: Vec<Combo, U16>,
+    combo_timeout: u16,
+    active_combos: Vec<ActiveCombo, U8>,
+    one_shots: Vec<OneShotState, U8>,
+    last_tap: Option<LastTap>,
+    macros: Vec<MacroPlayer, U4>,
+    text_layout: TextLayout,
+    leader_sequences: Vec<LeaderSequence, U16>,
+    leader_timeout: u16,
+    leading: Option<LeaderState>,
+    key_repeat: Option<RepeatConfig>,
+    repeat_overrides: Vec<(RepeatTarget, Option<RepeatConfig>), U16>,
+    modifier_repeat: bool,
+    repeating: Option<Repeating>,
+    mouse: MouseKeys,
+    /// このtickで動かす量
+    motion: MouseReport,
+    keymap: Keymap,
+    reports: ReportQueue,
+    report_error: Option<ReportError>
 }
 
 impl Evaluator {
 
     pub fn new() -> Self {
         Self {
-            default_layer: 0,
+            layers: LayerState::new(),
             states: Vec::new(),
             waiting: None,
-            stacked: ArrayDeque::new()
+            dancing: None,
+            stacked: ArrayDeque::new(),
+            combos: Vec::new(),
+            combo_timeout: DEFAULT_COMBO_TIMEOUT,
+            active_combos: Vec::new(),
+            one_shots: Vec::new(),
+            last_tap: None,
+            macros: Vec::new(),
+            text_layout: TextLayout::default(),
+            leader_sequences: Vec::new(),
+            leader_timeout: DEFAULT_LEADER_TIMEOUT,
+            leading: None,
+            key_repeat: None,
+            repeat_overrides: Vec::new(),
+            modifier_repeat: false,
+            repeating: None,
+            mouse: MouseKeys::default(),
+            motion: MouseReport::default(),
+            keymap: Keymap::default(),
+            reports: ReportQueue::new(),
+            report_error: None
+        }
+    }
+
+    /// 有効なレイヤ（ビットマスク）
+    pub fn layer_state(&self) -> u32 {
+        let momentary = self
+            .states
+            .iter()
+            .filter_map(KeyState::get_layer)
+            .fold(0, |bits, l| bits | 1_u32.checked_shl(l as u32).unwrap_or(0));
+        self.layers.active(momentary)
+    }
+
+    /// 今のレイヤ（有効なレイヤのうち一番大きいもの）
+    pub fn current_layer(&self) -> usize {
+        LayerState::highest(self.layer_state())
+    }
+
+    pub fn layers(&self) -> &LayerState {
+        &self.layers
+    }
+
+    /// デフォルトレイヤやトグルしたレイヤを、ファームウェアから変える
+    pub fn layers_mut(&mut self) -> &mut LayerState {
+        &mut self.layers
+    }
+
+    /// キースイッチの表とVIA/Remapで書き換えるキーマップ（書き換えたところは、スイッチのアクションより優先する）
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    pub fn keymap(&self) -> &Keymap {
+        &self.keymap
+    }
+
+    pub fn keymap_mut(&mut self) -> &mut Keymap {
+        &mut self.keymap
+    }
+
+    /// キースイッチの表（アクションを書き換えるときに使う）
+    pub fn switches_mut(&mut self) -> &mut KeySwitches {
+        self.keymap.switches_mut()
+    }
+
+    /// トライレイヤを追加
+    pub fn add_tri_layer(&mut self, tri_layer: TriLayer) -> Result<(), TriLayer> {
+        self.layers.add_tri_layer(tri_layer)
+    }
+
+    /// ファームウェアでのキーリピート（Noneならしない。デフォルトはしない）
+    pub fn set_key_repeat(&mut self, config: Option<RepeatConfig>) {
+        self.key_repeat = config;
+    }
+
+    /// キーコードやスイッチ毎のキーリピート（スイッチの設定が優先）
+    pub fn set_key_repeat_for(&mut self, target: RepeatTarget, config: Option<RepeatConfig>) -> Result<(), RepeatTarget> {
+        let found = self.repeat_overrides.iter_mut().find(|(t, _)| t.is_same(&target));
+        match found {
+            Some(o) => {
+                o.1 = config;
+                Ok(())
+            }
+            None => self.repeat_overrides.push((target, config)).map_err(|(t, _)| t)
+        }
+    }
+
+    /// 修飾キーもリピートするか（デフォルトはしない）
+    pub fn set_modifier_repeat(&mut self, repeat: bool) {
+        self.modifier_repeat = repeat;
+    }
+
+    /// マウスキーの速さや加速
+    pub fn set_mouse_config(&mut self, config: MouseConfig) {
+        self.mouse.config = config;
+    }
+
+    /// コンボを追加
+    pub fn add_combo(&mut self, combo: Combo) -> Result<(), Combo> {
+        if combo.switches.is_empty() || combo.switches.len() > 8 {
+            return Err(combo);
         }
+        self.combos.push(combo)
+    }
+
+    /// コンボの判定を待つ時間（tick）
+    pub fn set_combo_timeout(&mut self, timeout: u16) {
+        self.combo_timeout = timeout;
+    }
+
+    /// マクロの文字列を変換するときのキー配列
+    pub fn set_text_layout(&mut self, layout: TextLayout) {
+        self.text_layout = layout;
+    }
+
+    /// マクロを再生中か
+    pub fn is_playing(&self) -> bool {
+        !self.macros.is_empty()
+    }
+
+    /// リーダーキーのシーケンスを追加
+    pub fn add_leader_sequence(&mut self, sequence: LeaderSequence) -> Result<(), LeaderSequence> {
+        if sequence.keys.is_empty() || sequence.keys.len() > 4 {
+            return Err(sequence);
+        }
+        self.leader_sequences.push(sequence)
+    }
+
+    /// リーダーキーの後、次のキーを待つ時間（tick）
+    pub fn set_leader_timeout(&mut self, timeout: u16) {
+        self.leader_timeout = timeout;
+    }
+
+    /// リーダーキーの後のキーを集めているところか
+    pub fn is_leading(&self) -> bool {
+        self.leading.is_some()
     }
 
     pub fn eval(&mut self, event: KeyEvent, reporter: &mut dyn Reporter)  {
         if let Some(stacked) = self.stacked.push_back(event.into()) {
+            self.waiting_into_hold();
+            self.finish_dance();
+            // タップダンスがホールドタップになったときも、ホールドで決める
             self.waiting_into_hold();
             self.unstack(stacked);
         }
-        if self
-            .waiting
-            .as_ref()
-            .map(|w| w.is_corresponding_release(&event))
-            .unwrap_or(false)
-        {
-            self.waiting_into_tap();
-        }
-        reporter.send_codes(&self.keycodes()[..]);
+        self.resolve_waiting();
+        self.reports.push(self.report());
+        self.send_reports(reporter);
     }
 
     pub fn tick(&mut self, reporter: &mut dyn Reporter) {
         self.states = self.states.iter().filter_map(KeyState::tick).collect();
+        self.tick_one_shots();
+        if let Some(r) = &mut self.repeating {
+            r.tick();
+        }
+        let layout = self.text_layout;
+        self.macros.iter_mut().for_each(|m| m.tick(layout));
+        while let Some(p) = self.macros.iter().position(MacroPlayer::is_finished) {
+            self.macros.swap_remove(p);
+        }
+        if let Some(t) = &mut self.last_tap {
+            t.elapsed = t.elapsed.saturating_add(1);
+        }
         self.stacked.iter_mut().for_each(Stacked::tick);
-        match &mut self.waiting {
-            Some(w) => {
-                if w.tick() {
-                    self.waiting_into_hold();
+        if self.dancing.is_some() {
+            self.tick_dance();
+        } else {
+            match &mut self.waiting {
+                Some(w) => {
+                    if w.tick() {
+                        self.waiting_into_hold();
+                    } else {
+                        self.resolve_waiting();
+                    }
+                }
+                None => {
+                    match self.resolve_combo() {
+                        ComboResolution::Undecided => {}
+                        ComboResolution::Fire(index) => self.fire_combo(index),
+                        ComboResolution::NotCombo => {
+                            if let Some(s) = self.stacked.pop_front() {
+                                self.unstack(s);
+                            }
+                        }
+                    }
                 }
             }
-            None => {
-                if let Some(s) = self.stacked.pop_front() {
-                    self.unstack(s);
+            self.tick_leader();
+        }
+        self.motion = self.mouse.tick(self.mouse_directions());
+        self.reports.push(self.report());
+        // 移動量はこのtickの分だけ
+        self.motion = MouseReport::default();
+        self.send_reports(reporter);
+    }
+
+    /// `eval`と`tick`でレポートを送ったときのエラー（送れたか、待っているだけならNone）
+    pub fn report_error(&self) -> Option<ReportError> {
+        self.report_error
+    }
+
+    fn send_reports(&mut self, reporter: &mut dyn Reporter) {
+        self.report_error = match self.reports.flush(reporter) {
+            Err(nb::Error::Other(e)) => Some(e),
+            _ => None
+        };
+    }
+
+    /// 送れていないレポートを送る（`eval`と`tick`でも送っている）
+    ///
+    /// 全部送れたらOk
+    pub fn flush(&mut self, reporter: &mut dyn Reporter) -> nb::Result<(), ReportError> {
+        self.reports.flush(reporter)
+    }
+
+    /// 今のキーボードのレポートを、もう一度送る
+    ///
+    /// ホストがプロトコル（6KRO/NKRO）を切り替えたら、Reporterを切り替えてからこれを呼ぶ
+    pub fn resend(&mut self, reporter: &mut dyn Reporter) -> nb::Result<(), ReportError> {
+        self.reports.resend();
+        self.reports.flush(reporter)
+    }
+
+    /// 今押されているキーの、プロトコルに合わせたレポート
+    pub fn keyboard_report(&self, protocol: KeyboardProtocol) -> KeyboardReport {
+        KeyboardReport::new(protocol, &self.keycodes())
+    }
+
+    /// 送れていないレポートの数
+    pub fn pending_reports(&self) -> usize {
+        self.reports.len()
+    }
+
+    /// 押されているマウスの移動キーの方向
+    fn mouse_directions(&self) -> u8 {
+        self.states.iter().fold(0, |bits, s| match s {
+            MouseMoveKey { direction, .. } => bits | direction.bit(),
+            _ => bits
+        })
+    }
+
+    /// 先頭の押下イベントがコンボになるかどうか
+    fn resolve_combo(&self) -> ComboResolution {
+        let front = match self.stacked.front() {
+            Some(Stacked { event: Pressed(switch), since }) => (*switch, *since),
+            _ => return ComboResolution::NotCombo
+        };
+        let (first, since) = front;
+        if !self.combos.iter().any(|c| c.index_of(first).is_some()) {
+            return ComboResolution::NotCombo;
+        }
+
+        // 続けて押されたキーで、コンボになりうるものを集める
+        let mut pressed: Vec<SwitchId, U8> = Vec::new();
+        let _ = pressed.push(first);
+        let mut interrupted = false;
+        for s in self.stacked.iter().skip(1) {
+            match s.event {
+                Pressed(switch) => {
+                    let mut next = pressed.clone();
+                    if next.push(switch).is_err() || !self.combos.iter().any(|c| c.contains_all(&next)) {
+                        interrupted = true;
+                        break;
+                    }
+                    pressed = next;
+                }
+                Released(switch) => {
+                    if pressed.contains(&switch) {
+                        interrupted = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        let candidates = || self.combos.iter().enumerate().filter(|(_, c)| c.contains_all(&pressed));
+        let complete = candidates().find(|(_, c)| c.switches.len() == pressed.len()).map(|(i, _)| i);
+        let larger = candidates().any(|(_, c)| c.switches.len() > pressed.len());
+        let undecided = larger && !interrupted && since < self.combo_timeout;
+        match complete {
+            _ if undecided => ComboResolution::Undecided,
+            Some(index) => ComboResolution::Fire(index),
+            None => ComboResolution::NotCombo
+        }
+    }
+
+    /// コンボのアクションを実行する（コンボのキーの押下イベントは捨てる）
+    fn fire_combo(&mut self, index: usize) {
+        let combo = self.combos[index];
+        let since = self.stacked.front().map(|s| s.since).unwrap_or(0);
+        let mut taken = 0_u8;
+        self.stacked.retain(|s| {
+            if let Pressed(switch) = s.event {
+                if let Some(i) = combo.index_of(switch) {
+                    if taken & (1 << i) == 0 {
+                        taken |= 1 << i;
+                        return false;
+                    }
                 }
             }
+            true
+        });
+        let _ = self.active_combos.push(ActiveCombo { index, held: taken, ended: false });
+        self.do_action(combo.action, combo.switches[0], since);
+    }
+
+    /// 実行中のコンボのキーが離されたときの処理
+    ///
+    /// コンボのキーだったらtrue（そのキーの離したイベントは、これで処理済み）
+    fn release_combo(&mut self, switch: SwitchId) -> bool {
+        let combos = &self.combos;
+        let found = self.active_combos.iter().position(|a| {
+            combos[a.index].index_of(switch).map(|i| a.held & (1 << i) != 0).unwrap_or(false)
+        });
+        let position = match found {
+            Some(p) => p,
+            None => return false
+        };
+        let combo = self.combos[self.active_combos[position].index];
+        let active = &mut self.active_combos[position];
+        active.held &= !(1 << combo.index_of(switch).unwrap_or(0));
+        if !active.ended {
+            // どれか1つでも離したら、コンボのアクションを離す
+            active.ended = true;
+            self.release_states(combo.switches[0]);
         }
-        reporter.send_codes(&self.keycodes()[..]);
+        if self.active_combos[position].held == 0 {
+            self.active_combos.swap_remove(position);
+        }
+        true
+    }
+
+    /// 今押されているもの
+    pub fn report(&self) -> Report {
+        let mut report = Report::new();
+        report.keys = self.keycodes();
+        for s in self.states.iter() {
+            match s {
+                ConsumerKey { code, .. } if !report.consumer.contains(code) => {
+                    let _ = report.consumer.push(*code);
+                }
+                SystemKey { code, .. } => report.system = Some(*code),
+                MouseButtonKey { button, .. } => report.mouse.buttons |= *button as u8,
+                _ => {}
+            }
+        }
+        report.mouse.x = self.motion.x;
+        report.mouse.y = self.motion.y;
+        report.mouse.wheel = self.motion.wheel;
+        report.mouse.pan = self.motion.pan;
+        report
     }
 
     fn keycodes(&self) -> Vec<KeyCode, U64> {
         let mut codes: Vec<KeyCode, U64> = Vec::new();
+        let gap = self.repeating.filter(|r| r.gap).map(|r| r.keycode);
         for kc in self.states.iter().filter_map(KeyState::keycode) {
-            let _ = codes.push(kc);
+            if gap != Some(kc) {
+                let _ = codes.push(kc);
+            }
+        }
+        for kc in self.macros.iter().flat_map(|m| m.keys().iter()) {
+            let _ = codes.push(*kc);
         }
         codes
     }
@@ -89,70 +519,299 @@ impl Evaluator {
             let tap = w.tap;
             let switch = w.switch;
             self.waiting = None;
+            self.last_tap = Some(LastTap { switch, elapsed: 0 });
             self.do_action(tap, switch, 0);
         }
     }
 
+    /// 積まれているイベントで、ホールドタップが決まるか見る
+    fn resolve_waiting(&mut self) {
+        match self.waiting.as_ref().and_then(|w| w.decide(&self.stacked)) {
+            Some(Decision::Hold) => self.waiting_into_hold(),
+            Some(Decision::Tap) => self.waiting_into_tap(),
+            None => {}
+        }
+    }
+
+    /// タップダンスの途中で積まれたイベントを見る
+    fn tick_dance(&mut self) {
+        while let Some(mut dance) = self.dancing {
+            match self.stacked.front().map(|s| (s.event, s.since)) {
+                Some((Pressed(switch), since)) if switch == dance.switch => {
+                    self.stacked.pop_front();
+                    dance.count += 1;
+                    dance.pressed = true;
+                    dance.remaining = dance.timeout.saturating_sub(since);
+                    self.dancing = Some(dance);
+                }
+                Some((Released(switch), since)) if switch == dance.switch => {
+                    self.stacked.pop_front();
+                    dance.pressed = false;
+                    dance.remaining = dance.timeout.saturating_sub(since);
+                    self.dancing = Some(dance);
+                }
+                Some((Released(_), _)) => {
+                    // タップダンスの前に押されていたキー
+                    if let Some(s) = self.stacked.pop_front() {
+                        self.unstack(s);
+                    }
+                }
+                Some((Pressed(_), _)) => {
+                    // 他のキーが割り込んだ
+                    self.finish_dance();
+                    return;
+                }
+                None => break
+            }
+            if dance.is_last() {
+                self.finish_dance();
+                return;
+            }
+        }
+
+        if let Some(dance) = &mut self.dancing {
+            dance.remaining = dance.remaining.saturating_sub(1);
+            if dance.remaining == 0 {
+                self.finish_dance();
+            }
+        }
+    }
+
+    /// タップダンスの回数を確定して、アクションを実行する
+    fn finish_dance(&mut self) {
+        if let Some(dance) = self.dancing.take() {
+            let action = dance.action();
+            self.do_action(action, dance.switch, 0);
+            if !dance.pressed {
+                // 離したイベントは使ってしまったので、積み直す
+                let _ = self.stacked.push_front(Released(dance.switch).into());
+            }
+        }
+    }
+
     fn unstack(&mut self, stacked: Stacked) {
         match stacked.event {
             Released(switch) => {
-                self.states = self
-                    .states
-                    .iter()
-                    .filter_map(|s| s.release(switch))
-                    .collect()
+                if self.release_combo(switch) || self.release_one_shot(switch) {
+                    return;
+                }
+                self.release_states(switch);
+                self.end_one_shots(switch);
             }
             Pressed(switch) => {
-                let action = self.press_as_action(switch, self.current_layer());
-                self.do_action(action, switch, stacked.since);
+                let action = self.press_as_action(switch);
+                if self.leading.is_some() {
+                    self.lead(&action);
+                } else {
+                    self.do_action(&action, switch, stacked.since);
+                }
             }
         }
     }
 
-    fn press_as_action(&self, switch: &'static KeySwitch, layer: usize) -> &'static Action {
-        let action = switch.action_at(layer);
-        match action {
-            None => &NoOp,
-            Some(Trans) => {
-                if layer != self.default_layer {
-                    self.press_as_action(switch, self.default_layer)
-                } else {
-                    &NoOp
+    /// リーダーキーの後に押されたキーを集める（キーのアクションは実行しない）
+    fn lead(&mut self, action: &Action) {
+        let mut leading = match self.leading.take() {
+            Some(l) => l,
+            None => return
+        };
+        let pushed = match *action {
+            KeyCode(kc) => leading.keys.push(kc).is_ok(),
+            _ => false
+        };
+        let matched = |s: &&LeaderSequence| s.keys.starts_with(&leading.keys);
+        if !pushed || !self.leader_sequences.iter().any(|s| matched(&s)) {
+            // どのシーケンスにもならないので捨てる
+            return;
+        }
+        let longer = self.leader_sequences.iter().filter(matched).any(|s| s.keys.len() > leading.keys.len());
+        leading.remaining = self.leader_timeout;
+        self.leading = Some(leading);
+        if !longer {
+            self.finish_leader();
+        }
+    }
+
+    fn tick_leader(&mut self) {
+        if let Some(leading) = &mut self.leading {
+            leading.remaining = leading.remaining.saturating_sub(1);
+            if leading.remaining == 0 {
+                self.finish_leader();
+            }
+        }
+    }
+
+    /// 集めたキーと一致するシーケンスがあれば、そのアクションをタップする
+    fn finish_leader(&mut self) {
+        let leading = match self.leading.take() {
+            Some(l) => l,
+            None => return
+        };
+        let found = self.leader_sequences.iter().find(|s| s.keys == &leading.keys[..]).map(|s| s.action);
+        if let Some(action) = found {
+            if self.waiting.is_none() && self.dancing.is_none() {
+                self.do_action(action, leading.switch, 0);
+                let _ = self.stacked.push_front(Released(leading.switch).into());
+            }
+        }
+    }
+
+    fn release_states(&mut self, switch: SwitchId) {
+        if self.repeating.map(|r| r.switch == switch).unwrap_or(false) {
+            self.repeating = None;
+        }
+        self.states = self
+            .states
+            .iter()
+            .filter_map(|s| s.release(switch))
+            .collect()
+    }
+
+    /// キーリピートを始める（最後に押したキーだけリピートする）
+    fn start_repeat(&mut self, switch: SwitchId, keycode: KeyCode) {
+        if keycode.is_modifier() && !self.modifier_repeat {
+            return;
+        }
+        let by_switch = self.repeat_overrides.iter().find(|(t, _)| {
+            matches!(t, RepeatTarget::Switch(s) if *s == switch)
+        });
+        let by_key = self.repeat_overrides.iter().find(|(t, _)| {
+            matches!(t, RepeatTarget::Key(k) if *k == keycode)
+        });
+        let config = match by_switch.or(by_key) {
+            Some((_, config)) => *config,
+            None => self.key_repeat
+        };
+        self.repeating = config.map(|config| Repeating {
+            switch,
+            keycode,
+            config,
+            elapsed: 0,
+            gap: false
+        });
+    }
+
+    /// ワンショットのキーが離されたときの処理
+    ///
+    /// ワンショットとして次のキーを待つことになったらtrue（アクションは離さない）
+    fn release_one_shot(&mut self, switch: SwitchId) -> bool {
+        let position = self.one_shots.iter().position(|o| {
+            o.switch == switch && matches!(o.phase, OneShotPhase::Held { .. })
+        });
+        if let Some(p) = position {
+            let one_shot = &mut self.one_shots[p];
+            match one_shot.phase {
+                OneShotPhase::Held { used } if used && one_shot.hold == OneShotHold::Momentary => {
+                    // 普通の修飾キーとして使われた
+                    self.one_shots.swap_remove(p);
+                }
+                _ => {
+                    one_shot.phase = OneShotPhase::Armed { remaining: one_shot.timeout };
+                    return true;
                 }
             }
-            Some(a) => a
         }
+        false
+    }
+
+    /// 修飾キー以外のキーが押されたので、ワンショットを使う
+    fn use_one_shots(&mut self, switch: SwitchId) {
+        for o in self.one_shots.iter_mut() {
+            match o.phase {
+                OneShotPhase::Held { .. } => o.phase = OneShotPhase::Held { used: true },
+                OneShotPhase::Armed { .. } => o.phase = OneShotPhase::Used(switch),
+                OneShotPhase::Used(_) => {}
+            }
+        }
+    }
+
+    /// ワンショットを使ったキーが離されたので、ワンショットを解除する
+    fn end_one_shots(&mut self, switch: SwitchId) {
+        while let Some(p) = self.one_shots.iter().position(|o| {
+            matches!(o.phase, OneShotPhase::Used(s) if s == switch)
+        }) {
+            let one_shot = self.one_shots.swap_remove(p);
+            self.release_states(one_shot.switch);
+        }
+    }
+
+    fn tick_one_shots(&mut self) {
+        for o in self.one_shots.iter_mut() {
+            if let OneShotPhase::Armed { remaining } = o.phase {
+                o.phase = OneShotPhase::Armed { remaining: remaining.saturating_sub(1) };
+            }
+        }
+        while let Some(p) = self.one_shots.iter().position(|o| {
+            matches!(o.phase, OneShotPhase::Armed { remaining: 0 })
+        }) {
+            let one_shot = self.one_shots.swap_remove(p);
+            self.release_states(one_shot.switch);
+        }
+    }
+
+    /// 有効なレイヤを上から見て、透過でないアクションを探す
+    fn press_as_action(&self, switch: SwitchId) -> Action {
+        let mut bits = self.layer_state();
+        while bits != 0 {
+            let layer = LayerState::highest(bits);
+            match self.keymap.action(switch, layer) {
+                Trans => bits &= !(1 << layer),
+                a => return a
+            }
+        }
+        NoOp
     }
 
-    fn do_action(&mut self, action: &Action, switch: &'static KeySwitch, delay: u16) {
-        assert!(self.waiting.is_none());
+    fn do_action(&mut self, action: &Action, switch: SwitchId, delay: u16) {
+        // 決まっていないホールドタップが残っていたら、先にホールドで決める
+        self.waiting_into_hold();
         use Action::*;
         match *action {
             NoOp | Trans => (),
-            HoldTap { timeout, hold, tap } => {
-                let waiting = WaitingState {
+            HoldTap { timeout, hold, tap, config, tap_hold_interval } => {
+                let quick_tap = self.last_tap.map(|t| {
+                    t.switch == switch && t.elapsed.saturating_sub(delay) < tap_hold_interval
+                });
+                if quick_tap.unwrap_or(false) {
+                    // 続けて押したので、長押しでもタップ
+                    self.do_action(tap, switch, delay);
+                    return;
+                }
+                self.waiting = Some(WaitingState {
                     switch,
-                    timeout: timeout.saturating_sub(delay),
+                    timeout,
+                    elapsed: delay,
                     hold,
                     tap,
-                };
-                self.waiting = Some(waiting);
-                if let Some(Stacked { since, .. }) = self
-                    .stacked
-                    .iter()
-                    .find(|s| waiting.is_corresponding_release(&s.event))
-                {
-                    if timeout >= delay - since {
-                        self.waiting_into_tap();
-                    } else {
-                        self.waiting_into_hold();
-                    }
-                }
+                    config
+                });
+                self.resolve_waiting();
             }
             KeyCode(keycode) => {
+                if !action.is_modifier() {
+                    self.use_one_shots(switch);
+                }
                 let _ = self.states.push(NormalKey { switch, keycode });
+                self.start_repeat(switch, keycode);
+            }
+            Consumer(code) => {
+                self.use_one_shots(switch);
+                let _ = self.states.push(ConsumerKey { code, switch });
+            }
+            MouseButton(button) => {
+                self.use_one_shots(switch);
+                let _ = self.states.push(MouseButtonKey { button, switch });
+            }
+            MouseMove(direction) => {
+                let _ = self.states.push(MouseMoveKey { direction, switch });
+            }
+            System(code) => {
+                let _ = self.states.push(SystemKey { code, switch });
             }
             MultipleKeyCodes(v) => {
+                if !action.is_modifier() {
+                    self.use_one_shots(switch);
+                }
                 for &keycode in v {
                     let _ = self.states.push(NormalKey { switch, keycode });
                 }
@@ -166,29 +825,77 @@ impl Evaluator {
                 let _ = self.states.push(LayerModifier { value, switch });
             }
             DefaultLayer(value) => {
-                self.default_layer = value
+                self.layers.set_default_layer(value)
+            }
+            ToggleLayer(value) => {
+                self.layers.toggle(value)
+            }
+            ToLayer(value) => {
+                // 押している間だけのレイヤも無効にする
+                self.states = self.states.iter().filter(|s| s.get_layer().is_none()).cloned().collect();
+                self.layers.move_to(value)
+            }
+            Leader => {
+                self.leading = Some(LeaderState {
+                    switch,
+                    keys: Vec::new(),
+                    remaining: self.leader_timeout
+                });
+            }
+            Macro(steps) => {
+                self.use_one_shots(switch);
+                let _ = self.macros.push(MacroPlayer::new(steps));
+            }
+            TapDance { timeout, taps, holds } => {
+                if !taps.is_empty() {
+                    self.dancing = Some(DanceState {
+                        switch,
+                        timeout,
+                        taps,
+                        holds,
+                        count: 1,
+                        pressed: true,
+                        remaining: timeout.saturating_sub(delay)
+                    });
+                }
+            }
+            OneShot { action, timeout, hold } => {
+                let armed = self.one_shots.iter().position(|o| {
+                    o.switch == switch && matches!(o.phase, OneShotPhase::Armed { .. })
+                });
+                if let Some(p) = armed {
+                    // もう一度タップしたら解除
+                    self.one_shots.swap_remove(p);
+                    self.release_states(switch);
+                    return;
+                }
+                let one_shot = OneShotState {
+                    switch,
+                    timeout,
+                    hold,
+                    phase: OneShotPhase::Held { used: false }
+                };
+                if self.one_shots.push(one_shot).is_ok() {
+                    self.do_action(action, switch, delay);
+                }
             }
-            _ => {}
         }
     }
 
-    fn current_layer(&self) -> usize {
-        let mut iter = self.states.iter().filter_map(KeyState::get_layer);
-        let mut layer = match iter.next() {
-            None => self.default_layer,
-            Some(l) => l,
-        };
-        for l in iter {
-            layer += l;
-        }
-        layer
-    }
+}
+
+impl Default for Evaluator {
+    fn default() -> Self { Evaluator::new() }
 }
 
 #[derive(Debug, Clone, Copy)]
 enum KeyState {
-    NormalKey { keycode: KeyCode, switch: &'static KeySwitch },
-    LayerModifier { value: usize, switch: &'static KeySwitch },
+    NormalKey { keycode: KeyCode, switch: SwitchId },
+    LayerModifier { value: usize, switch: SwitchId },
+    ConsumerKey { code: ConsumerCode, switch: SwitchId },
+    SystemKey { code: SystemCode, switch: SwitchId },
+    MouseButtonKey { button: MouseButton, switch: SwitchId },
+    MouseMoveKey { direction: MouseDirection, switch: SwitchId },
 }
 
 impl KeyState {
@@ -201,14 +908,14 @@ impl KeyState {
     }
 
     fn tick(&self) -> Option<Self> {
-        match *self {
-            _ => Some(*self),
-        }
+        Some(*self)
     }
 
-    fn release(&self, s: &KeySwitch) -> Option<Self> {
+    fn release(&self, s: SwitchId) -> Option<Self> {
         match *self {
             NormalKey { switch, .. } | LayerModifier { switch, .. } if switch == s => None,
+            ConsumerKey { switch, .. } | SystemKey { switch, .. } if switch == s => None,
+            MouseButtonKey { switch, .. } | MouseMoveKey { switch, .. } if switch == s => None,
             _ => Some(*self),
         }
     }
@@ -221,26 +928,188 @@ impl KeyState {
     }
 }
 
+enum ComboResolution {
+    /// まだ分からない（続きのキーを待つ）
+    Undecided,
+    /// コンボになった（値はコンボのインデックス）
+    Fire(usize),
+    /// コンボではない
+    NotCombo
+}
+
+/// 実行中のコンボ
+#[derive(Debug, Copy, Clone)]
+struct ActiveCombo {
+    index: usize,
+    /// まだ離されていないスイッチ（`Combo::switches`のインデックスのビット）
+    held: u8,
+    /// アクションを離したか
+    ended: bool
+}
+
+/// ワンショットの状態
+#[derive(Debug, Copy, Clone)]
+enum OneShotPhase {
+    /// ワンショットのキーが押されている（usedは、その間に他のキーが押されたか）
+    Held { used: bool },
+    /// 次のキーを待っている（remainingは残り時間）
+    Armed { remaining: u16 },
+    /// 次のキーに使われた（そのキーが離されたら解除）
+    Used(SwitchId)
+}
+
+#[derive(Debug, Copy, Clone)]
+struct OneShotState {
+    switch: SwitchId,
+    timeout: u16,
+    hold: OneShotHold,
+    phase: OneShotPhase
+}
+
+/// タップダンスの途中経過
+#[derive(Debug, Copy, Clone)]
+struct DanceState {
+    switch: SwitchId,
+    timeout: u16,
+    taps: &'static [Action],
+    holds: &'static [Action],
+    /// 押した回数
+    count: usize,
+    /// 今押されているか
+    pressed: bool,
+    /// 次のタップを待つ残り時間
+    remaining: u16
+}
+
+impl DanceState {
+
+    /// これ以上待っても結果が変わらないか
+    fn is_last(&self) -> bool {
+        self.count >= self.taps.len() && (!self.pressed || self.hold().is_none())
+    }
+
+    fn hold(&self) -> Option<&'static Action> {
+        self.holds.get(self.count - 1).filter(|a| **a != NoOp)
+    }
+
+    fn action(&self) -> &'static Action {
+        let tap = &self.taps[self.count.min(self.taps.len()) - 1];
+        if self.pressed {
+            self.hold().unwrap_or(tap)
+        } else {
+            tap
+        }
+    }
+}
+
+impl RepeatTarget {
+
+    fn is_same(&self, other: &RepeatTarget) -> bool {
+        match (self, other) {
+            (RepeatTarget::Key(a), RepeatTarget::Key(b)) => a == b,
+            (RepeatTarget::Switch(a), RepeatTarget::Switch(b)) => *a == *b,
+            _ => false
+        }
+    }
+}
+
+/// リピート中のキー
+#[derive(Debug, Clone, Copy)]
+struct Repeating {
+    switch: SwitchId,
+    keycode: KeyCode,
+    config: RepeatConfig,
+    /// 押されてからの時間
+    elapsed: u16,
+    /// このtickは離したことにする
+    gap: bool
+}
+
+impl Repeating {
+
+    fn tick(&mut self) {
+        self.elapsed = self.elapsed.saturating_add(1);
+        let rate = self.config.rate.max(1);
+        self.gap = self.elapsed >= self.config.delay && (self.elapsed - self.config.delay) % rate == 0;
+        if self.elapsed == u16::MAX {
+            // あふれないように、周期を保ったまま戻す
+            self.elapsed = self.config.delay;
+        }
+    }
+}
+
+/// リーダーキーの後のキーを集めている状態
+#[derive(Debug, Clone)]
+struct LeaderState {
+    switch: SwitchId,
+    keys: Vec<KeyCode, U4>,
+    /// 次のキーを待つ残り時間
+    remaining: u16
+}
+
+/// 最後にタップになったホールドタップ
+#[derive(Debug, Copy, Clone)]
+struct LastTap {
+    switch: SwitchId,
+    /// タップになってからの時間
+    elapsed: u16
+}
+
+enum Decision {
+    Hold,
+    Tap
+}
+
 #[derive(Debug, Copy, Clone)]
 struct WaitingState  {
-    switch: &'static KeySwitch,
+    switch: SwitchId,
     timeout: u16,
+    /// 押されてからの時間
+    elapsed: u16,
     hold: &'static Action,
     tap: &'static Action,
+    config: HoldTapConfig
 }
 
 impl WaitingState {
 
     fn tick(&mut self) -> bool {
-        self.timeout = self.timeout.saturating_sub(1);
-        self.timeout == 0
+        self.elapsed = self.elapsed.saturating_add(1);
+        self.elapsed >= self.timeout
     }
 
-    fn is_corresponding_release(&self, event: &KeyEvent) -> bool {
-        match event {
-            KeyEvent::Released(switch) if *switch == self.switch => true,
-            _ => false,
+    /// 押されてから積まれたイベントで決める（決まらなければNone）
+    fn decide(&self, stacked: &ArrayDeque<[Stacked; 16], Wrapping>) -> Option<Decision> {
+        let mut others: Vec<SwitchId, U16> = Vec::new();
+        for s in stacked.iter() {
+            // 押されてから、そのイベントまでの時間
+            let at = self.elapsed.saturating_sub(s.since);
+            match s.event {
+                Released(switch) if switch == self.switch => {
+                    return if at > self.timeout {
+                        Some(Decision::Hold)
+                    } else {
+                        Some(Decision::Tap)
+                    };
+                }
+                Pressed(switch) => {
+                    match self.config {
+                        HoldTapConfig::HoldOnOtherKeyPress => return Some(Decision::Hold),
+                        HoldTapConfig::Balanced if at >= self.timeout / 2 => return Some(Decision::Hold),
+                        _ => {}
+                    }
+                    let _ = others.push(switch);
+                }
+                Released(switch) => {
+                    let nested = others.contains(&switch);
+                    let permissive = matches!(self.config, HoldTapConfig::PermissiveHold | HoldTapConfig::Balanced);
+                    if nested && permissive {
+                        return Some(Decision::Hold);
+                    }
+                }
+            }
         }
+        None
     }
 }
 
@@ -3,21 +3,21 @@
 //
 
 
-use crate::key_switch::KeySwitch;
+use crate::key_switch::SwitchId;
 use heapless::Vec;
 use heapless::consts::U64;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum KeyEvent {
-    Pressed(&'static KeySwitch),
-    Released(&'static KeySwitch)
+    Pressed(SwitchId),
+    Released(SwitchId)
 }
 
 pub struct EventBuffer {
     pub buffer: Vec<KeyEvent, U64>
 }
 
-impl<'a> EventBuffer {
+impl EventBuffer {
     pub fn new() -> Self {
         Self {
             buffer: Vec::new()
@@ -25,6 +25,10 @@ impl<'a> EventBuffer {
     }
 }
 
+impl Default for EventBuffer {
+    fn default() -> Self { EventBuffer::new() }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum IndexEvent {
     PressedAt(usize),
@@ -42,3 +46,7 @@ impl IndexEvents {
         }
     }
 }
+
+impl Default for IndexEvents {
+    fn default() -> Self { IndexEvents::new() }
+}
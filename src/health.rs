@@ -0,0 +1,99 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use heapless::Vec;
+use heapless::consts::U16;
+
+/// 何回続けて読込に失敗したらオフラインとみなすか（デフォルト）
+pub const DEFAULT_FAILURE_LIMIT: u8 = 3;
+
+/// # デバイスの健康状態
+///
+/// 読込に続けて失敗するとオフラインになり、
+/// オフラインのデバイスは`init_device`が成功するとオンラインに戻る
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DeviceHealth {
+    /// 連続して失敗した回数
+    pub failures: u8,
+    /// オンラインかどうか
+    pub online: bool
+}
+
+impl DeviceHealth {
+
+    pub fn new() -> Self {
+        Self {
+            failures: 0,
+            online: true
+        }
+    }
+
+    /// 成功を記録する
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    /// 失敗を記録する
+    ///
+    /// オンラインからオフラインに変わったときにtrue
+    pub fn failed(&mut self, limit: u8) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        if self.online && self.failures >= limit {
+            self.online = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// すぐにオフラインにする（バスにいないことが分かったとき）
+    ///
+    /// オンラインからオフラインに変わったときにtrue
+    pub fn lost(&mut self) -> bool {
+        let changed = self.online;
+        self.online = false;
+        changed
+    }
+
+    /// オンラインに戻す
+    ///
+    /// オフラインからオンラインに変わったときにtrue
+    pub fn recovered(&mut self) -> bool {
+        self.failures = 0;
+        let changed = !self.online;
+        self.online = true;
+        changed
+    }
+}
+
+impl Default for DeviceHealth {
+    fn default() -> Self { DeviceHealth::new() }
+}
+
+/// デバイスの状態の変化（値は`DeviceHolder::devices`のインデックス）
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HealthEvent {
+    /// オフラインになった（押されていたキーは離したことにした）
+    Offline(usize),
+    /// オンラインに戻った（`init_device`をやり直した）
+    Online(usize),
+    /// `DeviceHolder`にないモジュールを見つけた（値はアドレス）
+    Unknown(u8)
+}
+
+pub struct HealthEvents {
+    pub buffer: Vec<HealthEvent, U16>
+}
+
+impl HealthEvents {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new()
+        }
+    }
+}
+
+impl Default for HealthEvents {
+    fn default() -> Self { HealthEvents::new() }
+}
@@ -0,0 +1,327 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # ゆるいJSONのパーサ
+//!
+//! Keyboard Layout Editorのraw-dataや、手で書いたレイアウトのファイルを読むためのもの（`std`フィーチャが必要）。
+//! 普通のJSONに加えて、次のものも受け付ける。
+//!
+//! * クォートしていないオブジェクトのキー（`{x: 1}`）
+//! * シングルクォートの文字列
+//! * 最後の要素の後のカンマ
+//! * `//`から行末までのコメント
+//! * 一番外側の`[]`を省略した配列（KLEのraw-dataはこの形）
+
+use std::fmt;
+use std::string::String;
+use std::vec::Vec;
+
+/// JSONの値
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// 書かれていた順のキーと値
+    Object(Vec<(String, Value)>)
+}
+
+impl Value {
+
+    /// オブジェクトのキーの値
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Value)]> {
+        match self {
+            Value::Object(members) => Some(members),
+            _ => None
+        }
+    }
+}
+
+/// 読めなかった場所（先頭からのバイト数）と理由
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: &'static str
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// 文字列全体を1つの値として読む
+///
+/// カンマで区切られた値が並んでいたら、`[]`で囲まれていたものとして配列にする
+pub fn parse(text: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser { text, pos: 0 };
+    let first = parser.value()?;
+    parser.skip_spaces();
+    if parser.eat(b',') {
+        let mut items = vec![first];
+        loop {
+            parser.skip_spaces();
+            if parser.peek().is_none() {
+                break;
+            }
+            items.push(parser.value()?);
+            parser.skip_spaces();
+            if !parser.eat(b',') {
+                break;
+            }
+        }
+        parser.skip_spaces();
+        parser.end()?;
+        return Ok(Value::Array(items));
+    }
+    parser.end()?;
+    Ok(first)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize
+}
+
+impl Parser<'_> {
+
+    fn error<T>(&self, message: &'static str) -> Result<T, ParseError> {
+        Err(ParseError { offset: self.pos, message })
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn end(&self) -> Result<(), ParseError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => self.error("unexpected character")
+        }
+    }
+
+    /// 空白とコメントを読み飛ばす
+    fn skip_spaces(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') => self.pos += 1,
+                Some(b'/') if self.text[self.pos..].starts_with("//") => {
+                    self.pos = self.text[self.pos..].find('\n').map(|i| self.pos + i).unwrap_or(self.text.len());
+                }
+                _ => return
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.skip_spaces();
+        match self.peek() {
+            Some(b'[') => self.array(),
+            Some(b'{') => self.object(),
+            Some(b'"') | Some(b'\'') => self.string().map(Value::String),
+            Some(b'-') | Some(b'+') | Some(b'.') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => match self.word() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                "null" => Ok(Value::Null),
+                _ => self.error("unknown word")
+            },
+            None => self.error("unexpected end")
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_spaces();
+            if self.eat(b']') {
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_spaces();
+            if !self.eat(b',') {
+                self.skip_spaces();
+                if self.eat(b']') {
+                    return Ok(Value::Array(items));
+                }
+                return self.error("expected ',' or ']'");
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.pos += 1;
+        let mut members = Vec::new();
+        loop {
+            self.skip_spaces();
+            if self.eat(b'}') {
+                return Ok(Value::Object(members));
+            }
+            let key = match self.peek() {
+                Some(b'"') | Some(b'\'') => self.string()?,
+                _ => {
+                    let word = self.word();
+                    if word.is_empty() {
+                        return self.error("expected key");
+                    }
+                    String::from(word)
+                }
+            };
+            self.skip_spaces();
+            if !self.eat(b':') {
+                return self.error("expected ':'");
+            }
+            let value = self.value()?;
+            members.push((key, value));
+            self.skip_spaces();
+            if !self.eat(b',') {
+                self.skip_spaces();
+                if self.eat(b'}') {
+                    return Ok(Value::Object(members));
+                }
+                return self.error("expected ',' or '}'");
+            }
+        }
+    }
+
+    /// クォートしていない識別子（キーやtrueなど）
+    fn word(&mut self) -> &str {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == b'_' || c == b'-' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        &self.text[start..self.pos]
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() || c == b'-' || c == b'+' || c == b'.' || c == b'e' || c == b'E' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        match self.text[start..self.pos].parse::<f64>() {
+            Ok(n) => Ok(Value::Number(n)),
+            Err(_) => Err(ParseError { offset: start, message: "invalid number" })
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        let quote = self.peek().unwrap_or(b'"') as char;
+        self.pos += 1;
+        let mut result = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    let escaped = match chars.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, c)) if matches!(c, '"' | '\'' | '\\' | '/') => c,
+                        Some((j, 'u')) => {
+                            let at = self.pos + j + 1;
+                            let mut code = self.hex4(at)?;
+                            for _ in 0..4 {
+                                chars.next();
+                            }
+                            if (0xD800..0xDC00).contains(&code) {
+                                // サロゲートペアは、続く下位サロゲートと合わせて1文字にする
+                                let low = if self.text[at + 4..].starts_with("\\u") { self.hex4(at + 6)? } else { 0 };
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(ParseError { offset: self.pos + j, message: "invalid escape" });
+                                }
+                                for _ in 0..6 {
+                                    chars.next();
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            match char::from_u32(code) {
+                                Some(c) => c,
+                                None => return Err(ParseError { offset: self.pos + j, message: "invalid escape" })
+                            }
+                        }
+                        Some((j, _)) => return Err(ParseError { offset: self.pos + j, message: "invalid escape" }),
+                        None => break
+                    };
+                    result.push(escaped);
+                }
+                c if c == quote => {
+                    self.pos += i + 1;
+                    return Ok(result);
+                }
+                c => result.push(c)
+            }
+        }
+        self.pos = self.text.len();
+        self.error("unterminated string")
+    }
+
+    /// `\u`の後の16進4桁
+    fn hex4(&self, at: usize) -> Result<u32, ParseError> {
+        match self.text.get(at..at + 4) {
+            Some(hex) if hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                u32::from_str_radix(hex, 16).map_err(|_| ParseError { offset: at, message: "invalid escape" })
+            }
+            _ => Err(ParseError { offset: at, message: "invalid escape" })
+        }
+    }
+}
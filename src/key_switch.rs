@@ -2,10 +2,11 @@
 // All right reserved.
 //
 
-use keyberon::action::Action;
+use crate::action::Action;
 use heapless::Vec;
-use heapless::consts::U4;
-use keyberon::action::Action::{NoOp, Trans};
+use heapless::consts::{U4, U128};
+use crate::action::Action::{NoOp, Trans};
+use crate::keymap::{to_keycode, UNSUPPORTED_KEYCODE};
 
 /// # キーの形状
 ///
@@ -61,8 +62,8 @@ impl Position {
 /// x, y, w, hの単位は、いわゆる1u。
 ///
 /// Keyboard Layout Editor のraw-dataは、右に進み、改行時に左端に戻るという規則に
-/// タートル・グラフィックスの要素を加えたものなので、ここでの仕様とかなり違うけど、
-/// 変換処理実装時に頑張る
+/// タートル・グラフィックスの要素を加えたものなので、ここでの仕様とかなり違う。
+/// 変換は`kle`モジュールで行う
 #[derive(Debug, Eq, PartialEq)]
 pub struct KeySwitch {
     pub shape: Shape,
@@ -174,19 +175,107 @@ impl KeySwitch {
     }
 
     /// レイヤを指定してアクションを取得
-    pub fn action_at(&'static self, layer: usize) -> Option<&'static Action> {
+    pub fn action_at(&self, layer: usize) -> Option<&Action> {
         if layer < self.actions.len() {
             Some(&self.actions[layer])
         } else {
             None
         }
     }
+
+    /// レイヤを指定してアクションを取得（そのレイヤのアクションがなければデフォルトアクション）
+    pub fn action_or_default(&self, layer: usize) -> &Action {
+        self.action_at(layer).unwrap_or(&self.default_action)
+    }
 }
 
 impl Default for KeySwitch {
     fn default() -> Self { KeySwitch::dummy() }
 }
 
+/// `KeySwitches`に入るスイッチの数
+pub const MAX_SWITCHES: usize = 128;
+
+/// 1つのスイッチに持てるアクション（レイヤ）の数
+pub const MAX_SWITCH_ACTIONS: usize = 4;
+
+/// # スイッチの番号
+///
+/// `KeySwitches`に追加した順の番号。イベントやデバイスは、スイッチをこれで指す
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct SwitchId(pub u16);
+
+impl SwitchId {
+
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// # キースイッチの表
+///
+/// キースイッチの定義を持っていて、`SwitchId`で引く。
+/// `Evaluator`が（`Keymap`として）持つので、後からアクションを書き換えられる
+pub struct KeySwitches {
+    switches: Vec<KeySwitch, U128>,
+    /// 追加したときのアクションのキーコード
+    defaults: Vec<[u16; MAX_SWITCH_ACTIONS], U128>
+}
+
+impl KeySwitches {
+
+    pub fn new() -> Self {
+        Self {
+            switches: Vec::new(),
+            defaults: Vec::new()
+        }
+    }
+
+    /// スイッチを追加（いっぱいならNone）
+    ///
+    /// このときのアクションを、ファームウェアのデフォルトとして覚えておく
+    pub fn add(&mut self, switch: KeySwitch) -> Option<SwitchId> {
+        let id = SwitchId(self.switches.len() as u16);
+        let mut defaults = [UNSUPPORTED_KEYCODE; MAX_SWITCH_ACTIONS];
+        for (code, action) in defaults.iter_mut().zip(switch.actions.iter()) {
+            *code = to_keycode(action);
+        }
+        self.switches.push(switch).ok()?;
+        let _ = self.defaults.push(defaults);
+        Some(id)
+    }
+
+    /// 追加したときのアクションのキーコード（アクションがないところや、表せないものは`UNSUPPORTED_KEYCODE`）
+    pub fn default_keycodes(&self, id: SwitchId) -> Option<&[u16; MAX_SWITCH_ACTIONS]> {
+        self.defaults.get(id.index())
+    }
+
+    pub fn get(&self, id: SwitchId) -> Option<&KeySwitch> {
+        self.switches.get(id.index())
+    }
+
+    /// アクションを書き換えるときに使う
+    pub fn get_mut(&mut self, id: SwitchId) -> Option<&mut KeySwitch> {
+        self.switches.get_mut(id.index())
+    }
+
+    pub fn len(&self) -> usize {
+        self.switches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.switches.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SwitchId, &KeySwitch)> {
+        self.switches.iter().enumerate().map(|(i, s)| (SwitchId(i as u16), s))
+    }
+}
+
+impl Default for KeySwitches {
+    fn default() -> Self { KeySwitches::new() }
+}
+
 #[macro_export]
 macro_rules! switch_pool {
     ($(#[$top_attr:meta])* struct $Type:ident,
@@ -198,21 +287,21 @@ macro_rules! switch_pool {
             pub struct $Type {
                 $(
                     $(#[$attr])*
-                    pub $name: KeySwitch
+                    pub $name: $crate::key_switch::SwitchId
                 ),+
             }
         }
 
         impl $Type {
-            /// Returns the pins for the device
+            /// Adds the switches to the table and returns their ids
             paste::item! {
-                pub fn new() -> Self {
-                    $Type {
+                pub fn new(switches: &mut $crate::key_switch::KeySwitches) -> Option<Self> {
+                    Some($Type {
                         $(
                         $(#[$attr])*
-                        $name: $switch_expr
+                        $name: switches.add($switch_expr)?
                         ),+
-                    }
+                    })
                 }
             }
         }
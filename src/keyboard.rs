@@ -0,0 +1,119 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # キーボードのHIDレポート
+//!
+//! ブートプロトコル（6KRO）とNKRO（ビットマップ）の2種類。
+//! どちらを使うかはホストとのネゴシエーション次第なので、Reporterが実行時に切り替える
+
+use keyberon::key_code::KeyCode;
+
+/// NKROのビットマップのバイト数（0x00〜0xEFのキーを扱える）
+pub const NKRO_BITMAP_BYTES: usize = 30;
+
+/// キーボードのプロトコル
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeyboardProtocol {
+    /// ブートプロトコル（6KRO）。BIOSなどはこちらしか使えない
+    Boot,
+    /// NKRO
+    Nkro
+}
+
+impl Default for KeyboardProtocol {
+    fn default() -> Self { KeyboardProtocol::Boot }
+}
+
+/// # ブートプロトコルのレポート
+///
+/// 修飾キー、予約、キー6つの8バイト。7つ以上押されていたら、キーは全部`ErrorRollOver`にする
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub struct BootReport {
+    pub bytes: [u8; 8]
+}
+
+impl BootReport {
+
+    pub fn new(codes: &[KeyCode]) -> Self {
+        let mut bytes = [0_u8; 8];
+        let mut count = 0;
+        for kc in codes.iter() {
+            if kc.is_modifier() {
+                bytes[0] |= kc.as_modifier_bit();
+            } else if *kc != KeyCode::No && !bytes[2..2 + count].contains(&(*kc as u8)) {
+                if count == 6 {
+                    for b in bytes[2..].iter_mut() {
+                        *b = KeyCode::ErrorRollOver as u8;
+                    }
+                    break;
+                }
+                bytes[2 + count] = *kc as u8;
+                count += 1;
+            }
+        }
+        Self { bytes }
+    }
+}
+
+/// # NKROのレポート
+///
+/// 先頭が修飾キー、その後がキーコード毎のビット
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct NkroReport {
+    pub bytes: [u8; NKRO_BITMAP_BYTES + 1]
+}
+
+impl NkroReport {
+
+    pub fn new(codes: &[KeyCode]) -> Self {
+        let mut bytes = [0_u8; NKRO_BITMAP_BYTES + 1];
+        for kc in codes.iter() {
+            let code = *kc as usize;
+            if kc.is_modifier() {
+                bytes[0] |= kc.as_modifier_bit();
+            } else if *kc != KeyCode::No && code < NKRO_BITMAP_BYTES * 8 {
+                bytes[1 + code / 8] |= 1 << (code % 8);
+            }
+        }
+        Self { bytes }
+    }
+
+    /// キーが押されているか
+    pub fn is_pressed(&self, kc: KeyCode) -> bool {
+        let code = kc as usize;
+        if kc.is_modifier() {
+            self.bytes[0] & kc.as_modifier_bit() != 0
+        } else {
+            code < NKRO_BITMAP_BYTES * 8 && self.bytes[1 + code / 8] & (1 << (code % 8)) != 0
+        }
+    }
+}
+
+impl Default for NkroReport {
+    fn default() -> Self { NkroReport::new(&[]) }
+}
+
+/// プロトコルに合わせたレポート
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeyboardReport {
+    Boot(BootReport),
+    Nkro(NkroReport)
+}
+
+impl KeyboardReport {
+
+    pub fn new(protocol: KeyboardProtocol, codes: &[KeyCode]) -> Self {
+        match protocol {
+            KeyboardProtocol::Boot => KeyboardReport::Boot(BootReport::new(codes)),
+            KeyboardProtocol::Nkro => KeyboardReport::Nkro(NkroReport::new(codes))
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            KeyboardReport::Boot(r) => &r.bytes,
+            KeyboardReport::Nkro(r) => &r.bytes
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # 書き換えられるキーマップ
+//!
+//! makbeにはRow, Colがないので、`DeviceHolder`のデバイスのインデックスをrow、
+//! デバイスのピンをcolとした仮想的なマトリクスを作って、VIA/Remapからはそれで指定してもらう。
+//!
+//! キーコードはVIA（QMK）の16bitの値。
+//! 書き換えられるのは、参照を持たないアクション（キーコード、レイヤ、メディアキー、マウスなど）だけ。
+//! それ以外のアクションを変えたいときは、`KeySwitches`のスイッチを直接書き換える
+
+use keyberon::key_code::KeyCode;
+use keyberon::key_code::KeyCode::*;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use crate::action::Action;
+use crate::consumer::{ConsumerCode, SystemCode};
+use crate::device::DeviceHolder;
+use crate::key_switch::{KeySwitches, SwitchId};
+use crate::mouse::{MouseButton, MouseDirection};
+
+/// 書き換えられるレイヤの数
+pub const KEYMAP_LAYERS: usize = 4;
+/// 仮想マトリクスの行の数（デバイスの数）
+pub const KEYMAP_ROWS: usize = 8;
+/// 仮想マトリクスの列の数（デバイスのピンの数）
+pub const KEYMAP_COLS: usize = 16;
+
+/// VIAのキーコードで表せないアクション
+///
+/// これを書き込むと、スイッチに元々割り付けられていたアクションに戻る
+pub const UNSUPPORTED_KEYCODE: u16 = 0xFFFF;
+
+/// # 仮想マトリクス
+///
+/// rowはデバイスのインデックス、colはピン
+pub struct VirtualMatrix {
+    switches: [[Option<SwitchId>; KEYMAP_COLS]; KEYMAP_ROWS],
+    rows: usize
+}
+
+impl VirtualMatrix {
+
+    pub fn new() -> Self {
+        Self {
+            switches: [[None; KEYMAP_COLS]; KEYMAP_ROWS],
+            rows: 0
+        }
+    }
+
+    /// デバイスに割り付けられているスイッチから作る
+    pub fn from_devices<I2C, E>(holder: &DeviceHolder<I2C, E>) -> Self
+        where
+            I2C: Write<Error = E>,
+            I2C: WriteRead<Error = E>
+    {
+        let mut matrix = Self::new();
+        for (row, device) in holder.devices.iter().take(KEYMAP_ROWS).enumerate() {
+            for col in 0..KEYMAP_COLS {
+                matrix.switches[row][col] = device.switch_at(col);
+            }
+            matrix.rows = row + 1;
+        }
+        matrix
+    }
+
+    /// 行の数
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn switch_at(&self, row: usize, col: usize) -> Option<SwitchId> {
+        self.switches.get(row)?.get(col).copied().flatten()
+    }
+
+    /// スイッチの位置（row, col）
+    pub fn position_of(&self, switch: SwitchId) -> Option<(usize, usize)> {
+        for (row, cols) in self.switches.iter().enumerate().take(self.rows) {
+            if let Some(col) = cols.iter().position(|s| *s == Some(switch)) {
+                return Some((row, col));
+            }
+        }
+        None
+    }
+}
+
+impl Default for VirtualMatrix {
+    fn default() -> Self { VirtualMatrix::new() }
+}
+
+/// # キーマップ
+///
+/// キースイッチの表と、VIAで書き換えたキーコードを持つ。
+/// 書き換えていないところはスイッチのアクションを使う
+pub struct Keymap {
+    switches: KeySwitches,
+    matrix: VirtualMatrix,
+    codes: [[[u16; KEYMAP_COLS]; KEYMAP_ROWS]; KEYMAP_LAYERS]
+}
+
+impl Keymap {
+
+    pub fn new(switches: KeySwitches, matrix: VirtualMatrix) -> Self {
+        Self {
+            switches,
+            matrix,
+            codes: [[[UNSUPPORTED_KEYCODE; KEYMAP_COLS]; KEYMAP_ROWS]; KEYMAP_LAYERS]
+        }
+    }
+
+    pub fn switches(&self) -> &KeySwitches {
+        &self.switches
+    }
+
+    pub fn switches_mut(&mut self) -> &mut KeySwitches {
+        &mut self.switches
+    }
+
+    pub fn matrix(&self) -> &VirtualMatrix {
+        &self.matrix
+    }
+
+    /// デバイスにスイッチを割り付けた後で、仮想マトリクスを作り直す
+    pub fn set_matrix(&mut self, matrix: VirtualMatrix) {
+        self.matrix = matrix;
+    }
+
+    /// VIAのキーコード（書き換えていなければ、スイッチのアクションから求める）
+    pub fn keycode(&self, layer: usize, row: usize, col: usize) -> u16 {
+        if layer >= KEYMAP_LAYERS || row >= KEYMAP_ROWS || col >= KEYMAP_COLS {
+            return 0;
+        }
+        let code = self.codes[layer][row][col];
+        if code != UNSUPPORTED_KEYCODE {
+            return code;
+        }
+        match self.matrix.switch_at(row, col).and_then(|id| self.switches.get(id)) {
+            Some(switch) => to_keycode(switch.action_or_default(layer)),
+            None => 0
+        }
+    }
+
+    /// キーコードを書き換える（範囲外ならfalse）
+    pub fn set_keycode(&mut self, layer: usize, row: usize, col: usize, code: u16) -> bool {
+        if layer >= KEYMAP_LAYERS || row >= KEYMAP_ROWS || col >= KEYMAP_COLS {
+            return false;
+        }
+        self.codes[layer][row][col] = code;
+        true
+    }
+
+    /// 書き換えたものを全部元に戻す
+    pub fn reset(&mut self) {
+        self.codes = [[[UNSUPPORTED_KEYCODE; KEYMAP_COLS]; KEYMAP_ROWS]; KEYMAP_LAYERS];
+    }
+
+    /// 書き換えたキーコード（レイヤ、row、col、キーコード）
+    pub fn remapped(&self) -> impl Iterator<Item = (usize, usize, usize, u16)> + '_ {
+        self.codes.iter().enumerate().flat_map(|(layer, rows)| {
+            rows.iter().enumerate().flat_map(move |(row, cols)| {
+                cols.iter().enumerate()
+                    .filter(|(_, code)| **code != UNSUPPORTED_KEYCODE)
+                    .map(move |(col, code)| (layer, row, col, *code))
+            })
+        })
+    }
+
+    /// レイヤでのスイッチのアクション（書き換えたものが優先。スイッチがなければ`NoOp`）
+    pub fn action(&self, switch: SwitchId, layer: usize) -> Action {
+        match self.remapped_action(switch, layer) {
+            Some(action) => action,
+            None => self.switches.get(switch).map(|s| *s.action_or_default(layer)).unwrap_or(Action::NoOp)
+        }
+    }
+
+    /// 書き換えたアクション（書き換えていないか、makbeで扱えないキーコードならNone）
+    pub fn remapped_action(&self, switch: SwitchId, layer: usize) -> Option<Action> {
+        if layer >= KEYMAP_LAYERS {
+            return None;
+        }
+        let (row, col) = self.matrix.position_of(switch)?;
+        match self.codes[layer][row][col] {
+            UNSUPPORTED_KEYCODE => None,
+            code => from_keycode(code)
+        }
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self { Keymap::new(KeySwitches::new(), VirtualMatrix::new()) }
+}
+
+/// VIAのキーコード（0x00〜0xA4）
+const BASIC_KEY_CODES: [KeyCode; 0xA5] = [
+    No, ErrorRollOver, PostFail, ErrorUndefined, A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q,
+    R, S, T, U, V, W, X, Y, Z, Kb1, Kb2, Kb3, Kb4, Kb5, Kb6, Kb7, Kb8, Kb9, Kb0, Enter, Escape,
+    BSpace, Tab, Space, Minus, Equal, LBracket, RBracket, Bslash, NonUsHash, SColon, Quote, Grave,
+    Comma, Dot, Slash, CapsLock, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, PScreen,
+    ScrollLock, Pause, Insert, Home, PgUp, Delete, End, PgDown, Right, Left, Down, Up, NumLock,
+    KpSlash, KpAsterisk, KpMinus, KpPlus, KpEnter, Kp1, Kp2, Kp3, Kp4, Kp5, Kp6, Kp7, Kp8, Kp9,
+    Kp0, KpDot, NonUsBslash, Application, Power, KpEqual, F13, F14, F15, F16, F17, F18, F19, F20,
+    F21, F22, F23, F24, Execute, Help, Menu, Select, Stop, Again, Undo, Cut, Copy, Paste, Find,
+    Mute, VolUp, VolDown, LockingCapsLock, LockingNumLock, LockingScrollLock, KpComma, KpEqualSign,
+    Intl1, Intl2, Intl3, Intl4, Intl5, Intl6, Intl7, Intl8, Intl9, Lang1, Lang2, Lang3, Lang4,
+    Lang5, Lang6, Lang7, Lang8, Lang9, AltErase, SysReq, Cancel, Clear, Prior, Return, Separator,
+    Out, Oper, ClearAgain, CrSel, ExSel
+];
+
+/// VIAのキーコード（0xE0〜0xE7）
+const MODIFIER_KEY_CODES: [KeyCode; 8] = [
+    LCtrl, LShift, LAlt, LGui, RCtrl, RShift, RAlt, RGui
+];
+
+const KC_TRANSPARENT: u16 = 0x0001;
+const KC_SYSTEM_POWER: u16 = 0x00A5;
+const KC_AUDIO_MUTE: u16 = 0x00A8;
+const KC_MS_UP: u16 = 0x00F0;
+const KC_MS_BTN1: u16 = 0x00F4;
+const KC_MS_WH_UP: u16 = 0x00F9;
+const QK_TO: u16 = 0x5010;
+const QK_MOMENTARY: u16 = 0x5100;
+const QK_DEF_LAYER: u16 = 0x5200;
+const QK_TOGGLE_LAYER: u16 = 0x5300;
+
+const SYSTEM_CODES: [SystemCode; 3] = [SystemCode::PowerDown, SystemCode::Sleep, SystemCode::WakeUp];
+
+/// 0x00A8〜0x00BEの順（makbeにないものはNone）
+const CONSUMER_CODES: [Option<ConsumerCode>; 23] = [
+    Some(ConsumerCode::Mute), Some(ConsumerCode::VolumeUp), Some(ConsumerCode::VolumeDown),
+    Some(ConsumerCode::NextTrack), Some(ConsumerCode::PrevTrack), Some(ConsumerCode::Stop),
+    Some(ConsumerCode::PlayPause), Some(ConsumerCode::MediaSelect), Some(ConsumerCode::Eject),
+    Some(ConsumerCode::Mail), Some(ConsumerCode::Calculator), Some(ConsumerCode::MyComputer),
+    Some(ConsumerCode::WwwSearch), Some(ConsumerCode::WwwHome), Some(ConsumerCode::WwwBack),
+    Some(ConsumerCode::WwwForward), None, Some(ConsumerCode::WwwRefresh), None, None, None,
+    Some(ConsumerCode::BrightnessUp), Some(ConsumerCode::BrightnessDown)
+];
+
+const MOUSE_DIRECTIONS: [MouseDirection; 4] = [
+    MouseDirection::Up, MouseDirection::Down, MouseDirection::Left, MouseDirection::Right
+];
+
+const MOUSE_BUTTONS: [MouseButton; 5] = [
+    MouseButton::Left, MouseButton::Right, MouseButton::Middle, MouseButton::Back, MouseButton::Forward
+];
+
+const MOUSE_WHEELS: [MouseDirection; 4] = [
+    MouseDirection::WheelUp, MouseDirection::WheelDown, MouseDirection::WheelLeft, MouseDirection::WheelRight
+];
+
+fn index_of<T: PartialEq>(table: &[T], value: &T) -> Option<u16> {
+    table.iter().position(|t| t == value).map(|i| i as u16)
+}
+
+/// アクションをVIAのキーコードにする（表せなければ`UNSUPPORTED_KEYCODE`）
+pub fn to_keycode(action: &Action) -> u16 {
+    let code = match *action {
+        Action::NoOp => Some(0),
+        Action::Trans => Some(KC_TRANSPARENT),
+        Action::KeyCode(kc) => match kc as u16 {
+            c @ 0x00 | c @ 0x04..=0xA4 | c @ 0xE0..=0xE7 => Some(c),
+            _ => None
+        },
+        Action::System(sc) => index_of(&SYSTEM_CODES, &sc).map(|i| KC_SYSTEM_POWER + i),
+        Action::Consumer(cc) => index_of(&CONSUMER_CODES, &Some(cc)).map(|i| KC_AUDIO_MUTE + i),
+        Action::MouseMove(dir) => index_of(&MOUSE_DIRECTIONS, &dir).map(|i| KC_MS_UP + i)
+            .or_else(|| index_of(&MOUSE_WHEELS, &dir).map(|i| KC_MS_WH_UP + i)),
+        Action::MouseButton(b) => index_of(&MOUSE_BUTTONS, &b).map(|i| KC_MS_BTN1 + i),
+        Action::Layer(l) if l < 16 => Some(QK_MOMENTARY | l as u16),
+        Action::DefaultLayer(l) if l < 16 => Some(QK_DEF_LAYER | l as u16),
+        Action::ToggleLayer(l) if l < 16 => Some(QK_TOGGLE_LAYER | l as u16),
+        Action::ToLayer(l) if l < 16 => Some(QK_TO | l as u16),
+        _ => None
+    };
+    code.unwrap_or(UNSUPPORTED_KEYCODE)
+}
+
+/// VIAのキーコードをアクションにする（makbeで扱えなければNone）
+pub fn from_keycode(code: u16) -> Option<Action> {
+    let low = (code & 0x00FF) as usize;
+    let layer = (code & 0x000F) as usize;
+    match code {
+        0x0000 => Some(Action::NoOp),
+        KC_TRANSPARENT => Some(Action::Trans),
+        0x0004..=0x00A4 => Some(Action::KeyCode(BASIC_KEY_CODES[low])),
+        0x00A5..=0x00A7 => Some(Action::System(SYSTEM_CODES[low - 0xA5])),
+        0x00A8..=0x00BE => CONSUMER_CODES[low - 0xA8].map(Action::Consumer),
+        0x00E0..=0x00E7 => Some(Action::KeyCode(MODIFIER_KEY_CODES[low - 0xE0])),
+        0x00F0..=0x00F3 => Some(Action::MouseMove(MOUSE_DIRECTIONS[low - 0xF0])),
+        0x00F4..=0x00F8 => Some(Action::MouseButton(MOUSE_BUTTONS[low - 0xF4])),
+        0x00F9..=0x00FC => Some(Action::MouseMove(MOUSE_WHEELS[low - 0xF9])),
+        0x5010..=0x501F => Some(Action::ToLayer(layer)),
+        0x5100..=0x510F => Some(Action::Layer(layer)),
+        0x5200..=0x520F => Some(Action::DefaultLayer(layer)),
+        0x5300..=0x530F => Some(Action::ToggleLayer(layer)),
+        _ => None
+    }
+}
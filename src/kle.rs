@@ -0,0 +1,193 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # Keyboard Layout Editorのraw-data
+//!
+//! KLEのraw-dataから、キーの位置と形を読む（`std`フィーチャが必要）。
+//! build.rsやホストのツールから使うことを想定している。
+//!
+//! KLEは、キーを置いたら右に進み、行が変わったら左端（`rx`）に戻る。
+//! キーの前のオブジェクトで、次のキーの位置（`x`, `y`は相対値）や大きさ、回転を変える。
+//! `KeySwitch`はキーの中心の絶対座標で、回転は反時計回りなので、ここで変換する
+
+use std::fmt;
+use std::string::String;
+use std::vec::Vec;
+use crate::json::{self, ParseError, Value};
+use crate::key_switch::{KeySwitch, Position, Shape};
+
+/// KLEのキー
+#[derive(Debug, Clone, PartialEq)]
+pub struct KleKey {
+    /// KLEのラベル（位置毎のラベルが改行で区切られている）
+    pub label: String,
+    pub shape: Shape,
+    pub position: Position
+}
+
+impl KleKey {
+
+    /// 位置毎のラベル（左上、左下、右上、……の順）
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.label.split('\n')
+    }
+
+    /// 同じ位置と形の`KeySwitch`（アクションは空）
+    pub fn to_switch(&self) -> KeySwitch {
+        let mut switch = KeySwitch::new(0.0, 0.0);
+        switch.shape = self.shape;
+        switch.position = self.position;
+        switch
+    }
+}
+
+/// 読めなかった理由
+#[derive(Debug, Clone, PartialEq)]
+pub enum KleError {
+    /// JSONとして読めない
+    Parse(ParseError),
+    /// JSONとしては読めたけど、KLEの形ではない
+    Format(&'static str)
+}
+
+impl From<ParseError> for KleError {
+    fn from(e: ParseError) -> Self { KleError::Parse(e) }
+}
+
+impl fmt::Display for KleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KleError::Parse(e) => write!(f, "{}", e),
+            KleError::Format(message) => write!(f, "{}", message)
+        }
+    }
+}
+
+impl std::error::Error for KleError {}
+
+/// 次に置くキーの状態（単位は1u）
+struct Cursor {
+    x: f64,
+    y: f64,
+    w: f64,
+    h: f64,
+    x2: f64,
+    y2: f64,
+    w2: f64,
+    h2: f64,
+    r: f64,
+    rx: f64,
+    ry: f64
+}
+
+impl Cursor {
+
+    /// キー毎の設定を戻す
+    fn reset_key(&mut self) {
+        self.w = 1.0;
+        self.h = 1.0;
+        self.x2 = 0.0;
+        self.y2 = 0.0;
+        self.w2 = 0.0;
+        self.h2 = 0.0;
+    }
+
+    fn apply(&mut self, props: &Value) {
+        let get = |key: &str| props.get(key).and_then(Value::as_f64);
+        if let Some(r) = get("r") {
+            self.r = r;
+        }
+        // 回転の中心を変えると、そこが新しい原点になる
+        if let Some(rx) = get("rx") {
+            self.rx = rx;
+            self.x = self.rx;
+            self.y = self.ry;
+        }
+        if let Some(ry) = get("ry") {
+            self.ry = ry;
+            self.x = self.rx;
+            self.y = self.ry;
+        }
+        if let Some(x) = get("x") {
+            self.x += x;
+        }
+        if let Some(y) = get("y") {
+            self.y += y;
+        }
+        if let Some(w) = get("w") {
+            self.w = w;
+        }
+        if let Some(h) = get("h") {
+            self.h = h;
+        }
+        if let Some(x2) = get("x2") {
+            self.x2 = x2;
+        }
+        if let Some(y2) = get("y2") {
+            self.y2 = y2;
+        }
+        if let Some(w2) = get("w2") {
+            self.w2 = w2;
+        }
+        if let Some(h2) = get("h2") {
+            self.h2 = h2;
+        }
+    }
+
+    fn key(&self, label: &str) -> KleKey {
+        // 2つ目の四角が1つ目と違って縦長なら、ISOのEnter
+        let w2 = if self.w2 == 0.0 { self.w } else { self.w2 };
+        let h2 = if self.h2 == 0.0 { self.h } else { self.h2 };
+        let stepped = w2 != self.w || h2 != self.h || self.x2 != 0.0 || self.y2 != 0.0;
+        let shape = if stepped && self.h > self.w { Shape::IsoEnter } else { Shape::Rectangle };
+        let (r, rx, ry) = if self.r == 0.0 { (0.0, 0.0, 0.0) } else { (-self.r, self.rx, self.ry) };
+        KleKey {
+            label: String::from(label),
+            shape,
+            position: Position::new(
+                (self.x + self.w / 2.0) as f32,
+                (self.y + self.h / 2.0) as f32,
+                self.w as f32,
+                self.h as f32,
+                r as f32,
+                rx as f32,
+                ry as f32
+            )
+        }
+    }
+}
+
+/// raw-dataを読む（一番外側の`[]`はあってもなくてもいい）
+pub fn parse(text: &str) -> Result<Vec<KleKey>, KleError> {
+    from_value(&json::parse(text)?)
+}
+
+/// JSONとして読んだraw-dataからキーを取り出す
+pub fn from_value(value: &Value) -> Result<Vec<KleKey>, KleError> {
+    let rows = value.as_array().ok_or(KleError::Format("expected array of rows"))?;
+    let mut cursor = Cursor { x: 0.0, y: 0.0, w: 1.0, h: 1.0, x2: 0.0, y2: 0.0, w2: 0.0, h2: 0.0, r: 0.0, rx: 0.0, ry: 0.0 };
+    let mut keys = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        let items = match row {
+            Value::Array(items) => items,
+            // 先頭のオブジェクトはキーボード全体の情報
+            Value::Object(_) if i == 0 => continue,
+            _ => return Err(KleError::Format("expected row"))
+        };
+        for item in items.iter() {
+            match item {
+                Value::String(label) => {
+                    keys.push(cursor.key(label));
+                    cursor.x += cursor.w;
+                    cursor.reset_key();
+                }
+                Value::Object(_) => cursor.apply(item),
+                _ => return Err(KleError::Format("expected key or properties"))
+            }
+        }
+        cursor.y += 1.0;
+        cursor.x = cursor.rx;
+    }
+    Ok(keys)
+}
@@ -0,0 +1,124 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # レイヤの状態
+//!
+//! 有効なレイヤをビットマスクで持ち、有効なもののうち一番大きいレイヤを使う。
+//! 押している間だけのレイヤ（`Action::Layer`）は`Evaluator`がキーの状態として持っているので、
+//! ここではデフォルトレイヤとトグルしたレイヤ、トライレイヤの規則を持つ
+
+use heapless::Vec;
+use heapless::consts::U4;
+
+/// 扱えるレイヤの数
+pub const MAX_LAYERS: usize = 32;
+
+/// # トライレイヤ
+///
+/// `lower`と`raise`が両方とも有効なら、`adjust`も有効にする
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TriLayer {
+    pub lower: usize,
+    pub raise: usize,
+    pub adjust: usize
+}
+
+impl TriLayer {
+
+    pub const fn new(lower: usize, raise: usize, adjust: usize) -> Self {
+        Self {
+            lower,
+            raise,
+            adjust
+        }
+    }
+}
+
+fn bit(layer: usize) -> u32 {
+    if layer < MAX_LAYERS {
+        1 << layer
+    } else {
+        0
+    }
+}
+
+pub struct LayerState {
+    default_layer: usize,
+    toggled: u32,
+    tri_layers: Vec<TriLayer, U4>
+}
+
+impl LayerState {
+
+    pub fn new() -> Self {
+        Self {
+            default_layer: 0,
+            toggled: 0,
+            tri_layers: Vec::new()
+        }
+    }
+
+    pub fn default_layer(&self) -> usize {
+        self.default_layer
+    }
+
+    pub fn set_default_layer(&mut self, layer: usize) {
+        if layer < MAX_LAYERS {
+            self.default_layer = layer;
+        }
+    }
+
+    /// トグルで有効にしているレイヤ
+    pub fn toggled(&self) -> u32 {
+        self.toggled
+    }
+
+    /// レイヤを有効・無効にする
+    pub fn set(&mut self, layer: usize, on: bool) {
+        if on {
+            self.toggled |= bit(layer);
+        } else {
+            self.toggled &= !bit(layer);
+        }
+    }
+
+    /// レイヤの有効・無効を切り替える（TG）
+    pub fn toggle(&mut self, layer: usize) {
+        self.toggled ^= bit(layer);
+    }
+
+    /// デフォルトレイヤ以外を無効にして、レイヤを有効にする（TO）
+    pub fn move_to(&mut self, layer: usize) {
+        self.toggled = bit(layer);
+    }
+
+    pub fn add_tri_layer(&mut self, tri_layer: TriLayer) -> Result<(), TriLayer> {
+        self.tri_layers.push(tri_layer)
+    }
+
+    /// 有効なレイヤ（momentaryは押している間だけ有効なレイヤ）
+    pub fn active(&self, momentary: u32) -> u32 {
+        let mut bits = bit(self.default_layer) | self.toggled | momentary;
+        for t in self.tri_layers.iter() {
+            let both = bit(t.lower) | bit(t.raise);
+            if bits & both == both {
+                bits |= bit(t.adjust);
+            }
+        }
+        bits
+    }
+
+    /// 有効なレイヤのうち一番大きいもの
+    pub fn highest(bits: u32) -> usize {
+        if bits == 0 {
+            0
+        } else {
+            (31 - bits.leading_zeros()) as usize
+        }
+    }
+}
+
+impl Default for LayerState {
+    fn default() -> Self { LayerState::new() }
+}
@@ -2,12 +2,32 @@
 // All right reserved.
 //
 
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 pub mod scanner;
 pub mod device;
 pub mod devices;
+pub mod action;
+pub mod consumer;
+pub mod layers;
+pub mod keyboard;
+pub mod keymap;
+pub mod via;
+pub mod storage;
+pub mod macros;
+pub mod mouse;
 pub mod key_switch;
 pub mod event;
 pub mod debouncer;
 pub mod evaluator;
+pub mod encoder;
 pub mod reporter;
+pub mod health;
+pub mod probe;
+#[cfg(feature = "std")]
+pub mod mock;
+#[cfg(feature = "std")]
+pub mod json;
+#[cfg(feature = "std")]
+pub mod kle;
+#[cfg(feature = "std")]
+pub mod codegen;
@@ -0,0 +1,219 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # マクロ
+//!
+//! キーを押す・離す・待つといった手順を、`Evaluator`の`tick`毎に1つずつ実行する
+
+use keyberon::key_code::KeyCode;
+use keyberon::key_code::KeyCode::*;
+use heapless::Vec;
+use heapless::consts::{U4, U8};
+
+/// マクロの手順
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MacroStep {
+    /// キーを押す（`Release`するか、マクロが終わるまで押したまま）
+    Press(KeyCode),
+    /// キーを離す
+    Release(KeyCode),
+    /// キーを押して、次のtickで離す
+    Tap(KeyCode),
+    /// 指定したtickだけ待つ
+    Wait(u16),
+    /// ASCIIの文字列を1文字ずつタップする（変換できない文字は飛ばす）
+    Text(&'static str),
+}
+
+/// 文字列をキーコードに変換するときの、ホスト側のキー配列
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TextLayout {
+    Us,
+    Jis
+}
+
+impl Default for TextLayout {
+    fn default() -> Self { TextLayout::Us }
+}
+
+impl TextLayout {
+
+    /// ASCIIの1文字を、キーコードとシフトが必要かどうかに変換する
+    pub fn to_keycode(&self, c: u8) -> Option<(KeyCode, bool)> {
+        let letter = |i: u8| -> KeyCode {
+            const LETTERS: [KeyCode; 26] = [
+                A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z
+            ];
+            LETTERS[i as usize]
+        };
+        const DIGITS: [KeyCode; 10] = [Kb0, Kb1, Kb2, Kb3, Kb4, Kb5, Kb6, Kb7, Kb8, Kb9];
+        match c {
+            b'a'..=b'z' => return Some((letter(c - b'a'), false)),
+            b'A'..=b'Z' => return Some((letter(c - b'A'), true)),
+            b'0'..=b'9' => return Some((DIGITS[(c - b'0') as usize], false)),
+            b' ' => return Some((Space, false)),
+            b'\n' => return Some((Enter, false)),
+            b'\t' => return Some((Tab, false)),
+            b',' => return Some((Comma, false)),
+            b'<' => return Some((Comma, true)),
+            b'.' => return Some((Dot, false)),
+            b'>' => return Some((Dot, true)),
+            b'/' => return Some((Slash, false)),
+            b'?' => return Some((Slash, true)),
+            b';' => return Some((SColon, false)),
+            b'!' => return Some((Kb1, true)),
+            b'#' => return Some((Kb3, true)),
+            b'$' => return Some((Kb4, true)),
+            b'%' => return Some((Kb5, true)),
+            b'-' => return Some((Minus, false)),
+            _ => {}
+        }
+        match self {
+            TextLayout::Us => match c {
+                b'@' => Some((Kb2, true)),
+                b'^' => Some((Kb6, true)),
+                b'&' => Some((Kb7, true)),
+                b'*' => Some((Kb8, true)),
+                b'(' => Some((Kb9, true)),
+                b')' => Some((Kb0, true)),
+                b'_' => Some((Minus, true)),
+                b'=' => Some((Equal, false)),
+                b'+' => Some((Equal, true)),
+                b'[' => Some((LBracket, false)),
+                b'{' => Some((LBracket, true)),
+                b']' => Some((RBracket, false)),
+                b'}' => Some((RBracket, true)),
+                b'\\' => Some((Bslash, false)),
+                b'|' => Some((Bslash, true)),
+                b':' => Some((SColon, true)),
+                b'\'' => Some((Quote, false)),
+                b'"' => Some((Quote, true)),
+                b'`' => Some((Grave, false)),
+                b'~' => Some((Grave, true)),
+                _ => None
+            },
+            TextLayout::Jis => match c {
+                b'"' => Some((Kb2, true)),
+                b'&' => Some((Kb6, true)),
+                b'\'' => Some((Kb7, true)),
+                b'(' => Some((Kb8, true)),
+                b')' => Some((Kb9, true)),
+                b'=' => Some((Minus, true)),
+                b'^' => Some((Equal, false)),
+                b'~' => Some((Equal, true)),
+                b'@' => Some((LBracket, false)),
+                b'`' => Some((LBracket, true)),
+                b'[' => Some((RBracket, false)),
+                b'{' => Some((RBracket, true)),
+                b']' => Some((NonUsHash, false)),
+                b'}' => Some((NonUsHash, true)),
+                b'+' => Some((SColon, true)),
+                b':' => Some((Quote, false)),
+                b'*' => Some((Quote, true)),
+                b'\\' => Some((Intl1, false)),
+                b'_' => Some((Intl1, true)),
+                b'|' => Some((Intl3, true)),
+                _ => None
+            }
+        }
+    }
+}
+
+/// # 再生中のマクロ
+pub struct MacroPlayer {
+    steps: &'static [MacroStep],
+    index: usize,
+    /// `Text`の何文字目か
+    offset: usize,
+    wait: u16,
+    /// マクロが押しているキー
+    keys: Vec<KeyCode, U8>,
+    /// 次のtickで離すキー
+    tapped: Vec<KeyCode, U4>
+}
+
+impl MacroPlayer {
+
+    pub fn new(steps: &'static [MacroStep]) -> Self {
+        Self {
+            steps,
+            index: 0,
+            offset: 0,
+            wait: 0,
+            keys: Vec::new(),
+            tapped: Vec::new()
+        }
+    }
+
+    /// マクロが押しているキー
+    pub fn keys(&self) -> &[KeyCode] {
+        &self.keys
+    }
+
+    /// 終わったか（押していたキーを離すまでは終わらない。
+    /// 最後の`Press`も、1tick分はレポートに載る）
+    pub fn is_finished(&self) -> bool {
+        self.index >= self.steps.len() && self.tapped.is_empty() && self.wait == 0 && self.keys.is_empty()
+    }
+
+    /// 1tick分進める
+    pub fn tick(&mut self, layout: TextLayout) {
+        if self.wait > 0 {
+            self.wait -= 1;
+            return;
+        }
+        if !self.tapped.is_empty() {
+            let tapped = &self.tapped;
+            self.keys = self.keys.iter().filter(|k| !tapped.contains(k)).cloned().collect();
+            self.tapped = Vec::new();
+            return;
+        }
+        let step = match self.steps.get(self.index) {
+            Some(step) => *step,
+            None => {
+                self.keys = Vec::new();
+                return;
+            }
+        };
+        match step {
+            MacroStep::Press(kc) => {
+                let _ = self.keys.push(kc);
+            }
+            MacroStep::Release(kc) => {
+                self.keys = self.keys.iter().filter(|k| **k != kc).cloned().collect();
+            }
+            MacroStep::Tap(kc) => {
+                self.tap(kc, false);
+            }
+            MacroStep::Wait(ticks) => {
+                self.wait = ticks;
+            }
+            MacroStep::Text(text) => {
+                // 変換できる文字まで進める
+                let bytes = text.as_bytes();
+                while let Some(c) = bytes.get(self.offset) {
+                    self.offset += 1;
+                    if let Some((kc, shift)) = layout.to_keycode(*c) {
+                        self.tap(kc, shift);
+                        break;
+                    }
+                }
+                if self.offset < bytes.len() {
+                    return;
+                }
+                self.offset = 0;
+            }
+        }
+        self.index += 1;
+    }
+
+    fn tap(&mut self, kc: KeyCode, shift: bool) {
+        if shift {
+            let _ = self.keys.push(LShift);
+            let _ = self.tapped.push(LShift);
+        }
+        let _ = self.keys.push(kc);
+        let _ = self.tapped.push(kc);
+    }
+}
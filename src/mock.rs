@@ -0,0 +1,531 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # 模擬I2Cバス
+//!
+//! 実機なしで`Scanner::scan`を動かすためのもの（`std`フィーチャが必要）。
+//! TCA9554/TCA9555のレジスタ（Input, Output, Polarity Inversion, Configuration）と
+//! カウンタ値を返すロータリーエンコーダを模擬していて、
+//! テストからピンのレベルを操作して、`Reporter`に何が届いたかを確認できる。
+
+use std::collections::VecDeque;
+use std::vec::Vec;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use keyberon::key_code::KeyCode;
+use crate::reporter::{ReportError, Reporter};
+use crate::consumer::{ConsumerCode, SystemCode};
+use crate::mouse::MouseReport;
+
+/// 模擬バスのエラー
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MockError {
+    /// そのアドレスにデバイスがいない
+    Nack(u8),
+    /// 存在しないレジスタを指定した（アドレス, レジスタ）
+    InvalidRegister(u8, u8),
+    /// レジスタを指定せずに読み込んだ
+    NoRegister(u8)
+}
+
+/// 模擬するI/Oエクスパンダの種類
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExpanderModel {
+    /// 8bit（レジスタは0x00〜0x03）
+    TCA9554,
+    /// 16bit（レジスタは0x00〜0x07）
+    TCA9555
+}
+
+impl ExpanderModel {
+
+    /// ポート数（8bit単位）
+    fn ports(&self) -> usize {
+        match self {
+            ExpanderModel::TCA9554 => 1,
+            ExpanderModel::TCA9555 => 2
+        }
+    }
+}
+
+/// ピンの波形の1区間
+///
+/// `levels`のレベル（1ならHigh）を、Inputレジスタが`reads`回読まれるまで保持する
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Step {
+    pub levels: u16,
+    pub reads: usize
+}
+
+/// I/Oエクスパンダの模擬
+///
+/// ピンのレベルは1がHigh。スイッチはプルアップされていて、押すとLowになる前提。
+pub struct SimExpander {
+    model: ExpanderModel,
+    addr: u8,
+    /// レジスタファイル（TCA9554は先頭の4つだけ使う）
+    registers: [u8; 8],
+    /// 外部から与えられているピンのレベル
+    levels: u16,
+    /// これから流す波形
+    waveform: VecDeque<Step>,
+    /// レジスタ・ポインタ
+    pointer: Option<u8>,
+    /// 最後に読まれたInputレジスタの値（INTの判定用）
+    latched: u16,
+    /// Inputレジスタが読まれた回数
+    pub input_reads: usize
+}
+
+impl SimExpander {
+
+    /// 電源投入直後の状態で生成（addrは7bitのアドレスそのもの）
+    pub fn new(model: ExpanderModel, addr: u8) -> Self {
+        let mut registers = [0x00_u8; 8];
+        for port in 0..model.ports() {
+            registers[Self::output_reg(model, port) as usize] = 0xFF;
+            registers[Self::config_reg(model, port) as usize] = 0xFF;
+        }
+        Self {
+            model,
+            addr,
+            registers,
+            levels: 0xFFFF,
+            waveform: VecDeque::new(),
+            pointer: None,
+            latched: 0xFFFF,
+            input_reads: 0
+        }
+    }
+
+    pub fn tca9554(addr: u8) -> Self {
+        Self::new(ExpanderModel::TCA9554, addr)
+    }
+
+    pub fn tca9555(addr: u8) -> Self {
+        Self::new(ExpanderModel::TCA9555, addr)
+    }
+
+    pub fn addr(&self) -> u8 {
+        self.addr
+    }
+
+    pub fn model(&self) -> ExpanderModel {
+        self.model
+    }
+
+    fn output_reg(model: ExpanderModel, port: usize) -> u8 {
+        (model.ports() + port) as u8
+    }
+
+    fn polarity_reg(model: ExpanderModel, port: usize) -> u8 {
+        (model.ports() * 2 + port) as u8
+    }
+
+    fn config_reg(model: ExpanderModel, port: usize) -> u8 {
+        (model.ports() * 3 + port) as u8
+    }
+
+    /// レジスタの値
+    pub fn register(&self, reg: u8) -> u8 {
+        if (reg as usize) < self.model.ports() {
+            self.input(reg as usize)
+        } else {
+            self.registers[reg as usize]
+        }
+    }
+
+    /// Configurationレジスタ（1なら入力）
+    pub fn config(&self) -> u16 {
+        self.port_pair(|port| self.registers[Self::config_reg(self.model, port) as usize])
+    }
+
+    /// INTの状態（trueならアサートされている）
+    ///
+    /// 入力ピンのレベルが、最後にInputレジスタを読んだときから変わっていたらアサートされる
+    pub fn interrupt(&self) -> bool {
+        let mask = self.config() & self.port_mask();
+        (self.inputs() ^ self.latched) & mask != 0
+    }
+
+    fn port_mask(&self) -> u16 {
+        if self.model.ports() == 2 { 0xFFFF } else { 0x00FF }
+    }
+
+    fn inputs(&self) -> u16 {
+        self.port_pair(|port| self.input(port))
+    }
+
+    /// スイッチを押す（ピンをLowにする）
+    pub fn press(&mut self, pin: usize) {
+        self.levels &= !(1 << pin);
+    }
+
+    /// スイッチを離す（ピンをHighにする）
+    pub fn release(&mut self, pin: usize) {
+        self.levels |= 1 << pin;
+    }
+
+    /// ピンのレベルをまとめて設定する
+    pub fn set_levels(&mut self, levels: u16) {
+        self.levels = levels;
+    }
+
+    /// 現在のピンのレベル
+    pub fn levels(&self) -> u16 {
+        self.levels
+    }
+
+    /// 波形を追加する
+    ///
+    /// 波形が尽きたら、最後のレベルを保持する
+    pub fn push_step(&mut self, levels: u16, reads: usize) {
+        self.waveform.push_back(Step { levels, reads });
+    }
+
+    /// pinのチャタリングを模擬する
+    ///
+    /// 現在のレベルを基準に、pinだけを`times`回反転させ、最後に`pressed`の状態で落ち着く
+    pub fn bounce(&mut self, pin: usize, times: usize, pressed: bool) {
+        let base = self.waveform.back().map(|s| s.levels).unwrap_or(self.levels);
+        let mask = 1_u16 << pin;
+        let settled = if pressed { base & !mask } else { base | mask };
+        for i in 0..times {
+            let levels = if i % 2 == 0 { settled } else { settled ^ mask };
+            self.push_step(levels, 1);
+        }
+        self.push_step(settled, 1);
+    }
+
+    fn port_pair<F: Fn(usize) -> u8>(&self, f: F) -> u16 {
+        let mut value = 0_u16;
+        for port in 0..self.model.ports() {
+            value |= (f(port) as u16) << (port * 8);
+        }
+        value
+    }
+
+    /// Inputレジスタの値（入力ピンはピンのレベル、出力ピンはOutputレジスタの値）
+    fn input(&self, port: usize) -> u8 {
+        let levels = (self.levels >> (port * 8)) as u8;
+        let output = self.registers[Self::output_reg(self.model, port) as usize];
+        let polarity = self.registers[Self::polarity_reg(self.model, port) as usize];
+        let config = self.registers[Self::config_reg(self.model, port) as usize];
+        ((levels ^ polarity) & config) | (output & !config)
+    }
+
+    /// Inputレジスタの読込で波形を進める
+    fn advance(&mut self) {
+        self.input_reads += 1;
+        if let Some(step) = self.waveform.front_mut() {
+            self.levels = step.levels;
+            step.reads = step.reads.saturating_sub(1);
+            if step.reads == 0 {
+                self.waveform.pop_front();
+            }
+        }
+    }
+
+    fn check_register(&self, reg: u8) -> Result<u8, MockError> {
+        if (reg as usize) < self.model.ports() * 4 {
+            Ok(reg)
+        } else {
+            Err(MockError::InvalidRegister(self.addr, reg))
+        }
+    }
+
+    /// 2バイト目以降はペアになっているレジスタ間で自動的に切り替わる
+    fn next_register(&self, reg: u8) -> u8 {
+        if self.model.ports() == 2 { reg ^ 0x01 } else { reg }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), MockError> {
+        let (first, data) = match bytes.split_first() {
+            Some(split) => split,
+            None => return Ok(())
+        };
+        let mut reg = self.check_register(*first)?;
+        self.pointer = Some(reg);
+        for b in data {
+            if reg >= self.model.ports() as u8 {
+                // Inputレジスタへの書込は無視される
+                self.registers[reg as usize] = *b;
+            }
+            reg = self.next_register(reg);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), MockError> {
+        let mut reg = self.pointer.ok_or(MockError::NoRegister(self.addr))?;
+        if reg < self.model.ports() as u8 {
+            self.advance();
+            self.latched = self.inputs();
+        }
+        for b in buffer.iter_mut() {
+            *b = if reg < self.model.ports() as u8 {
+                self.input(reg as usize)
+            } else {
+                self.registers[reg as usize]
+            };
+            reg = self.next_register(reg);
+        }
+        Ok(())
+    }
+}
+
+/// カウンタ値を返すロータリーエンコーダ・モジュールの模擬
+///
+/// レジスタ0x00から、ビッグエンディアンでカウンタ値を返す
+pub struct SimCounter {
+    addr: u8,
+    bytes: usize,
+    value: u32,
+    pointer: Option<u8>
+}
+
+impl SimCounter {
+
+    /// bytesはカウンタのバイト数（1, 2, 4）
+    pub fn new(addr: u8, bytes: usize) -> Self {
+        Self {
+            addr,
+            bytes,
+            value: 0,
+            pointer: None
+        }
+    }
+
+    pub fn addr(&self) -> u8 {
+        self.addr
+    }
+
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    /// カウンタ値を設定する（ビット幅に収まるように切り詰める）
+    pub fn set_value(&mut self, value: u32) {
+        self.value = value & self.mask();
+    }
+
+    /// 回す（正なら時計回り）
+    pub fn rotate(&mut self, counts: i32) {
+        self.set_value(self.value.wrapping_add(counts as u32));
+    }
+
+    fn mask(&self) -> u32 {
+        if self.bytes >= 4 { u32::MAX } else { (1_u32 << (self.bytes * 8)) - 1 }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), MockError> {
+        match bytes.first() {
+            None => Ok(()),
+            Some(0x00) => {
+                self.pointer = Some(0x00);
+                Ok(())
+            }
+            Some(reg) => Err(MockError::InvalidRegister(self.addr, *reg))
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), MockError> {
+        self.pointer.ok_or(MockError::NoRegister(self.addr))?;
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = if i < self.bytes {
+                (self.value >> ((self.bytes - 1 - i) * 8)) as u8
+            } else {
+                0x00
+            };
+        }
+        Ok(())
+    }
+}
+
+/// 模擬バスにつなげるデバイス
+pub enum SimDevice {
+    Expander(SimExpander),
+    Counter(SimCounter)
+}
+
+impl SimDevice {
+
+    pub fn addr(&self) -> u8 {
+        match self {
+            SimDevice::Expander(e) => e.addr(),
+            SimDevice::Counter(c) => c.addr()
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), MockError> {
+        match self {
+            SimDevice::Expander(e) => e.write(bytes),
+            SimDevice::Counter(c) => c.write(bytes)
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), MockError> {
+        match self {
+            SimDevice::Expander(e) => e.read(buffer),
+            SimDevice::Counter(c) => c.read(buffer)
+        }
+    }
+}
+
+impl From<SimExpander> for SimDevice {
+    fn from(e: SimExpander) -> Self { SimDevice::Expander(e) }
+}
+
+impl From<SimCounter> for SimDevice {
+    fn from(c: SimCounter) -> Self { SimDevice::Counter(c) }
+}
+
+/// 模擬I2Cバス
+///
+/// `embedded_hal`のblockingなI2Cトレイトを実装しているので、そのまま`Scanner`に渡せる
+#[derive(Default)]
+pub struct MockBus {
+    devices: Vec<SimDevice>,
+    /// バス上のトランザクション数
+    pub transactions: usize
+}
+
+impl MockBus {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// デバイスを接続する
+    pub fn attach<D: Into<SimDevice>>(&mut self, device: D) -> &mut Self {
+        let device = device.into();
+        self.detach(device.addr());
+        self.devices.push(device);
+        self
+    }
+
+    /// デバイスを取り外す
+    pub fn detach(&mut self, addr: u8) -> Option<SimDevice> {
+        let index = self.devices.iter().position(|d| d.addr() == addr)?;
+        Some(self.devices.remove(index))
+    }
+
+    pub fn expander(&self, addr: u8) -> Option<&SimExpander> {
+        self.devices.iter().find_map(|d| match d {
+            SimDevice::Expander(e) if e.addr() == addr => Some(e),
+            _ => None
+        })
+    }
+
+    pub fn expander_mut(&mut self, addr: u8) -> Option<&mut SimExpander> {
+        self.devices.iter_mut().find_map(|d| match d {
+            SimDevice::Expander(e) if e.addr() == addr => Some(e),
+            _ => None
+        })
+    }
+
+    pub fn counter_mut(&mut self, addr: u8) -> Option<&mut SimCounter> {
+        self.devices.iter_mut().find_map(|d| match d {
+            SimDevice::Counter(c) if c.addr() == addr => Some(c),
+            _ => None
+        })
+    }
+
+    fn target(&mut self, addr: u8) -> Result<&mut SimDevice, MockError> {
+        self.transactions += 1;
+        self.devices.iter_mut().find(|d| d.addr() == addr).ok_or(MockError::Nack(addr))
+    }
+}
+
+impl Write for MockBus {
+    type Error = MockError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.target(address)?.write(bytes)
+    }
+}
+
+impl WriteRead for MockBus {
+    type Error = MockError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let device = self.target(address)?;
+        device.write(bytes)?;
+        device.read(buffer)
+    }
+}
+
+/// 送られてきたキーコードを記録するだけのReporter
+#[derive(Default)]
+pub struct RecordingReporter {
+    pub reports: Vec<Vec<KeyCode>>,
+    pub consumer_reports: Vec<Vec<ConsumerCode>>,
+    pub system_reports: Vec<Option<SystemCode>>,
+    pub mouse_reports: Vec<MouseReport>,
+    /// この回数だけ`WouldBlock`を返す（送信中のふり）
+    pub busy: usize,
+    /// trueなら`ReportError::Disconnected`を返す
+    pub disconnected: bool
+}
+
+impl RecordingReporter {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 最後に送られたキーコード
+    pub fn last(&self) -> &[KeyCode] {
+        self.reports.last().map(|r| &r[..]).unwrap_or(&[])
+    }
+
+    /// 直前と内容が変わったものだけを取り出す
+    pub fn changes(&self) -> Vec<Vec<KeyCode>> {
+        let mut result: Vec<Vec<KeyCode>> = Vec::new();
+        for r in self.reports.iter() {
+            let prev = result.last().map(|p| &p[..]).unwrap_or(&[]);
+            if prev != &r[..] {
+                result.push(r.clone());
+            }
+        }
+        result
+    }
+
+    fn accept(&mut self) -> nb::Result<(), ReportError> {
+        if self.disconnected {
+            return Err(nb::Error::Other(ReportError::Disconnected));
+        }
+        if self.busy > 0 {
+            self.busy -= 1;
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(())
+    }
+}
+
+impl Reporter for RecordingReporter {
+
+    fn send_codes(&mut self, codes: &[KeyCode]) -> nb::Result<(), ReportError> {
+        self.accept()?;
+        self.reports.push(codes.to_vec());
+        Ok(())
+    }
+
+    fn send_consumer(&mut self, codes: &[ConsumerCode]) -> nb::Result<(), ReportError> {
+        self.accept()?;
+        self.consumer_reports.push(codes.to_vec());
+        Ok(())
+    }
+
+    fn send_system(&mut self, code: Option<SystemCode>) -> nb::Result<(), ReportError> {
+        self.accept()?;
+        self.system_reports.push(code);
+        Ok(())
+    }
+
+    fn send_mouse(&mut self, report: &MouseReport) -> nb::Result<(), ReportError> {
+        self.accept()?;
+        self.mouse_reports.push(*report);
+        Ok(())
+    }
+}
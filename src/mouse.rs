@@ -0,0 +1,183 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # マウスキー
+//!
+//! キーでポインタを動かしたり、ホイールを回したり、クリックしたりする。
+//! 移動量は`Evaluator`の`tick`毎に計算して、押し続けると加速する
+
+/// マウスのボタン（値はHIDのレポートのビット）
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MouseButton {
+    Left = 0x01,
+    Right = 0x02,
+    Middle = 0x04,
+    Back = 0x08,
+    Forward = 0x10,
+}
+
+/// マウスの移動・ホイールの方向
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MouseDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+}
+
+impl MouseDirection {
+
+    /// 押されている方向をまとめるときのビット
+    pub fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+/// 加速のしかた
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MouseCurve {
+    /// 時間に比例して速くなる
+    Linear,
+    /// 最初はゆっくり、だんだん速くなる（時間の2乗）
+    Quadratic,
+}
+
+impl Default for MouseCurve {
+    fn default() -> Self { MouseCurve::Linear }
+}
+
+/// # マウスキーの設定
+///
+/// 時間の単位はtick
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MouseConfig {
+    /// 移動を送る間隔
+    pub interval: u16,
+    /// 押し始めの移動量
+    pub move_delta: u8,
+    /// 最高速度（1回の移動量）
+    pub max_speed: u8,
+    /// 最高速度になるまでの時間
+    pub time_to_max: u16,
+    pub curve: MouseCurve,
+    /// ホイールを送る間隔
+    pub wheel_interval: u16,
+    /// 1回のホイール量
+    pub wheel_delta: u8
+}
+
+impl Default for MouseConfig {
+    fn default() -> Self {
+        Self {
+            interval: 10,
+            move_delta: 1,
+            max_speed: 20,
+            time_to_max: 500,
+            curve: MouseCurve::Linear,
+            wheel_interval: 50,
+            wheel_delta: 1
+        }
+    }
+}
+
+/// # マウスのレポート
+///
+/// x, y, wheel, panは前回からの相対値（wheelは上、panは右が正）
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8
+}
+
+/// # 移動量の計算
+pub struct MouseKeys {
+    pub config: MouseConfig,
+    /// 移動キーを押し続けている時間
+    elapsed: u16,
+    /// ホイールのキーを押し続けている時間
+    wheel_elapsed: u16
+}
+
+impl MouseKeys {
+
+    pub fn new(config: MouseConfig) -> Self {
+        Self {
+            config,
+            elapsed: 0,
+            wheel_elapsed: 0
+        }
+    }
+
+    /// 押し続けている時間に応じた移動量
+    pub fn speed(&self, elapsed: u16) -> u8 {
+        let c = &self.config;
+        if c.max_speed <= c.move_delta || elapsed >= c.time_to_max {
+            return c.max_speed.max(c.move_delta);
+        }
+        // 2乗するとu32では溢れるので、u64で計算する
+        let range = (c.max_speed - c.move_delta) as u64;
+        let t = elapsed as u64;
+        let max = c.time_to_max.max(1) as u64;
+        let accel = match c.curve {
+            MouseCurve::Linear => range * t / max,
+            MouseCurve::Quadratic => range * t * t / (max * max)
+        };
+        c.move_delta + accel as u8
+    }
+
+    /// 1tick分の移動量（directionsは押されている方向のビット）
+    ///
+    /// ボタンはここでは扱わない
+    pub fn tick(&mut self, directions: u8) -> MouseReport {
+        let mut report = MouseReport::default();
+        let axis = |plus: MouseDirection, minus: MouseDirection| -> i16 {
+            (directions & plus.bit() != 0) as i16 - (directions & minus.bit() != 0) as i16
+        };
+
+        let dx = axis(MouseDirection::Right, MouseDirection::Left);
+        let dy = axis(MouseDirection::Down, MouseDirection::Up);
+        let moving = MouseDirection::Up.bit() | MouseDirection::Down.bit() | MouseDirection::Left.bit() | MouseDirection::Right.bit();
+        if directions & moving == 0 {
+            self.elapsed = 0;
+        } else {
+            if self.elapsed % self.config.interval.max(1) == 0 {
+                let speed = self.speed(self.elapsed) as i16;
+                report.x = saturate(dx * speed);
+                report.y = saturate(dy * speed);
+            }
+            self.elapsed = self.elapsed.saturating_add(1);
+        }
+
+        let wheel = axis(MouseDirection::WheelUp, MouseDirection::WheelDown);
+        let pan = axis(MouseDirection::WheelRight, MouseDirection::WheelLeft);
+        if directions & !moving == 0 {
+            self.wheel_elapsed = 0;
+        } else {
+            if self.wheel_elapsed % self.config.wheel_interval.max(1) == 0 {
+                let delta = self.config.wheel_delta as i16;
+                report.wheel = saturate(wheel * delta);
+                report.pan = saturate(pan * delta);
+            }
+            self.wheel_elapsed = self.wheel_elapsed.saturating_add(1);
+        }
+        report
+    }
+}
+
+/// レポートに入らない移動量は、向きを変えずに最大値にする
+fn saturate(v: i16) -> i8 {
+    v.max(i8::MIN as i16).min(i8::MAX as i16) as i8
+}
+
+impl Default for MouseKeys {
+    fn default() -> Self { MouseKeys::new(MouseConfig::default()) }
+}
@@ -0,0 +1,73 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use core::ops::RangeInclusive;
+use embedded_hal::blocking::i2c::Write;
+
+/// TCA9554/TCA9555（PCA9554/PCA9555）が取りうるアドレス
+pub const EXPANDER_ADDRESSES: RangeInclusive<u8> = 0x20..=0x27;
+
+/// # アドレスの集合
+///
+/// 7bitのアドレス（0x00〜0x7F）をビットマップで持つ
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct AddressSet {
+    bits: [u32; 4]
+}
+
+impl AddressSet {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, addr: u8) {
+        let addr = addr & 0x7F;
+        self.bits[(addr / 32) as usize] |= 1 << (addr % 32);
+    }
+
+    pub fn remove(&mut self, addr: u8) {
+        let addr = addr & 0x7F;
+        self.bits[(addr / 32) as usize] &= !(1 << (addr % 32));
+    }
+
+    pub fn contains(&self, addr: u8) -> bool {
+        let addr = addr & 0x7F;
+        self.bits[(addr / 32) as usize] & (1 << (addr % 32)) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|b| *b == 0)
+    }
+
+    /// 小さい順に取り出す
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0x00..=0x7F_u8).filter(move |a| self.contains(*a))
+    }
+}
+
+/// # デバイスがいるか調べる
+///
+/// アドレスだけを送って、ACKが返ってくるかどうかで判断する。
+/// TCA955xはInputレジスタを読むとINTが解除されるので、レジスタは読まない
+pub fn probe<I2C, E>(i2c: &mut I2C, addr: u8) -> bool
+    where
+        I2C: Write<Error = E>
+{
+    i2c.write(addr, &[]).is_ok()
+}
+
+/// # アドレスの範囲にいるデバイスを調べる
+pub fn probe_range<I2C, E>(i2c: &mut I2C, range: RangeInclusive<u8>) -> AddressSet
+    where
+        I2C: Write<Error = E>
+{
+    let mut found = AddressSet::new();
+    for addr in range {
+        if probe(i2c, addr) {
+            found.insert(addr);
+        }
+    }
+    found
+}
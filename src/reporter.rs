@@ -3,8 +3,231 @@
 //
 
 use keyberon::key_code::KeyCode;
+use crate::consumer::{ConsumerCode, SystemCode};
+use crate::mouse::MouseReport;
+use heapless::Vec;
+use heapless::consts::{U4, U64};
+use arraydeque::{ArrayDeque, Saturating};
 
+/// 送れずに溜めておけるレポートの数
+pub const REPORT_QUEUE_SIZE: usize = 8;
 
+/// # レポート
+///
+/// `Evaluator`が押されていると判断したもの全部
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Report {
+    /// キーボードのキーコード
+    pub keys: Vec<KeyCode, U64>,
+    /// コンシューマコントロール（メディアキー）
+    pub consumer: Vec<ConsumerCode, U4>,
+    /// システムコントロール（同時には1つだけ）
+    pub system: Option<SystemCode>,
+    /// マウス
+    pub mouse: MouseReport
+}
+
+impl Report {
+
+    pub fn new() -> Self {
+        Self {
+            keys: Vec::new(),
+            consumer: Vec::new(),
+            system: None,
+            mouse: MouseReport::default()
+        }
+    }
+
+    /// マウスを動かす（相対値なので、同じ内容でも省略できない）
+    pub fn has_motion(&self) -> bool {
+        let m = &self.mouse;
+        m.x != 0 || m.y != 0 || m.wheel != 0 || m.pan != 0
+    }
+}
+
+impl Default for Report {
+    fn default() -> Self { Report::new() }
+}
+
+/// レポートを送れなかった理由
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReportError {
+    /// ホストとつながっていない（USBが構成されていないなど）
+    Disconnected,
+    /// その他の送信エラー
+    Failed
+}
+
+/// # レポートの送り先
+///
+/// 前回から変わったものだけが、変わったときに呼ばれる。
+/// 今は送れないときは`nb::Error::WouldBlock`を返せば、次の`tick`でもう一度呼ばれる（待たなくていい）。
+///
+/// キーボードしか扱わないなら`send_codes`だけ実装すればいい。
+/// コンシューマやシステムコントロール、マウスは、それぞれ別のHIDレポートとして送ることを想定している
 pub trait Reporter {
-    fn send_codes(&mut self, codes: &[KeyCode]);
-}
\ No newline at end of file
+    fn send_codes(&mut self, codes: &[KeyCode]) -> nb::Result<(), ReportError>;
+
+    /// コンシューマコントロールのレポート（デフォルトは何もしない）
+    fn send_consumer(&mut self, _codes: &[ConsumerCode]) -> nb::Result<(), ReportError> {
+        Ok(())
+    }
+
+    /// システムコントロールのレポート（デフォルトは何もしない）
+    fn send_system(&mut self, _code: Option<SystemCode>) -> nb::Result<(), ReportError> {
+        Ok(())
+    }
+
+    /// マウスのレポート（デフォルトは何もしない）
+    fn send_mouse(&mut self, _report: &MouseReport) -> nb::Result<(), ReportError> {
+        Ok(())
+    }
+}
+
+/// # 送信待ちのレポート
+///
+/// 前のレポートと同じものは積まない。
+/// いっぱいになったら、押した・離したが消えない場合だけ最後のレポートにまとめる。
+/// まとめられなければ積んであるものを残して、空いたときに最新の状態を積む
+/// （押して離したのが潰れるのは、溢れている間に押して離したときだけ）
+pub struct ReportQueue {
+    queue: ArrayDeque<[Report; REPORT_QUEUE_SIZE], Saturating>,
+    /// 最後に積もうとしたレポート
+    last: Report,
+    /// `last`を積めていない
+    overflowed: bool,
+    /// 送り終えたレポート（チャンネル毎）
+    sent: Report,
+    /// キーボードのレポートを、変わっていなくても送る
+    resend_keys: bool
+}
+
+impl ReportQueue {
+
+    pub fn new() -> Self {
+        Self {
+            queue: ArrayDeque::new(),
+            last: Report::new(),
+            overflowed: false,
+            sent: Report::new(),
+            resend_keys: false
+        }
+    }
+
+    /// 送信待ちの数
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// レポートを積む（前と変わらなければ何もしない）
+    pub fn push(&mut self, report: Report) {
+        if report == self.last && !report.has_motion() {
+            return;
+        }
+        self.last = report.clone();
+        if self.overflowed {
+            return;
+        }
+        if let Err(e) = self.queue.push_back(report) {
+            let len = self.queue.len();
+            let merged = match (self.queue.get(len - 2), self.queue.get(len - 1)) {
+                (Some(prev), Some(back)) => merge(prev, back, &e.element),
+                _ => None
+            };
+            match (merged, self.queue.back_mut()) {
+                (Some(merged), Some(back)) => *back = merged,
+                _ => self.overflowed = true
+            }
+        }
+    }
+
+    /// 今のキーボードのレポートを、もう一度送る（ホストがプロトコルを切り替えたときなど）
+    pub fn resend(&mut self) {
+        self.resend_keys = true;
+        if self.queue.is_empty() {
+            let mut report = self.last.clone();
+            report.mouse = MouseReport { buttons: report.mouse.buttons, ..MouseReport::default() };
+            let _ = self.queue.push_back(report);
+        }
+    }
+
+    /// 溜まっているレポートを送る
+    ///
+    /// 送れなかったものは残しておいて、次に呼ばれたときに変わっていない部分から送り直す。
+    /// ホストとつながっていなければ、溜まっているものは捨てて今の状態だけ残す
+    /// （つながってから古い押下を送らないように）
+    pub fn flush(&mut self, reporter: &mut dyn Reporter) -> nb::Result<(), ReportError> {
+        let result = self.send(reporter);
+        if let Err(nb::Error::Other(ReportError::Disconnected)) = result {
+            self.queue.clear();
+            self.overflowed = false;
+            self.sent = Report::new();
+            self.resend();
+        }
+        result
+    }
+
+    fn send(&mut self, reporter: &mut dyn Reporter) -> nb::Result<(), ReportError> {
+        while let Some(report) = self.queue.front() {
+            if report.keys != self.sent.keys || self.resend_keys {
+                reporter.send_codes(&report.keys)?;
+                self.sent.keys = report.keys.clone();
+                self.resend_keys = false;
+            }
+            if report.consumer != self.sent.consumer {
+                reporter.send_consumer(&report.consumer)?;
+                self.sent.consumer = report.consumer.clone();
+            }
+            if report.system != self.sent.system {
+                reporter.send_system(report.system)?;
+                self.sent.system = report.system;
+            }
+            if report.mouse.buttons != self.sent.mouse.buttons || report.has_motion() {
+                reporter.send_mouse(&report.mouse)?;
+                self.sent.mouse = report.mouse;
+            }
+            self.queue.pop_front();
+            if self.overflowed {
+                let _ = self.queue.push_back(self.last.clone());
+                self.overflowed = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `prev`の次の`back`に`report`をまとめたもの（`back`で押した・離したが消えるならNone）
+fn merge(prev: &Report, back: &Report, report: &Report) -> Option<Report> {
+    let kept = |in_prev: bool, in_back: bool, in_report: bool| in_prev == in_back || in_back == in_report;
+    let keys = prev.keys.iter().chain(back.keys.iter());
+    if !keys.clone().all(|kc| kept(prev.keys.contains(kc), back.keys.contains(kc), report.keys.contains(kc))) {
+        return None;
+    }
+    let consumer = prev.consumer.iter().chain(back.consumer.iter());
+    if !consumer.clone().all(|c| kept(prev.consumer.contains(c), back.consumer.contains(c), report.consumer.contains(c))) {
+        return None;
+    }
+    if prev.system != back.system && back.system != report.system {
+        return None;
+    }
+    let changed = prev.mouse.buttons ^ back.mouse.buttons;
+    if (back.mouse.buttons ^ report.mouse.buttons) & changed != 0 {
+        return None;
+    }
+    // 移動量は足す（溢れるならまとめない）
+    let mut merged = report.clone();
+    let m = &back.mouse;
+    merged.mouse.x = m.x.checked_add(report.mouse.x)?;
+    merged.mouse.y = m.y.checked_add(report.mouse.y)?;
+    merged.mouse.wheel = m.wheel.checked_add(report.mouse.wheel)?;
+    merged.mouse.pan = m.pan.checked_add(report.mouse.pan)?;
+    Some(merged)
+}
+
+impl Default for ReportQueue {
+    fn default() -> Self { ReportQueue::new() }
+}
@@ -2,17 +2,25 @@
 // All right reserved.
 //
 
-use crate::device::DeviceHolder;
-use crate::device::DeviceState::{Pins16, Pins8};
+use crate::device::{Device, DeviceHolder};
+use crate::device::DeviceState::{Pins16, Pins8, Value8, Value16, Value32};
 use crate::evaluator::Evaluator;
-use core::ops::Deref;
 use core::marker::PhantomData;
 use embedded_hal::blocking::i2c::{Write, WriteRead};
 use crate::reporter::Reporter;
+use crate::health::{DeviceHealth, HealthEvent, HealthEvents, DEFAULT_FAILURE_LIMIT};
+use crate::probe::{probe, AddressSet};
+use core::ops::RangeInclusive;
+use heapless::Vec;
+use heapless::consts::U128;
 
 /// deviceを使用して、キーの状態をスキャンするもの
 pub struct Scanner<I2C, E> {
     evaluator: Evaluator,
+    health: Vec<DeviceHealth, U128>,
+    failure_limit: u8,
+    /// 続けて読む必要があるデバイス（ビット位置がインデックス）
+    pending: u128,
     phantom0: PhantomData<I2C>,
     phantom1: PhantomData<E>
 }
@@ -26,42 +34,222 @@ impl <I2C, E> Scanner<I2C, E>
     pub fn new(evaluator: Evaluator<>) -> Self {
         Self {
             evaluator,
+            health: Vec::new(),
+            failure_limit: DEFAULT_FAILURE_LIMIT,
+            pending: 0,
             phantom0: Default::default(),
             phantom1: Default::default()
         }
     }
 
+    pub fn evaluator(&self) -> &Evaluator {
+        &self.evaluator
+    }
+
+    /// キーマップを変えたり、レポートを送り直したりするときに使う
+    pub fn evaluator_mut(&mut self) -> &mut Evaluator {
+        &mut self.evaluator
+    }
+
+    /// 何回続けて読込に失敗したらオフラインとみなすか
+    pub fn set_failure_limit(&mut self, limit: u8) {
+        self.failure_limit = limit.max(1);
+    }
+
+    /// デバイスの健康状態（indexは`DeviceHolder::devices`のインデックス）
+    pub fn health(&self, index: usize) -> DeviceHealth {
+        self.health.get(index).copied().unwrap_or_default()
+    }
+
+    /// # モジュールの列挙
+    ///
+    /// rangesのアドレスと`DeviceHolder`にあるデバイスのアドレスを調べて、
+    /// 見つかったデバイスはオンライン（必要なら初期化）、見つからなかったデバイスはオフラインにする。
+    /// `DeviceHolder`にないアドレスで見つかったものは`HealthEvent::Unknown`で返す。
+    ///
+    /// モジュールの抜き差しに追従させたいときは、適当な間隔で呼ぶこと
+    pub fn enumerate(
+        &mut self,
+        i2c: &mut I2C,
+        holder: &DeviceHolder<I2C, E>,
+        ranges: &[RangeInclusive<u8>],
+        reporter: &mut dyn Reporter
+    ) -> HealthEvents {
+        let mut health_events = HealthEvents::new();
+        if self.health.len() < holder.devices.len() {
+            let _ = self.health.resize_default(holder.devices.len());
+        }
+        let mut probed = AddressSet::new();
+        let mut present = AddressSet::new();
+        for range in ranges {
+            for addr in range.clone() {
+                probed.insert(addr);
+                if probe(i2c, addr) {
+                    present.insert(addr);
+                }
+            }
+        }
+
+        let mut declared = AddressSet::new();
+        for (index, device) in holder.devices.iter().enumerate() {
+            let addr = device.address();
+            declared.insert(addr);
+            let found = if probed.contains(addr) { present.contains(addr) } else { probe(i2c, addr) };
+            let health = &mut self.health[index];
+            if found && !health.online {
+                if device.init_device(i2c).is_ok() && health.recovered() {
+                    let _ = health_events.buffer.push(HealthEvent::Online(index));
+                }
+            } else if !found && health.lost() {
+                for e in device.release_all().buffer {
+                    self.evaluator.eval(e, reporter);
+                }
+                let _ = health_events.buffer.push(HealthEvent::Offline(index));
+            }
+        }
+
+        for addr in present.iter().filter(|a| !declared.contains(*a)) {
+            let _ = health_events.buffer.push(HealthEvent::Unknown(addr));
+        }
+        health_events
+    }
+
     /// キー・イベントの収拾
-    pub fn scan(&mut self, i2c: &mut I2C, holder: &DeviceHolder<I2C, E>, reporter: &mut dyn Reporter) {
+    ///
+    /// nowは単調増加のミリ秒（デバウンスに使う）。
+    /// 返値は、オンライン/オフラインが変わったデバイス
+    pub fn scan(&mut self, i2c: &mut I2C, holder: &DeviceHolder<I2C, E>, now: u32, reporter: &mut dyn Reporter) -> HealthEvents {
+        let mut health_events = HealthEvents::new();
+        if self.health.len() < holder.devices.len() {
+            let _ = self.health.resize_default(holder.devices.len());
+        }
         // デバイス毎にイベント取得
-        for d in holder.devices.deref() {
-            let device = d.deref();
-            let result = device.read_device(i2c);
-            match result {
-                Ok(state) => {
-                    match state {
-                        // 16ビットのI/Oエクスパンダ
-                        Pins16(pins) => {
-                            for e in device.pick_events(&pins).buffer {
-                                self.evaluator.eval(e.clone(), reporter);
-                            }
+        for (index, device) in holder.devices.iter().enumerate() {
+            self.scan_device(i2c, index, *device, now, reporter, &mut health_events);
+        }
+        health_events
+    }
+
+    /// # 割込みがあったデバイスだけのキー・イベントの収拾
+    ///
+    /// flaggedは、INTが来たデバイスのインデックス（`DeviceHolder::devices`のインデックス）。
+    /// INTを共有している場合は、`scan`を使うこと。
+    ///
+    /// デバウンスが終わっていないデバイスは、INTが来ていなくても読むので、
+    /// INTがなくても（空のflaggedで）タイマーで定期的に呼ぶこと。
+    /// オフラインのデバイスは読まないので、復帰させるには`enumerate`を使う。
+    /// `DeviceHolder`にないインデックスは無視する
+    pub fn scan_flagged(
+        &mut self,
+        i2c: &mut I2C,
+        holder: &DeviceHolder<I2C, E>,
+        flagged: &[usize],
+        now: u32,
+        reporter: &mut dyn Reporter
+    ) -> HealthEvents {
+        let mut health_events = HealthEvents::new();
+        if self.health.len() < holder.devices.len() {
+            let _ = self.health.resize_default(holder.devices.len());
+        }
+        for index in flagged {
+            // `DeviceHolder`にないインデックスと、オフラインのデバイス（読まない）は無視する
+            if *index < holder.devices.len() && self.health[*index].online {
+                self.pending |= 1 << index;
+            }
+        }
+        for (index, device) in holder.devices.iter().enumerate() {
+            if self.pending & (1 << index) != 0 && self.health[index].online {
+                self.scan_device(i2c, index, *device, now, reporter, &mut health_events);
+            }
+        }
+        health_events
+    }
+
+    /// デバウンス中などで、続けて読む必要があるデバイスがあるか
+    pub fn has_pending(&self) -> bool {
+        self.pending != 0
+    }
+
+    fn scan_device(
+        &mut self,
+        i2c: &mut I2C,
+        index: usize,
+        device: &dyn Device<I2C, E>,
+        now: u32,
+        reporter: &mut dyn Reporter,
+        health_events: &mut HealthEvents
+    ) {
+        let health = &mut self.health[index];
+        if !health.online {
+            // 戻ってきたら初期化からやり直し
+            if device.init_device(i2c).is_err() {
+                return;
+            }
+            if health.recovered() {
+                let _ = health_events.buffer.push(HealthEvent::Online(index));
+            }
+        }
+        let result = device.read_device(i2c);
+        match result {
+            Ok(state) => {
+                health.succeeded();
+                match state {
+                    // 16ビットのI/Oエクスパンダ
+                    Pins16(pins) => {
+                        for e in device.pick_events(&pins, now).buffer {
+                            self.evaluator.eval(e, reporter);
                         }
-                        // 8ビットのI/Oエクスパンダ
-                        Pins8(pins) => {
-                            for e in device.pick_events(&pins).buffer {
-                                self.evaluator.eval(e.clone(), reporter);
-                            }
+                    }
+                    // 8ビットのI/Oエクスパンダ
+                    Pins8(pins) => {
+                        for e in device.pick_events(&pins, now).buffer {
+                            self.evaluator.eval(e, reporter);
                         }
-                        // その他のデバイス
-                        _ => {
-                            // ロータリーエンコーダのこととかはまだ考えない
+                    }
+                    // ロータリーエンコーダ
+                    Value8(value) => {
+                        for e in device.pick_value(value as u32, 8).buffer {
+                            self.evaluator.eval(e, reporter);
+                        }
+                    }
+                    Value16(value) => {
+                        for e in device.pick_value(value as u32, 16).buffer {
+                            self.evaluator.eval(e, reporter);
                         }
                     }
-                },
-                Err(_) => {
-                    // どうしよっか？
+                    Value32(value) => {
+                        for e in device.pick_value(value, 32).buffer {
+                            self.evaluator.eval(e, reporter);
+                        }
+                    }
+                }
+                if index < 128 {
+                    if device.is_settled() {
+                        self.pending &= !(1 << index);
+                    } else {
+                        self.pending |= 1 << index;
+                    }
+                }
+            },
+            Err(_) => {
+                if health.failed(self.failure_limit) {
+                    // 押されたままになるので、離したことにする
+                    for e in device.release_all().buffer {
+                        self.evaluator.eval(e, reporter);
+                    }
+                    let _ = health_events.buffer.push(HealthEvent::Offline(index));
+                    if index < 128 {
+                        self.pending &= !(1 << index);
+                    }
                 }
             }
         }
     }
+
+    /// 時間経過の処理
+    ///
+    /// HoldTapのタイムアウトなどはtick単位なので、一定周期（1msとか）で呼ぶこと
+    pub fn tick(&mut self, reporter: &mut dyn Reporter) {
+        self.evaluator.tick(reporter);
+    }
 }
@@ -0,0 +1,531 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # キーマップの保存
+//!
+//! VIA/Remapで書き換えたキーマップと、スイッチ毎のアクション、設定を、フラッシュやEEPROMに保存する。
+//! スイッチのアクションは、ファームウェアのデフォルトから書き換えたもののうち、VIAのキーコードで表せるものだけを保存する
+//! （ホールドタップなど参照を持つものは、ファームウェアに書いたものがそのまま使われる）。
+//! ファームウェアを更新して、スイッチの並びやデフォルトのアクションが変わったときは、保存したアクションは読まない。
+//! 書き込み先は`Storage`で抽象化しているので、実機ではNVMコントローラなどで実装する。
+//!
+//! 保存するたびに次のスロットに書くので、同じページばかり消去することはない。
+//! 読むときは、CRCが合っているもののうち一番新しいものを使うので、
+//! 書いている途中で電源が切れても、前に保存したものが残る
+
+use crate::key_switch::{KeySwitches, SwitchId, MAX_SWITCHES, MAX_SWITCH_ACTIONS};
+use crate::keymap::{from_keycode, to_keycode, Keymap, KEYMAP_COLS, KEYMAP_LAYERS, KEYMAP_ROWS, UNSUPPORTED_KEYCODE};
+
+/// # 保存先
+///
+/// 消去はページ単位で、消去したところにしか書けないものとする（フラッシュと同じ）
+pub trait Storage {
+    type Error;
+
+    /// 1ページのバイト数
+    fn page_size(&self) -> usize;
+
+    /// ページの数
+    fn page_count(&self) -> usize;
+
+    fn read(&mut self, page: usize, offset: usize, buffer: &mut [u8]) -> Result<(), Self::Error>;
+
+    fn write(&mut self, page: usize, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn erase(&mut self, page: usize) -> Result<(), Self::Error>;
+}
+
+/// 保存形式のバージョン
+pub const STORAGE_VERSION: u8 = 1;
+
+const MAGIC: [u8; 2] = *b"MK";
+/// マジック、バージョン、予約、通し番号、中身の長さ、レイアウトの指紋
+const HEADER_SIZE: usize = 14;
+/// デフォルトレイヤ、レイアウトのオプション、書き換えたキーの数
+const SETTINGS_SIZE: usize = 7;
+/// レイヤ、row、col、キーコード
+const ENTRY_SIZE: usize = 5;
+/// スイッチのアクションの数
+const SWITCH_COUNT_SIZE: usize = 2;
+/// スイッチの番号、レイヤ、キーコード
+const SWITCH_ENTRY_SIZE: usize = 5;
+const CRC_SIZE: usize = 4;
+
+/// 1回に保存する最大のバイト数（全部のキーを書き換えて、全部のスイッチのアクションを保存するとき）
+pub const MAX_RECORD_SIZE: usize =
+    HEADER_SIZE + SETTINGS_SIZE + KEYMAP_LAYERS * KEYMAP_ROWS * KEYMAP_COLS * ENTRY_SIZE
+        + SWITCH_COUNT_SIZE + MAX_SWITCHES * MAX_SWITCH_ACTIONS * SWITCH_ENTRY_SIZE + CRC_SIZE;
+
+/// キーマップと一緒に保存する設定
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub struct Settings {
+    pub default_layer: u8,
+    /// VIAのレイアウトのオプション
+    pub layout_options: u32
+}
+
+/// 保存・読込のエラー
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StoreError<E> {
+    /// 保存先のエラー
+    Storage(E),
+    /// 保存先が小さすぎる（2スロット分は必要）
+    TooSmall,
+    /// 保存したものがない（壊れているものしかない）
+    NotFound,
+    /// 知らないバージョンで保存されている
+    UnsupportedVersion(u8)
+}
+
+/// 保存されているもの（スロット、通し番号、バージョン、中身の長さ）
+struct Record {
+    slot: usize,
+    sequence: u32,
+    version: u8,
+    len: usize,
+    /// 保存したときのレイアウトの指紋
+    fingerprint: u32,
+    /// 指紋と中身（設定、書き換えたキー、スイッチのアクション）のCRC
+    payload_crc: u32
+}
+
+/// # キーマップの保存・読込
+///
+/// `MAX_RECORD_SIZE`が入るだけのページを1スロットにして、保存するたびに次のスロットに書く
+pub struct KeymapStore<S: Storage> {
+    storage: S,
+    slot_pages: usize,
+    slots: usize,
+    /// 最後に読み書きしたもの（スロット、通し番号）
+    current: Option<(usize, u32)>,
+    /// 最後に読み書きした中身のCRC（変わっていなければ書かない）
+    last_crc: Option<u32>
+}
+
+impl<S: Storage> KeymapStore<S> {
+
+    pub fn new(storage: S) -> Result<Self, StoreError<S::Error>> {
+        let page_size = storage.page_size();
+        if page_size == 0 {
+            return Err(StoreError::TooSmall);
+        }
+        let slot_pages = (MAX_RECORD_SIZE + page_size - 1) / page_size;
+        let slots = storage.page_count() / slot_pages;
+        if slots < 2 {
+            return Err(StoreError::TooSmall);
+        }
+        Ok(Self {
+            storage,
+            slot_pages,
+            slots,
+            current: None,
+            last_crc: None
+        })
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// スロットの数
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// 1スロットのページ数
+    pub fn slot_pages(&self) -> usize {
+        self.slot_pages
+    }
+
+    /// 最後に読み書きしたスロット
+    pub fn current_slot(&self) -> Option<usize> {
+        self.current.map(|(slot, _)| slot)
+    }
+
+    /// 一番新しいものを読み込む
+    ///
+    /// キーマップは、書き換えたものを全部元に戻してから、保存されていたものを書き換える。
+    /// スイッチのアクションは、保存されていたものだけを置き換える（レイアウトの指紋が違えば置き換えない）
+    pub fn load(&mut self, keymap: &mut Keymap) -> Result<Settings, StoreError<S::Error>> {
+        let record = self.latest()?.ok_or(StoreError::NotFound)?;
+        if record.version != STORAGE_VERSION {
+            return Err(StoreError::UnsupportedVersion(record.version));
+        }
+        if record.len < SETTINGS_SIZE {
+            return Err(StoreError::NotFound);
+        }
+        let first = record.slot * self.slot_pages;
+        let mut head = [0_u8; SETTINGS_SIZE];
+        self.read_at(first, HEADER_SIZE, &mut head).map_err(StoreError::Storage)?;
+        let settings = Settings {
+            default_layer: head[0],
+            layout_options: u32::from_le_bytes([head[1], head[2], head[3], head[4]])
+        };
+        let count = u16::from_le_bytes([head[5], head[6]]) as usize;
+        let count = count.min((record.len - SETTINGS_SIZE) / ENTRY_SIZE);
+        keymap.reset();
+        let mut entry = [0_u8; ENTRY_SIZE];
+        for i in 0..count {
+            self.read_at(first, HEADER_SIZE + SETTINGS_SIZE + i * ENTRY_SIZE, &mut entry).map_err(StoreError::Storage)?;
+            let code = u16::from_le_bytes([entry[3], entry[4]]);
+            keymap.set_keycode(entry[0] as usize, entry[1] as usize, entry[2] as usize, code);
+        }
+
+        let mut offset = HEADER_SIZE + SETTINGS_SIZE + count * ENTRY_SIZE;
+        if record.fingerprint == fingerprint(keymap.switches()) && offset + SWITCH_COUNT_SIZE <= HEADER_SIZE + record.len {
+            let mut count = [0_u8; SWITCH_COUNT_SIZE];
+            self.read_at(first, offset, &mut count).map_err(StoreError::Storage)?;
+            offset += SWITCH_COUNT_SIZE;
+            let count = (u16::from_le_bytes(count) as usize).min((HEADER_SIZE + record.len - offset) / SWITCH_ENTRY_SIZE);
+            let mut entry = [0_u8; SWITCH_ENTRY_SIZE];
+            for i in 0..count {
+                self.read_at(first, offset + i * SWITCH_ENTRY_SIZE, &mut entry).map_err(StoreError::Storage)?;
+                let id = SwitchId(u16::from_le_bytes([entry[0], entry[1]]));
+                let layer = entry[2] as usize;
+                let action = from_keycode(u16::from_le_bytes([entry[3], entry[4]]));
+                if let (Some(switch), Some(action)) = (keymap.switches_mut().get_mut(id), action) {
+                    if layer < switch.actions.len() {
+                        switch.actions[layer] = action;
+                    } else if layer == switch.actions.len() {
+                        let _ = switch.actions.push(action);
+                    }
+                }
+            }
+        }
+        self.current = Some((record.slot, record.sequence));
+        self.last_crc = Some(record.payload_crc);
+        Ok(settings)
+    }
+
+    /// 保存する（前に読み書きしたものと同じならfalseを返して、何も書かない）
+    ///
+    /// 全体をRAMに置かずに、少しずつCRCを計算しながら書く
+    pub fn save(&mut self, keymap: &Keymap, settings: &Settings) -> Result<bool, StoreError<S::Error>> {
+        if self.current.is_none() {
+            self.current = self.latest()?.map(|r| (r.slot, r.sequence));
+        }
+
+        let fingerprint = fingerprint(keymap.switches());
+        let mut crc = Crc32::new();
+        crc.update(&fingerprint.to_le_bytes());
+        let len = encode(keymap, settings, &mut |data: &[u8]| -> Result<(), S::Error> {
+            crc.update(data);
+            Ok(())
+        })?;
+        let crc = crc.finish();
+        if self.last_crc == Some(crc) {
+            return Ok(false);
+        }
+
+        let (slot, sequence) = match self.current {
+            Some((slot, sequence)) => ((slot + 1) % self.slots, sequence.wrapping_add(1)),
+            None => (0, 0)
+        };
+        let mut header = [0_u8; HEADER_SIZE];
+        header[0..2].copy_from_slice(&MAGIC);
+        header[2] = STORAGE_VERSION;
+        header[3] = 0;
+        header[4..8].copy_from_slice(&sequence.to_le_bytes());
+        header[8..10].copy_from_slice(&(len as u16).to_le_bytes());
+        header[10..14].copy_from_slice(&fingerprint.to_le_bytes());
+        let total = HEADER_SIZE + len + CRC_SIZE;
+
+        // 使うページだけ消去する
+        let first = slot * self.slot_pages;
+        let page_size = self.storage.page_size();
+        for page in first..first + (total + page_size - 1) / page_size {
+            self.storage.erase(page).map_err(StoreError::Storage)?;
+        }
+        let mut writer = Writer::new(&mut self.storage, first);
+        writer.put(&header).map_err(StoreError::Storage)?;
+        encode(keymap, settings, &mut |data: &[u8]| writer.put(data))?;
+        let record_crc = writer.crc.finish();
+        writer.put(&record_crc.to_le_bytes()).map_err(StoreError::Storage)?;
+        writer.flush().map_err(StoreError::Storage)?;
+
+        self.current = Some((slot, sequence));
+        self.last_crc = Some(crc);
+        Ok(true)
+    }
+
+    /// CRCが合っているもののうち、一番新しいもの
+    fn latest(&mut self) -> Result<Option<Record>, StoreError<S::Error>> {
+        let mut latest: Option<Record> = None;
+        for slot in 0..self.slots {
+            if let Some(record) = self.read_record(slot).map_err(StoreError::Storage)? {
+                if latest.as_ref().map(|l| is_newer(record.sequence, l.sequence)).unwrap_or(true) {
+                    latest = Some(record);
+                }
+            }
+        }
+        Ok(latest)
+    }
+
+    fn read_record(&mut self, slot: usize) -> Result<Option<Record>, S::Error> {
+        let first = slot * self.slot_pages;
+        let mut header = [0_u8; HEADER_SIZE];
+        self.read_at(first, 0, &mut header)?;
+        if header[0..2] != MAGIC {
+            return Ok(None);
+        }
+        let len = u16::from_le_bytes([header[8], header[9]]) as usize;
+        let total = HEADER_SIZE + len;
+        if total + CRC_SIZE > MAX_RECORD_SIZE {
+            return Ok(None);
+        }
+
+        // 中身は少しずつ読んでCRCを計算する
+        let mut record_crc = Crc32::new();
+        record_crc.update(&header);
+        let mut payload_crc = Crc32::new();
+        payload_crc.update(&header[10..14]);
+        let mut chunk = [0_u8; CHUNK_SIZE];
+        let mut offset = HEADER_SIZE;
+        while offset < total {
+            let n = (total - offset).min(CHUNK_SIZE);
+            self.read_at(first, offset, &mut chunk[..n])?;
+            record_crc.update(&chunk[..n]);
+            payload_crc.update(&chunk[..n]);
+            offset += n;
+        }
+        let mut crc = [0_u8; CRC_SIZE];
+        self.read_at(first, total, &mut crc)?;
+        if record_crc.finish().to_le_bytes() != crc {
+            return Ok(None);
+        }
+        Ok(Some(Record {
+            slot,
+            sequence: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+            version: header[2],
+            len,
+            fingerprint: u32::from_le_bytes([header[10], header[11], header[12], header[13]]),
+            payload_crc: payload_crc.finish()
+        }))
+    }
+
+    /// スロットの先頭からoffsetの位置を、ページをまたいで読む
+    fn read_at(&mut self, first: usize, offset: usize, buffer: &mut [u8]) -> Result<(), S::Error> {
+        let page_size = self.storage.page_size();
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done;
+            let n = (buffer.len() - done).min(page_size - position % page_size);
+            self.storage.read(first + position / page_size, position % page_size, &mut buffer[done..done + n])?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+/// 通し番号がbより新しいか（一周して0に戻っても、差が半周以内なら新しい方が分かる）
+fn is_newer(a: u32, b: u32) -> bool {
+    a.wrapping_sub(b) as i32 > 0
+}
+
+/// 1回に読み書きするバイト数
+const CHUNK_SIZE: usize = 32;
+
+/// 保存する中身（設定、書き換えたキー、スイッチのアクション）を少しずつ渡す。返すのは中身の長さ
+fn encode<E, F>(keymap: &Keymap, settings: &Settings, out: &mut F) -> Result<usize, StoreError<E>>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>
+{
+    let count = keymap.remapped().count();
+    let options = settings.layout_options.to_le_bytes();
+    let count_bytes = (count as u16).to_le_bytes();
+    out(&[settings.default_layer, options[0], options[1], options[2], options[3], count_bytes[0], count_bytes[1]])
+        .map_err(StoreError::Storage)?;
+    for (layer, row, col, code) in keymap.remapped() {
+        let code = code.to_le_bytes();
+        out(&[layer as u8, row as u8, col as u8, code[0], code[1]]).map_err(StoreError::Storage)?;
+    }
+
+    let switch_count = switch_actions(keymap).count();
+    out(&(switch_count as u16).to_le_bytes()).map_err(StoreError::Storage)?;
+    for (id, layer, code) in switch_actions(keymap) {
+        let id = id.0.to_le_bytes();
+        let code = code.to_le_bytes();
+        out(&[id[0], id[1], layer as u8, code[0], code[1]]).map_err(StoreError::Storage)?;
+    }
+    Ok(SETTINGS_SIZE + count * ENTRY_SIZE + SWITCH_COUNT_SIZE + switch_count * SWITCH_ENTRY_SIZE)
+}
+
+/// デフォルトから書き換えた、キーコードで表せるスイッチのアクション（スイッチ、レイヤ、キーコード）
+fn switch_actions(keymap: &Keymap) -> impl Iterator<Item = (SwitchId, usize, u16)> + '_ {
+    let switches = keymap.switches();
+    switches.iter()
+        .flat_map(|(id, switch)| switch.actions.iter().enumerate().map(move |(layer, a)| (id, layer, to_keycode(a))))
+        .filter(move |(id, layer, code)| {
+            let default = switches.default_keycodes(*id).map(|d| d[*layer]);
+            *code != UNSUPPORTED_KEYCODE && default != Some(*code)
+        })
+}
+
+/// レイアウトの指紋（スイッチの数と、デフォルトのアクションのCRC）
+///
+/// ファームウェアを更新してスイッチの並びが変わったら、保存したスイッチのアクションは使えない
+fn fingerprint(switches: &KeySwitches) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&(switches.len() as u16).to_le_bytes());
+    for (id, _) in switches.iter() {
+        for code in switches.default_keycodes(id).iter().flat_map(|d| d.iter()) {
+            crc.update(&code.to_le_bytes());
+        }
+    }
+    crc.finish()
+}
+
+/// スロットの先頭から順に書く（`CHUNK_SIZE`ずつまとめて書く）
+struct Writer<'a, S: Storage> {
+    storage: &'a mut S,
+    first: usize,
+    offset: usize,
+    buffer: [u8; CHUNK_SIZE],
+    used: usize,
+    /// 書いたもの全体のCRC
+    crc: Crc32
+}
+
+impl<'a, S: Storage> Writer<'a, S> {
+
+    fn new(storage: &'a mut S, first: usize) -> Self {
+        Self {
+            storage,
+            first,
+            offset: 0,
+            buffer: [0; CHUNK_SIZE],
+            used: 0,
+            crc: Crc32::new()
+        }
+    }
+
+    fn put(&mut self, data: &[u8]) -> Result<(), S::Error> {
+        self.crc.update(data);
+        for b in data.iter() {
+            if self.used == CHUNK_SIZE {
+                self.flush()?;
+            }
+            self.buffer[self.used] = *b;
+            self.used += 1;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), S::Error> {
+        let page_size = self.storage.page_size();
+        let mut done = 0;
+        while done < self.used {
+            let position = self.offset + done;
+            let n = (self.used - done).min(page_size - position % page_size);
+            self.storage.write(self.first + position / page_size, position % page_size, &self.buffer[done..done + n])?;
+            done += n;
+        }
+        self.offset += self.used;
+        self.used = 0;
+        Ok(())
+    }
+}
+
+/// CRC-32（IEEE 802.3）
+struct Crc32(u32);
+
+impl Crc32 {
+
+    fn new() -> Self {
+        Crc32(0xFFFF_FFFF)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for b in data.iter() {
+            self.0 ^= *b as u32;
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 { (self.0 >> 1) ^ 0xEDB8_8320 } else { self.0 >> 1 };
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// `MemoryStorage`のエラー
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MemoryError {
+    /// ページの外を読み書きしようとした
+    OutOfRange
+}
+
+/// # RAM上の保存先
+///
+/// フラッシュと同じように、消去すると0xFFになり、書き込みでは1を0にしかできない。
+/// ホストでのテストや、電源が切れたら消えてもいいときに使う
+pub struct MemoryStorage<'a> {
+    memory: &'a mut [u8],
+    page_size: usize
+}
+
+impl<'a> MemoryStorage<'a> {
+
+    pub fn new(memory: &'a mut [u8], page_size: usize) -> Self {
+        Self {
+            memory,
+            page_size
+        }
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.memory
+    }
+
+    fn range(&self, page: usize, offset: usize, len: usize) -> Result<core::ops::Range<usize>, MemoryError> {
+        if page >= self.page_count() || offset + len > self.page_size {
+            return Err(MemoryError::OutOfRange);
+        }
+        let start = page * self.page_size + offset;
+        Ok(start..start + len)
+    }
+}
+
+impl Storage for MemoryStorage<'_> {
+    type Error = MemoryError;
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn page_count(&self) -> usize {
+        self.memory.len().checked_div(self.page_size).unwrap_or(0)
+    }
+
+    fn read(&mut self, page: usize, offset: usize, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let range = self.range(page, offset, buffer.len())?;
+        buffer.copy_from_slice(&self.memory[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, page: usize, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        let range = self.range(page, offset, data.len())?;
+        for (m, d) in self.memory[range].iter_mut().zip(data.iter()) {
+            *m &= *d;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), Self::Error> {
+        let range = self.range(page, 0, self.page_size)?;
+        for m in self.memory[range].iter_mut() {
+            *m = 0xFF;
+        }
+        Ok(())
+    }
+}
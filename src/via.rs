@@ -0,0 +1,151 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+//! # VIA/Remapのプロトコル
+//!
+//! Raw HIDの32バイトのパケットを受け取って、その場で返事に書き換える（QMKの`raw_hid_receive`と同じ）。
+//! rowとcolは`Keymap`の仮想マトリクスなので、VIAの定義ファイルのmatrixは
+//! `{"rows": 8, "cols": 16}`（`KEYMAP_ROWS`, `KEYMAP_COLS`）にしておく。
+//!
+//! マクロのバッファは預かるだけで、makbe-ffでは実行しない
+
+use crate::keymap::{Keymap, KEYMAP_COLS, KEYMAP_LAYERS, KEYMAP_ROWS};
+
+/// パケットの大きさ
+pub const VIA_PACKET_SIZE: usize = 32;
+/// プロトコルのバージョン
+pub const VIA_PROTOCOL_VERSION: u16 = 0x0009;
+/// マクロの数
+pub const VIA_MACRO_COUNT: u8 = 16;
+/// マクロのバッファの大きさ
+pub const VIA_MACRO_BUFFER_SIZE: usize = 256;
+
+const ID_GET_PROTOCOL_VERSION: u8 = 0x01;
+const ID_GET_KEYBOARD_VALUE: u8 = 0x02;
+const ID_SET_KEYBOARD_VALUE: u8 = 0x03;
+const ID_DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const ID_DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const ID_DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+const ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+const ID_DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const ID_DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const ID_DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const ID_UNHANDLED: u8 = 0xFF;
+
+const ID_LAYOUT_OPTIONS: u8 = 0x02;
+
+/// バッファの読み書きで、1回に扱えるバイト数
+const MAX_CHUNK: usize = VIA_PACKET_SIZE - 4;
+
+/// # VIAのコマンドの処理
+pub struct Via {
+    macros: [u8; VIA_MACRO_BUFFER_SIZE],
+    layout_options: u32
+}
+
+impl Via {
+
+    pub fn new() -> Self {
+        Self {
+            macros: [0; VIA_MACRO_BUFFER_SIZE],
+            layout_options: 0
+        }
+    }
+
+    /// マクロのバッファ（VIAの形式のまま）
+    pub fn macro_buffer(&self) -> &[u8] {
+        &self.macros
+    }
+
+    /// レイアウトのオプション（VIAの定義ファイルのlayoutsで選んだもの）
+    pub fn layout_options(&self) -> u32 {
+        self.layout_options
+    }
+
+    /// 保存しておいたレイアウトのオプションを戻す
+    pub fn set_layout_options(&mut self, options: u32) {
+        self.layout_options = options;
+    }
+
+    /// パケットを処理して、返事に書き換える
+    ///
+    /// 知らないコマンドは先頭を`0xFF`にして返す
+    pub fn handle(&mut self, keymap: &mut Keymap, data: &mut [u8; VIA_PACKET_SIZE]) {
+        match data[0] {
+            ID_GET_PROTOCOL_VERSION => {
+                data[1..3].copy_from_slice(&VIA_PROTOCOL_VERSION.to_be_bytes());
+            }
+            ID_GET_KEYBOARD_VALUE if data[1] == ID_LAYOUT_OPTIONS => {
+                data[2..6].copy_from_slice(&self.layout_options.to_be_bytes());
+            }
+            ID_SET_KEYBOARD_VALUE if data[1] == ID_LAYOUT_OPTIONS => {
+                self.layout_options = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+            }
+            ID_DYNAMIC_KEYMAP_GET_KEYCODE => {
+                let code = keymap.keycode(data[1] as usize, data[2] as usize, data[3] as usize);
+                data[4..6].copy_from_slice(&code.to_be_bytes());
+            }
+            ID_DYNAMIC_KEYMAP_SET_KEYCODE => {
+                let code = u16::from_be_bytes([data[4], data[5]]);
+                keymap.set_keycode(data[1] as usize, data[2] as usize, data[3] as usize, code);
+            }
+            ID_DYNAMIC_KEYMAP_RESET => keymap.reset(),
+            ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT => data[1] = VIA_MACRO_COUNT,
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+                data[1..3].copy_from_slice(&(VIA_MACRO_BUFFER_SIZE as u16).to_be_bytes());
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+                let (offset, size) = chunk(data, VIA_MACRO_BUFFER_SIZE);
+                data[4..4 + size].copy_from_slice(&self.macros[offset..offset + size]);
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+                let (offset, size) = chunk(data, VIA_MACRO_BUFFER_SIZE);
+                self.macros[offset..offset + size].copy_from_slice(&data[4..4 + size]);
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_RESET => self.macros = [0; VIA_MACRO_BUFFER_SIZE],
+            ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT => data[1] = KEYMAP_LAYERS as u8,
+            ID_DYNAMIC_KEYMAP_GET_BUFFER => {
+                let (offset, size) = chunk(data, KEYMAP_BUFFER_SIZE);
+                for i in offset..offset + size {
+                    let (layer, row, col) = keymap_position(i / 2);
+                    data[4 + i - offset] = keymap.keycode(layer, row, col).to_be_bytes()[i % 2];
+                }
+            }
+            ID_DYNAMIC_KEYMAP_SET_BUFFER => {
+                let (offset, size) = chunk(data, KEYMAP_BUFFER_SIZE);
+                for i in offset..offset + size {
+                    let (layer, row, col) = keymap_position(i / 2);
+                    let mut bytes = keymap.keycode(layer, row, col).to_be_bytes();
+                    bytes[i % 2] = data[4 + i - offset];
+                    keymap.set_keycode(layer, row, col, u16::from_be_bytes(bytes));
+                }
+            }
+            _ => data[0] = ID_UNHANDLED
+        }
+    }
+}
+
+impl Default for Via {
+    fn default() -> Self { Via::new() }
+}
+
+/// キーマップのバッファの大きさ（レイヤ、row、colの順に並べた16bitのキーコード）
+const KEYMAP_BUFFER_SIZE: usize = KEYMAP_LAYERS * KEYMAP_ROWS * KEYMAP_COLS * 2;
+
+fn keymap_position(index: usize) -> (usize, usize, usize) {
+    let layer = index / (KEYMAP_ROWS * KEYMAP_COLS);
+    let row = index / KEYMAP_COLS % KEYMAP_ROWS;
+    (layer, row, index % KEYMAP_COLS)
+}
+
+/// バッファの読み書きの位置と大きさ（バッファからはみ出さないようにする）
+fn chunk(data: &[u8; VIA_PACKET_SIZE], len: usize) -> (usize, usize) {
+    let offset = (u16::from_be_bytes([data[1], data[2]]) as usize).min(len);
+    let size = (data[3] as usize).min(MAX_CHUNK).min(len - offset);
+    (offset, size)
+}
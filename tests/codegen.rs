@@ -0,0 +1,101 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use makbe_ff::codegen::{generate, LayoutError};
+
+const LAYOUT: &str = r#"{
+    i2c: "I2CMaster",
+    error: "I2CError",
+    layers: ["BASE", "LOWER"],
+    kle: [["escape", {w: 1.5}, "tab"], [{r: 15, rx: 1, ry: 2}, "rot"]],
+    switches: [
+        {name: "escape", actions: ["k(Escape)", "l(LOWER)"]},
+        {name: "tab", default: "k(Tab)"},
+        {name: "rot", label: "rot"},
+        {name: "kb1", x: 1.5, y: 0.5, shape: "iso", actions: ["k(Kb1)"]},
+    ],
+    devices: [
+        {type: "TCA9555", address: 0, pins: {0: "escape", 15: "tab"}},
+        {type: "TCA9554", address: 1, debounce: 10, pins: ["kb1", null, "rot"]},
+    ],
+}"#;
+
+fn with(from: &str, to: &str) -> String {
+    assert!(LAYOUT.contains(from));
+    LAYOUT.replacen(from, to, 1)
+}
+
+#[test]
+fn generates_switches_and_devices() {
+    let code = generate(LAYOUT).unwrap();
+
+    assert!(code.contains("pub const LOWER: usize = 1;"));
+    // KLEの位置
+    assert!(code.contains("switch escape = makbe_ff::key_switch::KeySwitch::new_with_size(0.5, 0.5, 1.0, 1.0)\
+        .apply(|s| s.append_action(k(Escape)).append_action(l(LOWER))),"));
+    assert!(code.contains("switch tab = makbe_ff::key_switch::KeySwitch::new_with_size(1.75, 0.5, 1.5, 1.0)\
+        .apply(|s| s.default_action(k(Tab))),"));
+    assert!(code.contains("switch rot = makbe_ff::key_switch::KeySwitch::new_with_size(1.5, 2.5, 1.0, 1.0)\
+        .apply(|s| s.rotate_at(-15.0, 1.0, 2.0)),"));
+    // 書いてある位置
+    assert!(code.contains("switch kb1 = makbe_ff::key_switch::KeySwitch::new_with_shape(\
+        makbe_ff::key_switch::Shape::IsoEnter, 1.5, 0.5).apply(|s| s.append_action(k(Kb1))),"));
+
+    assert!(code.contains("pub device1: &'static makbe_ff::devices::tca9554::TCA9554<I2CMaster, I2CError>,"));
+    assert!(code.contains("let mut device = makbe_ff::devices::tca9555::TCA9555::new(0x0, 5);"));
+    assert!(code.contains("let mut device = makbe_ff::devices::tca9554::TCA9554::new(0x1, 10);"));
+    assert!(code.contains("let _ = device.assign(15, switches.tab);"));
+    assert!(code.contains("let _ = device.assign(2, switches.rot);"));
+    assert!(!code.contains("assign(1,"));
+    assert!(code.contains("let _ = holder.devices.push(self.device1);"));
+    // スイッチの表を渡すと、Layoutは使い切る
+    assert!(code.contains("pub fn keymap(self) -> makbe_ff::keymap::Keymap {"));
+    assert!(!code.contains("mem::take"));
+}
+
+#[test]
+fn rejects_broken_layouts() {
+    assert!(matches!(generate("{i2c: \"I2CMaster\""), Err(LayoutError::Parse(_))));
+    assert_eq!(generate(&with("    i2c: \"I2CMaster\",\n", "")), Err(LayoutError::Format("expected i2c type")));
+    assert_eq!(
+        generate(&with("name: \"tab\"", "name: \"escape\"")),
+        Err(LayoutError::DuplicateSwitch("escape".to_string()))
+    );
+    assert_eq!(
+        generate(&with("name: \"tab\"", "name: \"tab-key\"")),
+        Err(LayoutError::InvalidName("tab-key".to_string()))
+    );
+    assert_eq!(
+        generate(&with("15: \"tab\"", "15: \"enter\"")),
+        Err(LayoutError::UnknownSwitch("enter".to_string()))
+    );
+    assert_eq!(
+        generate(&with("label: \"rot\"", "label: \"R\"")),
+        Err(LayoutError::NoPosition("rot".to_string()))
+    );
+    assert_eq!(
+        generate(&with("[\"kb1\", null, \"rot\"]", "{8: \"kb1\"}")),
+        Err(LayoutError::PinOutOfRange { device: 1, pin: 8 })
+    );
+    assert_eq!(
+        generate(&with("address: 1,", "address: 8,")),
+        Err(LayoutError::AddressOutOfRange { device: 1, address: 8.0 })
+    );
+    assert_eq!(
+        generate(&with("address: 0,", "address: -1,")),
+        Err(LayoutError::AddressOutOfRange { device: 0, address: -1.0 })
+    );
+    assert_eq!(
+        generate(&with("debounce: 10,", "debounce: 70000,")),
+        Err(LayoutError::DebounceOutOfRange { device: 1, debounce: 70000.0 })
+    );
+    assert_eq!(
+        generate(&with("debounce: 10,", "debounce: 2.5,")),
+        Err(LayoutError::DebounceOutOfRange { device: 1, debounce: 2.5 })
+    );
+    assert_eq!(
+        generate(&with("\"TCA9554\"", "\"MCP23017\"")),
+        Err(LayoutError::UnknownDevice("MCP23017".to_string()))
+    );
+}
@@ -0,0 +1,73 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use heapless::consts::U8;
+use makbe_ff::debouncer::{DebounceStrategy, Debouncer};
+use makbe_ff::event::IndexEvent::{self, PressedAt, ReleasedAt};
+
+/// pin0とpin1の状態を読ませて、確定したイベントを返す
+fn feed(debouncer: &mut Debouncer<U8>, pin0: bool, pin1: bool, now: u32) -> Vec<String> {
+    let mut pins = [false; 8];
+    pins[0] = pin0;
+    pins[1] = pin1;
+    debouncer.events(&pins, now).buffer.iter().map(describe).collect()
+}
+
+fn describe(e: &IndexEvent) -> String {
+    match e {
+        PressedAt(i) => format!("P{}", i),
+        ReleasedAt(i) => format!("R{}", i)
+    }
+}
+
+#[test]
+fn deferred_waits_for_whole_device() {
+    let mut debouncer: Debouncer<U8> = Debouncer::new(3);
+    assert!(feed(&mut debouncer, true, false, 0).is_empty());
+    // pin1のチャタリングでpin0も待たされる
+    assert!(feed(&mut debouncer, true, true, 2).is_empty());
+    assert!(feed(&mut debouncer, true, false, 4).is_empty());
+    assert!(feed(&mut debouncer, true, false, 6).is_empty());
+    assert_eq!(feed(&mut debouncer, true, false, 7), vec!["P0"]);
+}
+
+#[test]
+fn per_key_deferred_is_independent_per_pin() {
+    let mut debouncer: Debouncer<U8> = Debouncer::with_strategy(3, DebounceStrategy::PerKeyDeferred);
+    assert!(feed(&mut debouncer, true, false, 0).is_empty());
+    assert!(feed(&mut debouncer, true, true, 2).is_empty());
+    assert_eq!(feed(&mut debouncer, true, false, 3), vec!["P0"]);
+    assert!(debouncer.is_settled());
+    assert!(feed(&mut debouncer, false, false, 10).is_empty());
+    assert!(feed(&mut debouncer, true, false, 11).is_empty());
+    assert!(feed(&mut debouncer, false, false, 12).is_empty());
+    assert_eq!(feed(&mut debouncer, false, false, 15), vec!["R0"]);
+}
+
+#[test]
+fn eager_press_and_deferred_release() {
+    let mut debouncer: Debouncer<U8> = Debouncer::with_strategy(3, DebounceStrategy::EagerPressDeferredRelease);
+    assert_eq!(feed(&mut debouncer, true, false, 0), vec!["P0"]);
+    // 押した直後のチャタリング
+    assert!(feed(&mut debouncer, false, false, 1).is_empty());
+    assert!(feed(&mut debouncer, true, false, 2).is_empty());
+    assert!(feed(&mut debouncer, false, false, 3).is_empty());
+    assert!(feed(&mut debouncer, false, false, 5).is_empty());
+    assert_eq!(feed(&mut debouncer, false, false, 6), vec!["R0"]);
+    assert!(debouncer.is_settled());
+}
+
+#[test]
+fn symmetric_eager_ignores_changes_within_window() {
+    let mut debouncer: Debouncer<U8> = Debouncer::with_strategy(3, DebounceStrategy::SymmetricEager);
+    assert_eq!(feed(&mut debouncer, true, true, 0), vec!["P0", "P1"]);
+    assert!(feed(&mut debouncer, false, false, 1).is_empty());
+    assert!(!debouncer.is_settled());
+    assert_eq!(feed(&mut debouncer, true, false, 3), vec!["R1"]);
+    // pin1は確定したばかりなので無視
+    assert_eq!(feed(&mut debouncer, false, true, 4), vec!["R0"]);
+    assert_eq!(feed(&mut debouncer, false, true, 6), vec!["P1"]);
+    assert!(feed(&mut debouncer, false, true, 9).is_empty());
+    assert!(debouncer.is_settled());
+}
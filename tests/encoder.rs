@@ -0,0 +1,134 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use makbe_ff::action::k;
+use keyberon::key_code::KeyCode;
+use makbe_ff::device::{Device, DeviceHolder};
+use makbe_ff::devices::counter::{Counter, CounterWidth};
+use makbe_ff::devices::quadrature::Quadrature;
+use makbe_ff::devices::tca9555::TCA9555;
+use makbe_ff::encoder::{QuadratureDecoder, Rotation, CLOCKWISE, COUNTER_CLOCKWISE};
+use makbe_ff::evaluator::Evaluator;
+use makbe_ff::key_switch::{KeySwitch, SwitchId};
+use makbe_ff::mock::{MockBus, MockError, RecordingReporter, SimCounter, SimExpander};
+use makbe_ff::scanner::Scanner;
+
+fn switch(evaluator: &mut Evaluator, kc: KeyCode) -> SwitchId {
+    evaluator.switches_mut().add(KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(k(kc)))).unwrap()
+}
+
+#[test]
+fn delta_handles_wraparound() {
+    assert_eq!(Rotation::delta(0x10, 0x13, 8), 3);
+    assert_eq!(Rotation::delta(0xFE, 0x01, 8), 3);
+    assert_eq!(Rotation::delta(0x01, 0xFE, 8), -3);
+    assert_eq!(Rotation::delta(0x0000, 0xFFFF, 16), -1);
+    assert_eq!(Rotation::delta(0xFFFF_FFFF, 0x0000_0001, 32), 2);
+}
+
+#[test]
+fn resolution_accumulates_partial_steps() {
+    let mut rotation = Rotation::new(4);
+    assert_eq!(rotation.update(100, 16), 0);
+    assert_eq!(rotation.update(103, 16), 0);
+    assert_eq!(rotation.update(105, 16), 1);
+    assert_eq!(rotation.update(97, 16), -1);
+    assert_eq!(rotation.update(96, 16), -1);
+}
+
+#[test]
+fn counter_steps_are_tapped() {
+    let mut evaluator = Evaluator::new();
+    let mut bus = MockBus::new();
+    bus.attach(SimCounter::new(0x30, 1));
+    let mut device = Counter::new(0x30, CounterWidth::Bits8, 1);
+    device.assign(CLOCKWISE, switch(&mut evaluator, KeyCode::VolUp)).unwrap();
+    device.assign(COUNTER_CLOCKWISE, switch(&mut evaluator, KeyCode::VolDown)).unwrap();
+    let mut holder: DeviceHolder<MockBus, MockError> = DeviceHolder::new();
+    holder.devices.push(Box::leak(Box::new(device))).ok().unwrap();
+    let mut scanner = Scanner::new(evaluator);
+    let mut reporter = RecordingReporter::new();
+
+    scanner.scan(&mut bus, &holder, 0, &mut reporter);
+    bus.counter_mut(0x30).unwrap().rotate(2);
+    scanner.scan(&mut bus, &holder, 0, &mut reporter);
+    for _ in 0..4 {
+        scanner.tick(&mut reporter);
+    }
+    assert_eq!(
+        reporter.changes(),
+        vec![vec![KeyCode::VolUp], vec![], vec![KeyCode::VolUp], vec![]]
+    );
+
+    // 0を跨いで反時計回り
+    bus.counter_mut(0x30).unwrap().rotate(-3);
+    assert_eq!(bus.counter_mut(0x30).unwrap().value(), 0xFF);
+    let mut reporter = RecordingReporter::new();
+    scanner.scan(&mut bus, &holder, 0, &mut reporter);
+    for _ in 0..8 {
+        scanner.tick(&mut reporter);
+    }
+    assert_eq!(
+        reporter.changes(),
+        vec![vec![KeyCode::VolDown], vec![], vec![KeyCode::VolDown], vec![], vec![KeyCode::VolDown], vec![]]
+    );
+}
+
+#[test]
+fn quadrature_decoder_counts_valid_transitions_only() {
+    let mut decoder = QuadratureDecoder::new(4);
+    assert_eq!(decoder.update(false, false), 0);
+    // 時計回りに1周
+    assert_eq!(decoder.update(true, false), 0);
+    assert_eq!(decoder.update(true, true), 0);
+    assert_eq!(decoder.update(false, true), 0);
+    assert_eq!(decoder.update(false, false), 1);
+    // 2ビット同時の変化は無視
+    assert_eq!(decoder.update(true, true), 0);
+    assert_eq!(decoder.update(false, false), 0);
+    // 反時計回りに1周
+    assert_eq!(decoder.update(false, true), 0);
+    assert_eq!(decoder.update(true, true), 0);
+    assert_eq!(decoder.update(true, false), 0);
+    assert_eq!(decoder.update(false, false), -1);
+}
+
+#[test]
+fn quadrature_pins_coexist_with_switches() {
+    let mut evaluator = Evaluator::new();
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9555(0x20));
+    let mut device = Quadrature::new(TCA9555::new(0x0, 1));
+    device.assign_encoder(14, 15, 4).unwrap();
+    device.assign(14, switch(&mut evaluator, KeyCode::VolUp)).unwrap();
+    device.assign(15, switch(&mut evaluator, KeyCode::VolDown)).unwrap();
+    device.assign(0, switch(&mut evaluator, KeyCode::A)).unwrap();
+    assert!(device.assign_encoder(15, 3, 4).is_err());
+    let mut holder: DeviceHolder<MockBus, MockError> = DeviceHolder::new();
+    holder.devices.push(Box::leak(Box::new(device))).ok().unwrap();
+    let mut scanner = Scanner::new(evaluator);
+    let mut reporter = RecordingReporter::new();
+
+    let mut now = 0_u32;
+    let mut cycle = |bus: &mut MockBus, reporter: &mut RecordingReporter| {
+        now += 1;
+        scanner.scan(bus, &holder, now, reporter);
+        scanner.tick(reporter);
+    };
+    for _ in 0..3 {
+        cycle(&mut bus, &mut reporter);
+    }
+    // 反時計回りに1デテント（ピンはプルアップなので、押す＝Low）
+    for (a, b) in [(false, true), (true, true), (true, false), (false, false)].iter() {
+        let sim = bus.expander_mut(0x20).unwrap();
+        if *a { sim.press(14) } else { sim.release(14) }
+        if *b { sim.press(15) } else { sim.release(15) }
+        cycle(&mut bus, &mut reporter);
+    }
+    bus.expander_mut(0x20).unwrap().press(0);
+    for _ in 0..4 {
+        cycle(&mut bus, &mut reporter);
+    }
+    assert_eq!(reporter.changes(), vec![vec![KeyCode::VolDown], vec![], vec![KeyCode::A]]);
+}
@@ -0,0 +1,648 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use makbe_ff::action::{k, l, os, Action, HoldTapConfig, OneShotHold};
+use keyberon::key_code::KeyCode;
+use keyberon::key_code::KeyCode::*;
+use makbe_ff::evaluator::{Combo, Evaluator, LeaderSequence, RepeatConfig, RepeatTarget};
+use makbe_ff::event::KeyEvent::{Pressed, Released};
+use makbe_ff::key_switch::{KeySwitch, SwitchId};
+use makbe_ff::mock::RecordingReporter;
+use makbe_ff::reporter::ReportError;
+
+fn add(evaluator: &mut Evaluator, switch: KeySwitch) -> SwitchId {
+    evaluator.switches_mut().add(switch).unwrap()
+}
+
+fn switch(evaluator: &mut Evaluator, action: Action) -> SwitchId {
+    add(evaluator, KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(action)))
+}
+
+fn leak<T>(t: T) -> &'static T {
+    Box::leak(Box::new(t))
+}
+
+fn ticks(evaluator: &mut Evaluator, reporter: &mut RecordingReporter, n: usize) {
+    for _ in 0..n {
+        evaluator.tick(reporter);
+    }
+}
+
+fn changes(reporter: &RecordingReporter) -> Vec<Vec<KeyCode>> {
+    reporter.changes()
+}
+
+#[test]
+fn combo_replaces_its_keys() {
+    let mut evaluator = Evaluator::new();
+    let j = switch(&mut evaluator, k(J));
+    let kk = switch(&mut evaluator, k(K));
+    let l = switch(&mut evaluator, k(L));
+    evaluator.add_combo(Combo::new(leak([j, kk]), leak(k(Escape)))).unwrap();
+    evaluator.set_combo_timeout(10);
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(j), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 3);
+    evaluator.eval(Pressed(kk), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 3);
+    assert_eq!(reporter.last(), &[Escape]);
+    evaluator.eval(Released(kk), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    evaluator.eval(Released(j), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(changes(&reporter), vec![vec![Escape], vec![]]);
+
+    // 1つだけならタイムアウトで普通のキー
+    let mut reporter = RecordingReporter::new();
+    evaluator.eval(Pressed(j), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 9);
+    assert_eq!(reporter.last(), &[]);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(reporter.last(), &[J]);
+    evaluator.eval(Released(j), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+
+    // コンボにないキーが割り込んだら、普通のキー
+    let mut reporter = RecordingReporter::new();
+    evaluator.eval(Pressed(j), &mut reporter);
+    evaluator.eval(Pressed(l), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 3);
+    assert_eq!(changes(&reporter), vec![vec![J], vec![J, L]]);
+}
+
+#[test]
+fn one_shot_modifier_applies_to_next_key_only() {
+    let mut evaluator = Evaluator::new();
+    let shift = switch(&mut evaluator, os(leak(k(LShift)), 20));
+    let a = switch(&mut evaluator, k(A));
+    let b = switch(&mut evaluator, k(B));
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(shift), &mut reporter);
+    evaluator.eval(Released(shift), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 3);
+    assert_eq!(reporter.last(), &[LShift]);
+    evaluator.eval(Pressed(a), &mut reporter);
+    evaluator.eval(Released(a), &mut reporter);
+    evaluator.eval(Pressed(b), &mut reporter);
+    evaluator.eval(Released(b), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 6);
+    assert_eq!(
+        changes(&reporter),
+        vec![vec![LShift], vec![LShift, A], vec![], vec![B], vec![]]
+    );
+}
+
+#[test]
+fn one_shot_times_out_or_cancels() {
+    let mut evaluator = Evaluator::new();
+    let shift = switch(&mut evaluator, os(leak(k(LShift)), 5));
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(shift), &mut reporter);
+    evaluator.eval(Released(shift), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 8);
+    assert_eq!(changes(&reporter), vec![vec![LShift], vec![]]);
+
+    // もう一度タップしたら解除
+    let mut reporter = RecordingReporter::new();
+    evaluator.eval(Pressed(shift), &mut reporter);
+    evaluator.eval(Released(shift), &mut reporter);
+    evaluator.eval(Pressed(shift), &mut reporter);
+    evaluator.eval(Released(shift), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 4);
+    assert_eq!(changes(&reporter), vec![vec![LShift], vec![]]);
+}
+
+#[test]
+fn one_shot_held_behaves_as_modifier_unless_sticky() {
+    let mut evaluator = Evaluator::new();
+    let shift = switch(&mut evaluator, os(leak(k(LShift)), 20));
+    let ctrl = switch(&mut evaluator, Action::OneShot { action: leak(k(LCtrl)), timeout: 20, hold: OneShotHold::Sticky });
+    let a = switch(&mut evaluator, k(A));
+    let b = switch(&mut evaluator, k(B));
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(shift), &mut reporter);
+    evaluator.eval(Pressed(a), &mut reporter);
+    evaluator.eval(Released(a), &mut reporter);
+    evaluator.eval(Released(shift), &mut reporter);
+    evaluator.eval(Pressed(b), &mut reporter);
+    evaluator.eval(Released(b), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 8);
+    assert_eq!(
+        changes(&reporter),
+        vec![vec![LShift], vec![LShift, A], vec![LShift], vec![], vec![B], vec![]]
+    );
+
+    let mut reporter = RecordingReporter::new();
+    evaluator.eval(Pressed(ctrl), &mut reporter);
+    evaluator.eval(Pressed(a), &mut reporter);
+    evaluator.eval(Released(a), &mut reporter);
+    evaluator.eval(Released(ctrl), &mut reporter);
+    evaluator.eval(Pressed(b), &mut reporter);
+    evaluator.eval(Released(b), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 8);
+    assert_eq!(
+        changes(&reporter),
+        vec![vec![LCtrl], vec![LCtrl, A], vec![LCtrl], vec![LCtrl, B], vec![]]
+    );
+}
+
+#[test]
+fn one_shot_layer_selects_next_key() {
+    let mut evaluator = Evaluator::new();
+    let upper = add(&mut evaluator, KeySwitch::new(0.0, 0.0).apply(|s| {
+        s.append_action(k(A)).append_action(k(Kb1))
+    }));
+    let layer = switch(&mut evaluator, os(leak(l(1)), 20));
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(layer), &mut reporter);
+    evaluator.eval(Released(layer), &mut reporter);
+    evaluator.eval(Pressed(upper), &mut reporter);
+    evaluator.eval(Released(upper), &mut reporter);
+    evaluator.eval(Pressed(upper), &mut reporter);
+    evaluator.eval(Released(upper), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 8);
+    assert_eq!(changes(&reporter), vec![vec![Kb1], vec![], vec![A], vec![]]);
+}
+
+fn tap(evaluator: &mut Evaluator, reporter: &mut RecordingReporter, switch: SwitchId) {
+    evaluator.eval(Pressed(switch), reporter);
+    ticks(evaluator, reporter, 1);
+    evaluator.eval(Released(switch), reporter);
+    ticks(evaluator, reporter, 1);
+}
+
+#[test]
+fn tap_dance_counts_taps() {
+    let mut evaluator = Evaluator::new();
+    let dance = switch(&mut evaluator, Action::TapDance {
+        timeout: 10,
+        taps: leak([k(Escape), k(CapsLock)]),
+        holds: leak([Action::NoOp, l(1)])
+    });
+    let mut reporter = RecordingReporter::new();
+
+    tap(&mut evaluator, &mut reporter, dance);
+    ticks(&mut evaluator, &mut reporter, 7);
+    assert_eq!(reporter.last(), &[]);
+    ticks(&mut evaluator, &mut reporter, 4);
+    assert_eq!(changes(&reporter), vec![vec![Escape], vec![]]);
+
+    // 2回で最後なので、待たずに決まる
+    let mut reporter = RecordingReporter::new();
+    tap(&mut evaluator, &mut reporter, dance);
+    tap(&mut evaluator, &mut reporter, dance);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(changes(&reporter), vec![vec![CapsLock], vec![]]);
+}
+
+#[test]
+fn tap_dance_hold_and_interrupt() {
+    let mut evaluator = Evaluator::new();
+    let upper = add(&mut evaluator, KeySwitch::new(0.0, 0.0).apply(|s| {
+        s.append_action(k(A)).append_action(k(Kb1))
+    }));
+    let dance = switch(&mut evaluator, Action::TapDance {
+        timeout: 10,
+        taps: leak([k(Escape), k(CapsLock)]),
+        holds: leak([Action::NoOp, l(1)])
+    });
+    let mut reporter = RecordingReporter::new();
+
+    // タップしてから押したまま
+    tap(&mut evaluator, &mut reporter, dance);
+    evaluator.eval(Pressed(dance), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 12);
+    tap(&mut evaluator, &mut reporter, upper);
+    evaluator.eval(Released(dance), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    tap(&mut evaluator, &mut reporter, upper);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(changes(&reporter), vec![vec![Kb1], vec![], vec![A], vec![]]);
+
+    // 他のキーが押されたら、その時点で1回
+    let mut reporter = RecordingReporter::new();
+    tap(&mut evaluator, &mut reporter, dance);
+    tap(&mut evaluator, &mut reporter, upper);
+    ticks(&mut evaluator, &mut reporter, 4);
+    assert_eq!(changes(&reporter), vec![vec![Escape], vec![], vec![A], vec![]]);
+}
+
+fn hold_tap(evaluator: &mut Evaluator, config: HoldTapConfig, tap_hold_interval: u16) -> SwitchId {
+    switch(evaluator, Action::HoldTap {
+        timeout: 20,
+        hold: leak(k(LShift)),
+        tap: leak(k(Space)),
+        config,
+        tap_hold_interval
+    })
+}
+
+/// ホールドタップを押したまま、他のキーをタップしてからホールドタップを離す
+fn roll(evaluator: &mut Evaluator, thumb: SwitchId, other: SwitchId) -> Vec<Vec<KeyCode>> {
+    let mut reporter = RecordingReporter::new();
+    evaluator.eval(Pressed(thumb), &mut reporter);
+    ticks(evaluator, &mut reporter, 2);
+    evaluator.eval(Pressed(other), &mut reporter);
+    ticks(evaluator, &mut reporter, 2);
+    evaluator.eval(Released(other), &mut reporter);
+    ticks(evaluator, &mut reporter, 2);
+    evaluator.eval(Released(thumb), &mut reporter);
+    ticks(evaluator, &mut reporter, 6);
+    changes(&reporter)
+}
+
+#[test]
+fn hold_tap_flavors_decide_on_other_keys() {
+    let mut evaluator = Evaluator::new();
+    let a = switch(&mut evaluator, k(A));
+
+    let tap_preferred = hold_tap(&mut evaluator, HoldTapConfig::TapPreferred, 0);
+    assert_eq!(
+        roll(&mut evaluator, tap_preferred, a),
+        vec![vec![Space], vec![Space, A], vec![Space], vec![]]
+    );
+    let permissive = hold_tap(&mut evaluator, HoldTapConfig::PermissiveHold, 0);
+    assert_eq!(
+        roll(&mut evaluator, permissive, a),
+        vec![vec![LShift], vec![LShift, A], vec![LShift], vec![]]
+    );
+    let hold_on_press = hold_tap(&mut evaluator, HoldTapConfig::HoldOnOtherKeyPress, 0);
+    assert_eq!(
+        roll(&mut evaluator, hold_on_press, a),
+        vec![vec![LShift], vec![LShift, A], vec![LShift], vec![]]
+    );
+
+    // 他のキーを押したまま離したら、permissiveでもタップ
+    let mut reporter = RecordingReporter::new();
+    evaluator.eval(Pressed(permissive), &mut reporter);
+    evaluator.eval(Pressed(a), &mut reporter);
+    evaluator.eval(Released(permissive), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    evaluator.eval(Released(a), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 4);
+    assert_eq!(changes(&reporter), vec![vec![Space], vec![Space, A], vec![A], vec![]]);
+}
+
+#[test]
+fn balanced_holds_after_half_timeout() {
+    let mut evaluator = Evaluator::new();
+    let a = switch(&mut evaluator, k(A));
+    let balanced = hold_tap(&mut evaluator, HoldTapConfig::Balanced, 0);
+
+    let mut reporter = RecordingReporter::new();
+    evaluator.eval(Pressed(balanced), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 12);
+    evaluator.eval(Pressed(a), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 1);
+    assert_eq!(reporter.last(), &[LShift, A]);
+    evaluator.eval(Released(a), &mut reporter);
+    evaluator.eval(Released(balanced), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 4);
+    assert_eq!(reporter.last(), &[]);
+}
+
+#[test]
+fn quick_tap_repeats_tap() {
+    let mut evaluator = Evaluator::new();
+    let thumb = hold_tap(&mut evaluator, HoldTapConfig::PermissiveHold, 10);
+    let mut reporter = RecordingReporter::new();
+
+    tap(&mut evaluator, &mut reporter, thumb);
+    ticks(&mut evaluator, &mut reporter, 2);
+    evaluator.eval(Pressed(thumb), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 30);
+    assert_eq!(reporter.last(), &[Space]);
+    evaluator.eval(Released(thumb), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+
+    // 間が空いたら、普通のホールドタップ
+    ticks(&mut evaluator, &mut reporter, 20);
+    evaluator.eval(Pressed(thumb), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 30);
+    assert_eq!(reporter.last(), &[LShift]);
+}
+
+#[test]
+fn macro_plays_over_ticks_alongside_other_keys() {
+    use makbe_ff::macros::MacroStep;
+
+    let mut evaluator = Evaluator::new();
+    let hello = switch(&mut evaluator, Action::Macro(leak([MacroStep::Text("ab")])));
+    let c = switch(&mut evaluator, k(C));
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(hello), &mut reporter);
+    evaluator.eval(Released(hello), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert!(evaluator.is_playing());
+    evaluator.eval(Pressed(c), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 4);
+    assert!(!evaluator.is_playing());
+    assert_eq!(changes(&reporter), vec![vec![A], vec![C], vec![C, B], vec![C]]);
+}
+
+#[test]
+fn macro_ending_with_press_reaches_report() {
+    use makbe_ff::macros::MacroStep;
+
+    let mut evaluator = Evaluator::new();
+    let m = switch(&mut evaluator, Action::Macro(leak([MacroStep::Press(B)])));
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(m), &mut reporter);
+    evaluator.eval(Released(m), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 3);
+    assert!(!evaluator.is_playing());
+    assert_eq!(changes(&reporter), vec![vec![B], vec![]]);
+}
+
+#[test]
+fn leader_sequences_fire_or_drop() {
+    let mut evaluator = Evaluator::new();
+    let leader = switch(&mut evaluator, Action::Leader);
+    let g = switch(&mut evaluator, k(G));
+    let s = switch(&mut evaluator, k(S));
+    let x = switch(&mut evaluator, k(X));
+    evaluator.add_leader_sequence(LeaderSequence::new(leak([G]), leak(k(F1)))).unwrap();
+    evaluator.add_leader_sequence(LeaderSequence::new(leak([G, S]), leak(k(F2)))).unwrap();
+    evaluator.set_leader_timeout(10);
+
+    // 長いシーケンスは、最後のキーで決まる
+    let mut reporter = RecordingReporter::new();
+    tap(&mut evaluator, &mut reporter, leader);
+    tap(&mut evaluator, &mut reporter, g);
+    tap(&mut evaluator, &mut reporter, s);
+    ticks(&mut evaluator, &mut reporter, 4);
+    assert!(!evaluator.is_leading());
+    assert_eq!(changes(&reporter), vec![vec![F2], vec![]]);
+
+    // 続きがありうるときは、タイムアウトで決まる
+    let mut reporter = RecordingReporter::new();
+    tap(&mut evaluator, &mut reporter, leader);
+    tap(&mut evaluator, &mut reporter, g);
+    ticks(&mut evaluator, &mut reporter, 6);
+    assert!(evaluator.is_leading());
+    ticks(&mut evaluator, &mut reporter, 4);
+    assert_eq!(changes(&reporter), vec![vec![F1], vec![]]);
+
+    // 一致しなければ何も送らない
+    let mut reporter = RecordingReporter::new();
+    tap(&mut evaluator, &mut reporter, leader);
+    tap(&mut evaluator, &mut reporter, x);
+    ticks(&mut evaluator, &mut reporter, 12);
+    assert!(!evaluator.is_leading());
+    assert!(changes(&reporter).is_empty());
+
+    let mut reporter = RecordingReporter::new();
+    tap(&mut evaluator, &mut reporter, leader);
+    ticks(&mut evaluator, &mut reporter, 12);
+    tap(&mut evaluator, &mut reporter, x);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(changes(&reporter), vec![vec![X], vec![]]);
+}
+
+/// レイヤ毎のアクションを持つスイッチ
+fn layered(evaluator: &mut Evaluator, actions: &[Action]) -> SwitchId {
+    let actions = actions.to_vec();
+    add(evaluator, KeySwitch::new(0.0, 0.0).apply(|s| {
+        for a in actions.iter() {
+            s.append_action(*a);
+        }
+        s
+    }))
+}
+
+#[test]
+fn layer_keys_do_not_add_up() {
+    use makbe_ff::layers::TriLayer;
+
+    let mut evaluator = Evaluator::new();
+    let lower = switch(&mut evaluator, l(1));
+    let raise = switch(&mut evaluator, l(2));
+    let key = layered(&mut evaluator, &[k(A), k(Kb1), k(F1), k(F12)]);
+    let trans = layered(&mut evaluator, &[k(B), k(Kb2), Action::Trans, k(Home)]);
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(lower), &mut reporter);
+    evaluator.eval(Pressed(raise), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(evaluator.layer_state(), 0b0111);
+    assert_eq!(evaluator.current_layer(), 2);
+    tap(&mut evaluator, &mut reporter, key);
+    // 透過なら、その下の有効なレイヤ
+    tap(&mut evaluator, &mut reporter, trans);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(changes(&reporter), vec![vec![F1], vec![], vec![Kb2], vec![]]);
+
+    evaluator.add_tri_layer(TriLayer::new(1, 2, 3)).unwrap();
+    assert_eq!(evaluator.current_layer(), 3);
+    evaluator.eval(Released(raise), &mut reporter);
+    evaluator.eval(Released(lower), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(evaluator.current_layer(), 0);
+}
+
+#[test]
+fn toggle_and_to_layer() {
+    let mut evaluator = Evaluator::new();
+    let toggle = switch(&mut evaluator, Action::ToggleLayer(2));
+    let lower = switch(&mut evaluator, l(1));
+    let to = layered(&mut evaluator, &[k(A), Action::ToLayer(3)]);
+    let mut reporter = RecordingReporter::new();
+
+    tap(&mut evaluator, &mut reporter, toggle);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(evaluator.current_layer(), 2);
+    tap(&mut evaluator, &mut reporter, toggle);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(evaluator.current_layer(), 0);
+
+    evaluator.layers_mut().set(2, true);
+    evaluator.eval(Pressed(lower), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(evaluator.layer_state(), 0b0111);
+    evaluator.layers_mut().set(2, false);
+    tap(&mut evaluator, &mut reporter, to);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(evaluator.layer_state(), 0b1001);
+    evaluator.eval(Released(lower), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(evaluator.current_layer(), 3);
+}
+
+#[test]
+fn momentary_toggle_by_hold_tap() {
+    let mut evaluator = Evaluator::new();
+    let tt = switch(&mut evaluator, Action::HoldTap {
+        timeout: 10,
+        hold: leak(l(1)),
+        tap: leak(Action::ToggleLayer(1)),
+        config: HoldTapConfig::TapPreferred,
+        tap_hold_interval: 0
+    });
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(tt), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 12);
+    assert_eq!(evaluator.current_layer(), 1);
+    evaluator.eval(Released(tt), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(evaluator.current_layer(), 0);
+
+    tap(&mut evaluator, &mut reporter, tt);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(evaluator.current_layer(), 1);
+}
+
+#[test]
+fn key_repeat_from_tick() {
+    let mut evaluator = Evaluator::new();
+    let a = switch(&mut evaluator, k(A));
+    let b = switch(&mut evaluator, k(B));
+    let shift = switch(&mut evaluator, k(LShift));
+    evaluator.set_key_repeat(Some(RepeatConfig::new(5, 2)));
+    evaluator.set_key_repeat_for(RepeatTarget::Switch(b), None).unwrap();
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(shift), &mut reporter);
+    evaluator.eval(Pressed(a), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 10);
+    evaluator.eval(Released(a), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    // 修飾キーはリピートしない
+    assert_eq!(
+        reporter.reports,
+        vec![
+            vec![LShift],
+            vec![LShift, A],
+            vec![LShift],
+            vec![LShift, A],
+            vec![LShift],
+            vec![LShift, A],
+            vec![LShift]
+        ]
+    );
+
+    // スイッチ毎に止められる
+    let mut reporter = RecordingReporter::new();
+    evaluator.eval(Pressed(b), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 12);
+    assert_eq!(changes(&reporter), vec![vec![LShift, B]]);
+}
+
+#[test]
+fn consumer_and_system_reports_are_separate() {
+    use makbe_ff::consumer::{ConsumerCode, SystemCode};
+
+    let mut evaluator = Evaluator::new();
+    let volume = switch(&mut evaluator, Action::Consumer(ConsumerCode::VolumeUp));
+    let sleep = switch(&mut evaluator, Action::System(SystemCode::Sleep));
+    let a = switch(&mut evaluator, k(A));
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(volume), &mut reporter);
+    evaluator.eval(Pressed(a), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 2);
+    assert_eq!(reporter.last(), &[A]);
+    assert_eq!(reporter.consumer_reports.last().unwrap(), &vec![ConsumerCode::VolumeUp]);
+    assert_eq!(ConsumerCode::VolumeUp.usage(), 0xE9);
+    evaluator.eval(Released(volume), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 1);
+    assert!(reporter.consumer_reports.last().unwrap().is_empty());
+
+    evaluator.eval(Pressed(sleep), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 1);
+    assert_eq!(reporter.system_reports.last(), Some(&Some(SystemCode::Sleep)));
+    evaluator.eval(Released(sleep), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 1);
+    assert_eq!(reporter.system_reports.last(), Some(&None));
+    assert_eq!(evaluator.report().keys, [A]);
+}
+
+#[test]
+fn mouse_keys_click_and_move() {
+    use makbe_ff::mouse::{MouseButton, MouseConfig, MouseDirection};
+
+    let mut evaluator = Evaluator::new();
+    let click = switch(&mut evaluator, Action::MouseButton(MouseButton::Left));
+    let right = switch(&mut evaluator, Action::MouseMove(MouseDirection::Right));
+    evaluator.set_mouse_config(MouseConfig { interval: 1, move_delta: 3, max_speed: 3, ..MouseConfig::default() });
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(click), &mut reporter);
+    evaluator.eval(Pressed(right), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 3);
+    evaluator.eval(Released(right), &mut reporter);
+    evaluator.eval(Released(click), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 3);
+
+    let moves: Vec<(u8, i8)> = reporter.mouse_reports.iter().map(|r| (r.buttons, r.x)).collect();
+    // 動かないときは、ボタンが変わったときだけ
+    assert_eq!(moves, vec![(1, 0), (1, 3), (1, 3), (0, 0)]);
+}
+
+#[test]
+fn actions_can_be_changed_at_runtime() {
+    let mut evaluator = Evaluator::new();
+    let a = switch(&mut evaluator, k(A));
+    let mut reporter = RecordingReporter::new();
+
+    tap(&mut evaluator, &mut reporter, a);
+    evaluator.switches_mut().get_mut(a).unwrap().actions[0] = k(B);
+    tap(&mut evaluator, &mut reporter, a);
+    assert_eq!(changes(&reporter), vec![vec![A], vec![], vec![B], vec![]]);
+}
+
+#[test]
+fn stack_overflow_during_tap_dance() {
+    let mut evaluator = Evaluator::new();
+    let other = switch(&mut evaluator, k(A));
+    let shift_space = Action::HoldTap {
+        timeout: 20,
+        hold: leak(k(LShift)),
+        tap: leak(k(Space)),
+        config: HoldTapConfig::TapPreferred,
+        tap_hold_interval: 0
+    };
+    let dance = switch(&mut evaluator, Action::TapDance {
+        timeout: 10,
+        taps: leak([shift_space, k(CapsLock)]),
+        holds: leak([shift_space, l(1)])
+    });
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(dance), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 1);
+    // tickなしでイベントが溢れたら、その時点でタップダンスもホールドタップも決める
+    for _ in 0..9 {
+        evaluator.eval(Pressed(other), &mut reporter);
+        evaluator.eval(Released(other), &mut reporter);
+    }
+    evaluator.eval(Released(dance), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 40);
+    assert_eq!(changes(&reporter)[0], vec![LShift, A]);
+    assert_eq!(reporter.last(), &[]);
+}
+
+#[test]
+fn report_errors_are_kept() {
+    let mut evaluator = Evaluator::new();
+    let a = switch(&mut evaluator, k(A));
+    let mut reporter = RecordingReporter::new();
+    reporter.disconnected = true;
+
+    evaluator.eval(Pressed(a), &mut reporter);
+    ticks(&mut evaluator, &mut reporter, 1);
+    assert_eq!(evaluator.report_error(), Some(ReportError::Disconnected));
+
+    reporter.disconnected = false;
+    ticks(&mut evaluator, &mut reporter, 1);
+    assert_eq!(evaluator.report_error(), None);
+    assert_eq!(changes(&reporter), vec![vec![A]]);
+}
@@ -0,0 +1,60 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use keyberon::key_code::KeyCode::*;
+use makbe_ff::action::k;
+use makbe_ff::evaluator::Evaluator;
+use makbe_ff::event::KeyEvent::Pressed;
+use makbe_ff::key_switch::{KeySwitch, SwitchId};
+use makbe_ff::keyboard::{BootReport, KeyboardProtocol, KeyboardReport, NkroReport};
+use makbe_ff::mock::RecordingReporter;
+
+#[test]
+fn boot_report_rolls_over_after_six_keys() {
+    let report = BootReport::new(&[LShift, A, B, C]);
+    assert_eq!(report.bytes, [0x02, 0, 0x04, 0x05, 0x06, 0, 0, 0]);
+
+    let report = BootReport::new(&[RCtrl, A, B, C, D, E, F, G]);
+    assert_eq!(report.bytes, [0x10, 0, 1, 1, 1, 1, 1, 1]);
+}
+
+#[test]
+fn nkro_report_has_a_bit_per_key() {
+    let report = NkroReport::new(&[LShift, A, B, C, D, E, F, G, Lang1]);
+    assert_eq!(report.bytes[0], 0x02);
+    assert_eq!(report.bytes[1], 0xF0);
+    assert_eq!(report.bytes[2], 0x07);
+    assert!(report.is_pressed(G));
+    assert!(report.is_pressed(Lang1));
+    assert!(!report.is_pressed(H));
+}
+
+#[test]
+fn evaluator_builds_report_for_negotiated_protocol() {
+    let mut evaluator = Evaluator::new();
+    let switches: Vec<SwitchId> = [A, B, C, D, E, F, G].iter().map(|kc| {
+        evaluator.switches_mut().add(KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(k(*kc)))).unwrap()
+    }).collect();
+    let mut reporter = RecordingReporter::new();
+    for s in switches {
+        evaluator.eval(Pressed(s), &mut reporter);
+        evaluator.tick(&mut reporter);
+    }
+
+    match evaluator.keyboard_report(KeyboardProtocol::Boot) {
+        KeyboardReport::Boot(r) => assert_eq!(r.bytes[2..], [1; 6]),
+        _ => panic!()
+    }
+    let nkro = evaluator.keyboard_report(KeyboardProtocol::Nkro);
+    assert_eq!(nkro.as_bytes().len(), 31);
+    assert_eq!(&nkro.as_bytes()[1..3], &[0xF0, 0x07]);
+
+    // プロトコルが変わったら、同じ内容を送り直す
+    let sent = reporter.reports.len();
+    evaluator.tick(&mut reporter);
+    assert_eq!(reporter.reports.len(), sent);
+    evaluator.resend(&mut reporter).unwrap();
+    assert_eq!(reporter.reports.len(), sent + 1);
+    assert_eq!(reporter.last(), &[A, B, C, D, E, F, G]);
+}
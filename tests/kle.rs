@@ -0,0 +1,88 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use makbe_ff::json::{self, Value};
+use makbe_ff::key_switch::{Position, Shape};
+use makbe_ff::kle::{self, KleError, KleKey};
+
+/// 中心の座標と幅、高さ
+fn rect(key: &KleKey) -> (f32, f32, f32, f32) {
+    let p = &key.position;
+    let v = |i: i32| i as f32 / 256.0;
+    (v(p.x), v(p.y), v(p.w), v(p.h))
+}
+
+#[test]
+fn lenient_json() {
+    let value = json::parse("{a: 1, 'b': [true, null, \"\\u3042\\n\",], // コメント\n c: -1.5e1,}").unwrap();
+    assert_eq!(value.get("a"), Some(&Value::Number(1.0)));
+    assert_eq!(
+        value.get("b"),
+        Some(&Value::Array(vec![Value::Bool(true), Value::Null, Value::String("あ\n".to_string())]))
+    );
+    assert_eq!(value.get("c").and_then(Value::as_f64), Some(-15.0));
+
+    assert_eq!(json::parse("[1], [2]").unwrap().as_array().map(|a| a.len()), Some(2));
+    assert_eq!(json::parse("[1 2]").unwrap_err().offset, 3);
+    assert!(json::parse("\"abc").is_err());
+}
+
+#[test]
+fn string_escapes() {
+    assert_eq!(json::parse(r#""\uD83D\uDE00\/\'\"""#), Ok(Value::String("😀/'\"".to_string())));
+    // 知らないエスケープや、対になっていないサロゲートは読まない
+    assert_eq!(json::parse(r#""a\q""#).unwrap_err().offset, 3);
+    assert!(json::parse(r#""\uD83D""#).is_err());
+    assert!(json::parse(r#""\uD83Dx""#).is_err());
+    assert!(json::parse(r#""\uDE00""#).is_err());
+    assert!(json::parse(r#""\u+3042""#).is_err());
+}
+
+#[test]
+fn keys_advance_like_a_turtle() {
+    let keys = kle::parse(r#"[{x:0.5},"A",{w:1.5},"B","C"],
+[{y:0.5},"D\n\n\nd"]"#).unwrap();
+
+    let labels: Vec<&str> = keys.iter().map(|k| k.label.as_str()).collect();
+    assert_eq!(labels, ["A", "B", "C", "D\n\n\nd"]);
+    assert_eq!(rect(&keys[0]), (1.0, 0.5, 1.0, 1.0));
+    assert_eq!(rect(&keys[1]), (2.25, 0.5, 1.5, 1.0));
+    // 幅は次のキーには引き継がない
+    assert_eq!(rect(&keys[2]), (3.5, 0.5, 1.0, 1.0));
+    // 行が変わると左端に戻る
+    assert_eq!(rect(&keys[3]), (0.5, 2.0, 1.0, 1.0));
+    assert_eq!(keys[3].labels().collect::<Vec<_>>(), ["D", "", "", "d"]);
+}
+
+#[test]
+fn iso_enter_and_metadata() {
+    let keys = kle::parse(r#"[{name: "iso"}, ["Tab", {x:0.25,w:1.25,h:2,w2:1.5,h2:1,x2:-0.25}, "Enter"], [{w:2}, "Shift"]]"#).unwrap();
+
+    assert_eq!(keys.len(), 3);
+    assert_eq!(keys[1].shape, Shape::IsoEnter);
+    assert_eq!(rect(&keys[1]), (1.875, 1.0, 1.25, 2.0));
+    assert_eq!(keys[2].shape, Shape::Rectangle);
+    let switch = keys[2].to_switch();
+    assert_eq!(switch.position, keys[2].position);
+    assert!(switch.actions.is_empty());
+}
+
+#[test]
+fn rotation_moves_the_origin() {
+    let keys = kle::parse(r#"["A"],
+[{r:15,rx:1,ry:2,y:-1},"R"],
+["S"]"#).unwrap();
+
+    assert_eq!(keys[0].position, Position::new(0.5, 0.5, 1.0, 1.0, 0.0, 0.0, 0.0));
+    // 回転の中心が原点になって、行が変わると中心のxに戻る（回転は反時計回りにする）
+    assert_eq!(keys[1].position, Position::new(1.5, 1.5, 1.0, 1.0, -15.0, 1.0, 2.0));
+    assert_eq!(keys[2].position, Position::new(1.5, 2.5, 1.0, 1.0, -15.0, 1.0, 2.0));
+}
+
+#[test]
+fn rejects_what_is_not_kle() {
+    assert!(matches!(kle::parse("[[1]]"), Err(KleError::Format(_))));
+    assert!(matches!(kle::parse("{x: 1}"), Err(KleError::Format(_))));
+    assert!(matches!(kle::parse("[\"A"), Err(KleError::Parse(_))));
+}
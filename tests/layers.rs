@@ -0,0 +1,34 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use makbe_ff::layers::{LayerState, TriLayer};
+
+#[test]
+fn highest_active_layer_wins() {
+    let mut layers = LayerState::new();
+    assert_eq!(layers.active(0), 0b0001);
+    assert_eq!(LayerState::highest(layers.active(0b0110)), 2);
+
+    layers.toggle(3);
+    assert_eq!(LayerState::highest(layers.active(0b0110)), 3);
+    layers.toggle(3);
+    assert_eq!(layers.toggled(), 0);
+
+    layers.set(1, true);
+    layers.set(2, true);
+    layers.move_to(1);
+    assert_eq!(layers.active(0), 0b0011);
+
+    layers.set_default_layer(2);
+    assert_eq!(layers.active(0), 0b0110);
+}
+
+#[test]
+fn tri_layer_needs_both_layers() {
+    let mut layers = LayerState::new();
+    layers.add_tri_layer(TriLayer::new(1, 2, 3)).unwrap();
+    assert_eq!(layers.active(0b0010), 0b0011);
+    assert_eq!(layers.active(0b0110), 0b1111);
+    assert_eq!(LayerState::highest(layers.active(0b0110)), 3);
+}
@@ -0,0 +1,73 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use keyberon::key_code::KeyCode;
+use keyberon::key_code::KeyCode::*;
+use makbe_ff::macros::{MacroPlayer, MacroStep, TextLayout};
+
+fn play(steps: &'static [MacroStep], layout: TextLayout) -> Vec<Vec<KeyCode>> {
+    let mut player = MacroPlayer::new(steps);
+    let mut reports = Vec::new();
+    while !player.is_finished() {
+        player.tick(layout);
+        reports.push(player.keys().to_vec());
+    }
+    reports
+}
+
+#[test]
+fn text_is_typed_with_shift_where_needed() {
+    assert_eq!(
+        play(&[MacroStep::Text("Hi!")], TextLayout::Us),
+        vec![vec![LShift, H], vec![], vec![I], vec![], vec![LShift, Kb1], vec![]]
+    );
+    // 同じ文字が続いても、間で離す
+    assert_eq!(
+        play(&[MacroStep::Text("oo")], TextLayout::Us),
+        vec![vec![O], vec![], vec![O], vec![]]
+    );
+}
+
+#[test]
+fn symbols_depend_on_layout() {
+    assert_eq!(TextLayout::Us.to_keycode(b'@'), Some((Kb2, true)));
+    assert_eq!(TextLayout::Jis.to_keycode(b'@'), Some((LBracket, false)));
+    assert_eq!(TextLayout::Us.to_keycode(b':'), Some((SColon, true)));
+    assert_eq!(TextLayout::Jis.to_keycode(b':'), Some((Quote, false)));
+    assert_eq!(TextLayout::Jis.to_keycode(0x80), None);
+}
+
+#[test]
+fn press_release_and_wait() {
+    static STEPS: [MacroStep; 5] = [
+        MacroStep::Press(LCtrl),
+        MacroStep::Tap(C),
+        MacroStep::Release(LCtrl),
+        MacroStep::Wait(2),
+        MacroStep::Tap(V)
+    ];
+    assert_eq!(
+        play(&STEPS, TextLayout::Us),
+        vec![
+            vec![LCtrl],
+            vec![LCtrl, C],
+            vec![LCtrl],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![V],
+            vec![]
+        ]
+    );
+}
+
+#[test]
+fn last_press_is_reported_before_finishing() {
+    static STEPS: [MacroStep; 2] = [MacroStep::Tap(A), MacroStep::Press(LShift)];
+    assert_eq!(
+        play(&STEPS, TextLayout::Us),
+        vec![vec![A], vec![], vec![LShift], vec![]]
+    );
+}
@@ -0,0 +1,70 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use makbe_ff::mouse::{MouseConfig, MouseCurve, MouseDirection, MouseKeys};
+
+fn config(curve: MouseCurve) -> MouseConfig {
+    MouseConfig {
+        interval: 2,
+        move_delta: 2,
+        max_speed: 12,
+        time_to_max: 10,
+        curve,
+        wheel_interval: 3,
+        wheel_delta: 1
+    }
+}
+
+#[test]
+fn speed_follows_curve() {
+    let linear = MouseKeys::new(config(MouseCurve::Linear));
+    assert_eq!(linear.speed(0), 2);
+    assert_eq!(linear.speed(5), 7);
+    assert_eq!(linear.speed(10), 12);
+    assert_eq!(linear.speed(1000), 12);
+
+    let quadratic = MouseKeys::new(config(MouseCurve::Quadratic));
+    assert_eq!(quadratic.speed(5), 4);
+    assert_eq!(quadratic.speed(10), 12);
+}
+
+#[test]
+fn movement_is_sent_every_interval() {
+    let mut mouse = MouseKeys::new(config(MouseCurve::Linear));
+    let up_left = MouseDirection::Up.bit() | MouseDirection::Left.bit();
+
+    let xs: Vec<(i8, i8)> = (0..5).map(|_| {
+        let r = mouse.tick(up_left);
+        (r.x, r.y)
+    }).collect();
+    assert_eq!(xs, vec![(-2, -2), (0, 0), (-4, -4), (0, 0), (-6, -6)]);
+
+    // 離したら最初から
+    assert_eq!(mouse.tick(0).x, 0);
+    assert_eq!(mouse.tick(MouseDirection::Right.bit()).x, 2);
+}
+
+#[test]
+fn wheel_has_its_own_interval() {
+    let mut mouse = MouseKeys::new(config(MouseCurve::Linear));
+    let wheels: Vec<(i8, i8)> = (0..4).map(|_| {
+        let r = mouse.tick(MouseDirection::WheelDown.bit() | MouseDirection::WheelRight.bit());
+        (r.wheel, r.pan)
+    }).collect();
+    assert_eq!(wheels, vec![(-1, 1), (0, 0), (0, 0), (-1, 1)]);
+}
+
+#[test]
+fn large_configs_do_not_overflow() {
+    let slow = MouseKeys::new(MouseConfig { time_to_max: 60000, max_speed: 255, ..config(MouseCurve::Quadratic) });
+    assert_eq!(slow.speed(30000), 2 + 253 / 4);
+    assert_eq!(slow.speed(59999), 254);
+
+    // 127より速くても、向きは変わらない
+    let mut fast = MouseKeys::new(MouseConfig { max_speed: 200, time_to_max: 1, ..config(MouseCurve::Linear) });
+    fast.tick(MouseDirection::Left.bit());
+    fast.tick(MouseDirection::Left.bit());
+    let r = fast.tick(MouseDirection::Left.bit() | MouseDirection::Down.bit());
+    assert_eq!((r.x, r.y), (-128, 127));
+}
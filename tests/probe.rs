@@ -0,0 +1,67 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use makbe_ff::device::DeviceHolder;
+use makbe_ff::devices::tca9554::TCA9554;
+use makbe_ff::devices::tca9555::TCA9555;
+use makbe_ff::evaluator::Evaluator;
+use makbe_ff::health::HealthEvent::{Offline, Online, Unknown};
+use makbe_ff::mock::{MockBus, MockError, RecordingReporter, SimCounter, SimExpander};
+use makbe_ff::probe::{probe_range, AddressSet, EXPANDER_ADDRESSES};
+use makbe_ff::scanner::Scanner;
+
+#[test]
+fn address_set_keeps_7bit_addresses() {
+    let mut set = AddressSet::new();
+    assert!(set.is_empty());
+    set.insert(0x20);
+    set.insert(0x7F);
+    set.insert(0x00);
+    assert!(set.contains(0x20) && set.contains(0x7F) && set.contains(0x00));
+    set.remove(0x00);
+    assert_eq!(set.iter().collect::<Vec<u8>>(), vec![0x20, 0x7F]);
+}
+
+#[test]
+fn probe_range_finds_attached_devices() {
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9555(0x21));
+    bus.attach(SimExpander::tca9554(0x27));
+    bus.attach(SimCounter::new(0x30, 2));
+
+    let found = probe_range(&mut bus, EXPANDER_ADDRESSES);
+    assert_eq!(found.iter().collect::<Vec<u8>>(), vec![0x21, 0x27]);
+}
+
+#[test]
+fn enumerate_reconciles_declared_layout() {
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9555(0x20));
+    bus.attach(SimExpander::tca9555(0x22));
+    let mut holder: DeviceHolder<MockBus, MockError> = DeviceHolder::new();
+    holder.devices.push(Box::leak(Box::new(TCA9555::new(0x0, 1)))).ok().unwrap();
+    holder.devices.push(Box::leak(Box::new(TCA9554::new(0x1, 1)))).ok().unwrap();
+    assert_eq!(holder.index_of(0x21), Some(1));
+    let mut scanner = Scanner::new(Evaluator::new());
+    let mut reporter = RecordingReporter::new();
+
+    let events = scanner.enumerate(&mut bus, &holder, &[EXPANDER_ADDRESSES], &mut reporter);
+    assert_eq!(&events.buffer[..], &[Offline(1), Unknown(0x22)]);
+    assert!(scanner.health(0).online);
+    assert!(!scanner.health(1).online);
+
+    // 挿したら初期化される
+    let mut sim = SimExpander::tca9554(0x21);
+    sim.set_levels(0x0000);
+    bus.attach(sim);
+    bus.detach(0x22);
+    let events = scanner.enumerate(&mut bus, &holder, &[EXPANDER_ADDRESSES], &mut reporter);
+    assert_eq!(&events.buffer[..], &[Online(1)]);
+    assert_eq!(bus.expander(0x21).unwrap().config(), 0x00FF);
+
+    // 抜いたらオフライン
+    bus.detach(0x20);
+    let events = scanner.enumerate(&mut bus, &holder, &[], &mut reporter);
+    assert_eq!(&events.buffer[..], &[Offline(0)]);
+}
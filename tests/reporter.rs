@@ -0,0 +1,109 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use keyberon::key_code::KeyCode;
+use keyberon::key_code::KeyCode::*;
+use makbe_ff::mock::RecordingReporter;
+use makbe_ff::reporter::{Report, ReportError, ReportQueue, REPORT_QUEUE_SIZE};
+
+fn report(keys: &[KeyCode]) -> Report {
+    let mut report = Report::new();
+    for kc in keys {
+        report.keys.push(*kc).unwrap();
+    }
+    report
+}
+
+#[test]
+fn only_changed_reports_are_sent() {
+    let mut queue = ReportQueue::new();
+    let mut reporter = RecordingReporter::new();
+
+    queue.push(report(&[]));
+    queue.push(report(&[A]));
+    queue.push(report(&[A]));
+    queue.flush(&mut reporter).unwrap();
+    queue.push(report(&[A]));
+    queue.push(report(&[]));
+    queue.flush(&mut reporter).unwrap();
+    assert_eq!(reporter.reports, vec![vec![A], vec![]]);
+    // キーボード以外は変わっていないので呼ばれない
+    assert!(reporter.consumer_reports.is_empty());
+    assert!(reporter.mouse_reports.is_empty());
+}
+
+#[test]
+fn busy_reporter_keeps_press_and_release() {
+    let mut queue = ReportQueue::new();
+    let mut reporter = RecordingReporter::new();
+    reporter.busy = 2;
+
+    queue.push(report(&[A]));
+    assert_eq!(queue.flush(&mut reporter), Err(nb::Error::WouldBlock));
+    queue.push(report(&[]));
+    queue.push(report(&[B]));
+    assert_eq!(queue.flush(&mut reporter), Err(nb::Error::WouldBlock));
+    assert_eq!(queue.len(), 3);
+    queue.flush(&mut reporter).unwrap();
+    assert!(queue.is_empty());
+    assert_eq!(reporter.reports, vec![vec![A], vec![], vec![B]]);
+}
+
+#[test]
+fn full_queue_keeps_queued_transitions() {
+    let mut queue = ReportQueue::new();
+    let mut reporter = RecordingReporter::new();
+    reporter.busy = 1;
+
+    let keys = [A, B, C, D, E, F, G, H, I, J];
+    for kc in keys.iter() {
+        queue.push(report(&[*kc]));
+    }
+    assert_eq!(queue.flush(&mut reporter), Err(nb::Error::WouldBlock));
+    assert_eq!(queue.len(), REPORT_QUEUE_SIZE);
+
+    // 積んであるものは残して、最新の状態を最後に送る
+    queue.flush(&mut reporter).unwrap();
+    assert_eq!(reporter.reports, vec![vec![A], vec![B], vec![C], vec![D], vec![E], vec![F], vec![G], vec![H], vec![J]]);
+}
+
+#[test]
+fn full_queue_merges_only_without_losing_keystrokes() {
+    let mut queue = ReportQueue::new();
+    let mut reporter = RecordingReporter::new();
+    reporter.busy = 1;
+
+    for kc in [A, B, C, D].iter() {
+        queue.push(report(&[*kc]));
+        queue.push(report(&[]));
+    }
+    // Dを離したのは消えないので、まとめられる
+    queue.push(report(&[E]));
+    assert_eq!(queue.len(), REPORT_QUEUE_SIZE);
+    // Eを押したのが消えるので、まとめない
+    queue.push(report(&[]));
+    assert_eq!(queue.flush(&mut reporter), Err(nb::Error::WouldBlock));
+    queue.flush(&mut reporter).unwrap();
+    assert_eq!(
+        reporter.reports,
+        vec![vec![A], vec![], vec![B], vec![], vec![C], vec![], vec![D], vec![E], vec![]]
+    );
+}
+
+#[test]
+fn disconnected_host_gets_only_the_current_state() {
+    let mut queue = ReportQueue::new();
+    let mut reporter = RecordingReporter::new();
+    reporter.disconnected = true;
+
+    queue.push(report(&[A]));
+    queue.push(report(&[A, B]));
+    queue.push(report(&[B]));
+    assert_eq!(queue.flush(&mut reporter), Err(nb::Error::Other(ReportError::Disconnected)));
+    assert_eq!(queue.len(), 1);
+
+    reporter.disconnected = false;
+    queue.flush(&mut reporter).unwrap();
+    assert_eq!(reporter.reports, vec![vec![B]]);
+}
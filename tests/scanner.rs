@@ -0,0 +1,269 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use makbe_ff::action::k;
+use keyberon::key_code::KeyCode;
+use makbe_ff::device::{Device, DeviceHolder};
+use makbe_ff::devices::tca9554::TCA9554;
+use makbe_ff::devices::tca9555::TCA9555;
+use makbe_ff::evaluator::Evaluator;
+use makbe_ff::health::HealthEvent;
+use makbe_ff::key_switch::{KeySwitch, SwitchId};
+use makbe_ff::mock::{MockBus, MockError, RecordingReporter, SimExpander};
+use makbe_ff::scanner::Scanner;
+
+fn switch(evaluator: &mut Evaluator, kc: KeyCode) -> SwitchId {
+    evaluator.switches_mut().add(KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(k(kc)))).unwrap()
+}
+
+fn leak<T>(t: T) -> &'static T {
+    Box::leak(Box::new(t))
+}
+
+/// 1ms進めて、スキャンとtickを1回ずつ
+fn cycle(scanner: &mut Scanner<MockBus, MockError>, bus: &mut MockBus, holder: &DeviceHolder<MockBus, MockError>, now: &mut u32, reporter: &mut RecordingReporter) {
+    *now += 1;
+    scanner.scan(bus, holder, *now, reporter);
+    scanner.tick(reporter);
+}
+
+#[test]
+fn init_device_configures_all_pins_as_input() {
+    let mut bus = MockBus::new();
+    let mut sim = SimExpander::tca9555(0x21);
+    sim.set_levels(0x0000);
+    bus.attach(sim);
+    let device: TCA9555<MockBus, MockError> = TCA9555::new(0x1, 2);
+
+    device.init_device(&mut bus).unwrap();
+    assert_eq!(bus.expander(0x21).unwrap().config(), 0xFFFF);
+
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9554(0x22));
+    let device: TCA9554<MockBus, MockError> = TCA9554::new(0x2, 2);
+
+    device.init_device(&mut bus).unwrap();
+    assert_eq!(bus.expander(0x22).unwrap().config(), 0x00FF);
+}
+
+#[test]
+fn missing_device_is_reported_as_nack() {
+    let mut bus = MockBus::new();
+    let device: TCA9555<MockBus, MockError> = TCA9555::new(0x3, 2);
+
+    assert_eq!(device.read_device(&mut bus).err(), Some(MockError::Nack(0x23)));
+}
+
+#[test]
+fn press_and_release_reach_reporter() {
+    let mut evaluator = Evaluator::new();
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9555(0x20));
+    let mut device = TCA9555::new(0x0, 2);
+    device.assign(9, switch(&mut evaluator, KeyCode::A)).unwrap();
+    let mut holder = DeviceHolder::new();
+    holder.devices.push(leak(device)).ok().unwrap();
+    let mut scanner = Scanner::new(evaluator);
+    let mut reporter = RecordingReporter::new();
+    let mut now = 0_u32;
+
+    for _ in 0..4 {
+        cycle(&mut scanner, &mut bus, &holder, &mut now, &mut reporter);
+    }
+    assert_eq!(reporter.last(), &[]);
+
+    bus.expander_mut(0x20).unwrap().press(9);
+    for _ in 0..4 {
+        cycle(&mut scanner, &mut bus, &holder, &mut now, &mut reporter);
+    }
+    assert_eq!(reporter.last(), &[KeyCode::A]);
+
+    bus.expander_mut(0x20).unwrap().release(9);
+    for _ in 0..4 {
+        cycle(&mut scanner, &mut bus, &holder, &mut now, &mut reporter);
+    }
+    assert_eq!(reporter.changes(), vec![vec![KeyCode::A], vec![]]);
+}
+
+#[test]
+fn chattering_is_filtered_by_debouncer() {
+    let mut evaluator = Evaluator::new();
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9554(0x20));
+    let mut device = TCA9554::new(0x0, 2);
+    device.assign(3, switch(&mut evaluator, KeyCode::B)).unwrap();
+    let mut holder = DeviceHolder::new();
+    holder.devices.push(leak(device)).ok().unwrap();
+    let mut scanner = Scanner::new(evaluator);
+    let mut reporter = RecordingReporter::new();
+    let mut now = 0_u32;
+
+    for _ in 0..4 {
+        cycle(&mut scanner, &mut bus, &holder, &mut now, &mut reporter);
+    }
+    bus.expander_mut(0x20).unwrap().bounce(3, 5, true);
+    for _ in 0..5 {
+        cycle(&mut scanner, &mut bus, &holder, &mut now, &mut reporter);
+        assert_eq!(reporter.last(), &[]);
+    }
+    for _ in 0..4 {
+        cycle(&mut scanner, &mut bus, &holder, &mut now, &mut reporter);
+    }
+    assert_eq!(reporter.changes(), vec![vec![KeyCode::B]]);
+}
+
+#[test]
+fn unplugged_device_releases_keys_and_recovers() {
+    let mut evaluator = Evaluator::new();
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9555(0x20));
+    let mut device = TCA9555::new(0x0, 1);
+    device.assign(2, switch(&mut evaluator, KeyCode::C)).unwrap();
+    let mut holder = DeviceHolder::new();
+    holder.devices.push(leak(device)).ok().unwrap();
+    let mut scanner = Scanner::new(evaluator);
+    scanner.set_failure_limit(2);
+    let mut reporter = RecordingReporter::new();
+    let mut now = 0_u32;
+
+    bus.expander_mut(0x20).unwrap().press(2);
+    for _ in 0..4 {
+        cycle(&mut scanner, &mut bus, &holder, &mut now, &mut reporter);
+    }
+    assert_eq!(reporter.last(), &[KeyCode::C]);
+
+    bus.detach(0x20);
+    assert!(scanner.scan(&mut bus, &holder, now, &mut reporter).buffer.is_empty());
+    assert!(scanner.health(0).online);
+    let events = scanner.scan(&mut bus, &holder, now, &mut reporter);
+    assert_eq!(&events.buffer[..], &[HealthEvent::Offline(0)]);
+    assert!(!scanner.health(0).online);
+    scanner.tick(&mut reporter);
+    assert_eq!(reporter.last(), &[]);
+
+    // 戻ってきたら初期化し直す
+    let mut sim = SimExpander::tca9555(0x20);
+    sim.set_levels(0xFFFF);
+    bus.attach(sim);
+    bus.expander_mut(0x20).unwrap().press(2);
+    let events = scanner.scan(&mut bus, &holder, now, &mut reporter);
+    assert_eq!(&events.buffer[..], &[HealthEvent::Online(0)]);
+    assert_eq!(bus.expander(0x20).unwrap().config(), 0xFFFF);
+    for _ in 0..4 {
+        cycle(&mut scanner, &mut bus, &holder, &mut now, &mut reporter);
+    }
+    assert_eq!(reporter.changes(), vec![vec![KeyCode::C], vec![], vec![KeyCode::C]]);
+}
+
+#[test]
+fn flagged_scan_reads_only_interrupted_devices() {
+    let mut evaluator = Evaluator::new();
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9555(0x20));
+    bus.attach(SimExpander::tca9555(0x21));
+    let mut holder = DeviceHolder::new();
+    for addr in 0..2 {
+        let mut device = TCA9555::new(addr, 2);
+        device.assign(0, switch(&mut evaluator, if addr == 0 { KeyCode::A } else { KeyCode::B })).unwrap();
+        holder.devices.push(leak(device)).ok().unwrap();
+    }
+    let mut scanner = Scanner::new(evaluator);
+    let mut reporter = RecordingReporter::new();
+    let mut now = 0_u32;
+
+    let flagged = |bus: &MockBus| -> Vec<usize> {
+        [0x20_u8, 0x21].iter().enumerate()
+            .filter(|(_, a)| bus.expander(**a).unwrap().interrupt())
+            .map(|(i, _)| i)
+            .collect()
+    };
+    let mut cycle = |bus: &mut MockBus, reporter: &mut RecordingReporter| {
+        let f = flagged(bus);
+        now += 1;
+        scanner.scan_flagged(bus, &holder, &f, now, reporter);
+        scanner.tick(reporter);
+    };
+
+    // 何も起きなければ読まない
+    for _ in 0..3 {
+        cycle(&mut bus, &mut reporter);
+    }
+    assert_eq!(bus.transactions, 0);
+
+    bus.expander_mut(0x21).unwrap().press(0);
+    assert!(bus.expander(0x21).unwrap().interrupt());
+    cycle(&mut bus, &mut reporter);
+    assert!(!bus.expander(0x21).unwrap().interrupt());
+    // INTは読んだら消えるけど、デバウンスが終わるまでは読み続ける
+    for _ in 0..4 {
+        cycle(&mut bus, &mut reporter);
+    }
+    assert_eq!(reporter.last(), &[KeyCode::B]);
+    assert_eq!(bus.expander(0x20).unwrap().input_reads, 0);
+    assert_eq!(bus.expander(0x21).unwrap().input_reads, 3);
+    assert!(!scanner.has_pending());
+
+    // ないデバイスのインデックスは無視する
+    scanner.scan_flagged(&mut bus, &holder, &[2, 127, 200], now, &mut reporter);
+    assert!(!scanner.has_pending());
+}
+
+#[test]
+fn enumerate_leaves_interrupts_for_flagged_scan() {
+    let mut evaluator = Evaluator::new();
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9555(0x20));
+    let mut holder = DeviceHolder::new();
+    let mut device = TCA9555::new(0, 2);
+    device.assign(0, switch(&mut evaluator, KeyCode::A)).unwrap();
+    holder.devices.push(leak(device)).ok().unwrap();
+    let mut scanner = Scanner::new(evaluator);
+    let mut reporter = RecordingReporter::new();
+
+    scanner.enumerate(&mut bus, &holder, &[0x20..=0x27], &mut reporter);
+    bus.expander_mut(0x20).unwrap().press(0);
+    // スキャンの合間にenumerateしても、INTは消さない
+    scanner.enumerate(&mut bus, &holder, &[0x20..=0x27], &mut reporter);
+    assert!(bus.expander(0x20).unwrap().interrupt());
+    assert_eq!(bus.expander(0x20).unwrap().input_reads, 0);
+
+    for now in 1..=5 {
+        let flagged: Vec<usize> = if bus.expander(0x20).unwrap().interrupt() { vec![0] } else { vec![] };
+        scanner.scan_flagged(&mut bus, &holder, &flagged, now, &mut reporter);
+        scanner.tick(&mut reporter);
+    }
+    assert_eq!(reporter.last(), &[KeyCode::A]);
+}
+
+#[test]
+fn debounce_window_is_measured_in_milliseconds() {
+    let mut evaluator = Evaluator::new();
+    let mut bus = MockBus::new();
+    bus.attach(SimExpander::tca9555(0x20));
+    let mut device = TCA9555::new(0x0, 5);
+    device.assign(1, switch(&mut evaluator, KeyCode::D)).unwrap();
+    let mut holder = DeviceHolder::new();
+    holder.devices.push(leak(device)).ok().unwrap();
+    let mut scanner = Scanner::new(evaluator);
+    let mut reporter = RecordingReporter::new();
+
+    bus.expander_mut(0x20).unwrap().press(1);
+    // 何回読んでも、時間が経たなければ確定しない
+    for _ in 0..20 {
+        scanner.scan(&mut bus, &holder, 100, &mut reporter);
+    }
+    scanner.scan(&mut bus, &holder, 104, &mut reporter);
+    scanner.tick(&mut reporter);
+    assert_eq!(reporter.last(), &[]);
+    scanner.scan(&mut bus, &holder, 105, &mut reporter);
+    scanner.tick(&mut reporter);
+    assert_eq!(reporter.last(), &[KeyCode::D]);
+
+    // 時刻が一周しても大丈夫
+    bus.expander_mut(0x20).unwrap().release(1);
+    scanner.scan(&mut bus, &holder, u32::MAX - 1, &mut reporter);
+    scanner.scan(&mut bus, &holder, 3, &mut reporter);
+    scanner.tick(&mut reporter);
+    assert_eq!(reporter.last(), &[]);
+}
@@ -0,0 +1,246 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use keyberon::key_code::KeyCode::*;
+use makbe_ff::action::{k, l, Action, HoldTapConfig};
+use makbe_ff::key_switch::KeySwitch;
+use makbe_ff::keymap::Keymap;
+use makbe_ff::storage::{KeymapStore, MemoryError, MemoryStorage, Settings, Storage, StoreError, MAX_RECORD_SIZE};
+
+const PAGE_SIZE: usize = 256;
+/// 1スロットのページ数
+const SLOT_PAGES: usize = (MAX_RECORD_SIZE + PAGE_SIZE - 1) / PAGE_SIZE;
+
+fn memory(slots: usize) -> Vec<u8> {
+    vec![0xFF; PAGE_SIZE * SLOT_PAGES * slots]
+}
+
+/// 消去した回数を数えて、決めた回数だけ書いたら電源が切れたことにする
+struct Counting<'a> {
+    inner: MemoryStorage<'a>,
+    erases: Vec<usize>,
+    writes_left: Option<usize>
+}
+
+impl Storage for Counting<'_> {
+    type Error = MemoryError;
+
+    fn page_size(&self) -> usize { self.inner.page_size() }
+
+    fn page_count(&self) -> usize { self.inner.page_count() }
+
+    fn read(&mut self, page: usize, offset: usize, buffer: &mut [u8]) -> Result<(), MemoryError> {
+        self.inner.read(page, offset, buffer)
+    }
+
+    fn write(&mut self, page: usize, offset: usize, data: &[u8]) -> Result<(), MemoryError> {
+        match self.writes_left {
+            Some(0) => Err(MemoryError::OutOfRange),
+            Some(n) => {
+                self.writes_left = Some(n - 1);
+                self.inner.write(page, offset, data)
+            }
+            None => self.inner.write(page, offset, data)
+        }
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), MemoryError> {
+        self.erases[page] += 1;
+        self.inner.erase(page)
+    }
+}
+
+#[test]
+fn saved_keymap_and_settings_are_loaded() {
+    let mut memory = memory(2);
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    let mut keymap = Keymap::default();
+    keymap.set_keycode(0, 1, 2, 0x0029);
+    keymap.set_keycode(3, 7, 15, 0x5101);
+    let settings = Settings { default_layer: 1, layout_options: 0x0102_0304 };
+
+    assert_eq!(store.save(&keymap, &settings), Ok(true));
+    // 変わっていなければ書かない
+    assert_eq!(store.save(&keymap, &settings), Ok(false));
+
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    let mut loaded = Keymap::default();
+    loaded.set_keycode(0, 0, 0, 0x0004);
+    assert_eq!(store.load(&mut loaded), Ok(settings));
+    assert_eq!(loaded.remapped().collect::<Vec<_>>(), vec![(0, 1, 2, 0x0029), (3, 7, 15, 0x5101)]);
+}
+
+#[test]
+fn empty_or_small_storage() {
+    let mut memory = memory(2);
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    let mut keymap = Keymap::default();
+    assert_eq!(store.load(&mut keymap), Err(StoreError::NotFound));
+
+    let mut memory = memory[..PAGE_SIZE * (SLOT_PAGES + 1)].to_vec();
+    assert!(matches!(KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)), Err(StoreError::TooSmall)));
+}
+
+#[test]
+fn saves_rotate_through_slots() {
+    let mut memory = memory(3);
+    let storage = Counting { inner: MemoryStorage::new(&mut memory, PAGE_SIZE), erases: vec![0; SLOT_PAGES * 3], writes_left: None };
+    let mut store = KeymapStore::new(storage).unwrap();
+    let mut keymap = Keymap::default();
+
+    for i in 0..6 {
+        keymap.set_keycode(0, 0, 0, 0x0004 + i);
+        store.save(&keymap, &Settings::default()).unwrap();
+        assert_eq!(store.current_slot(), Some(i as usize % 3));
+    }
+    // 小さいので、各スロットの先頭のページだけを2回ずつ消去している
+    let erases = &store.storage().erases;
+    for slot in 0..3 {
+        assert_eq!(erases[slot * SLOT_PAGES], 2);
+        assert_eq!(erases[slot * SLOT_PAGES + 1], 0);
+    }
+}
+
+#[test]
+fn broken_record_falls_back_to_previous() {
+    let mut memory = memory(2);
+    let storage = Counting { inner: MemoryStorage::new(&mut memory, PAGE_SIZE), erases: vec![0; SLOT_PAGES * 2], writes_left: None };
+    let mut store = KeymapStore::new(storage).unwrap();
+    let mut keymap = Keymap::default();
+    keymap.set_keycode(0, 0, 0, 0x0004);
+    store.save(&keymap, &Settings::default()).unwrap();
+
+    // 書いている途中で電源が切れた
+    store.storage_mut().writes_left = Some(0);
+    keymap.set_keycode(0, 0, 0, 0x0005);
+    assert_eq!(store.save(&keymap, &Settings::default()), Err(StoreError::Storage(MemoryError::OutOfRange)));
+
+    let storage = store.storage_mut();
+    storage.writes_left = None;
+    let mut loaded = Keymap::default();
+    let mut store = KeymapStore::new(MemoryStorage::new(storage.inner.memory_mut(), PAGE_SIZE)).unwrap();
+    store.load(&mut loaded).unwrap();
+    assert_eq!(loaded.keycode(0, 0, 0), 0x0004);
+
+    // 新しい方が壊れていても、前のものを読む
+    keymap.set_keycode(0, 0, 0, 0x0006);
+    store.save(&keymap, &Settings::default()).unwrap();
+    assert_eq!(store.current_slot(), Some(1));
+    store.storage_mut().memory_mut()[SLOT_PAGES * PAGE_SIZE + 12] ^= 0x01;
+    let mut store = KeymapStore::new(MemoryStorage::new(store.storage_mut().memory_mut(), PAGE_SIZE)).unwrap();
+    store.load(&mut loaded).unwrap();
+    assert_eq!(loaded.keycode(0, 0, 0), 0x0004);
+}
+
+/// CRC-32（IEEE 802.3）
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for b in data.iter() {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[test]
+fn sequence_wraps_around() {
+    let mut memory = memory(2);
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    let mut keymap = Keymap::default();
+    keymap.set_keycode(0, 0, 0, 0x0004);
+    store.save(&keymap, &Settings::default()).unwrap();
+
+    // 通し番号を最大値に書き換える
+    let len = u16::from_le_bytes([memory[8], memory[9]]) as usize;
+    memory[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+    let crc = crc32(&memory[..14 + len]);
+    memory[14 + len..18 + len].copy_from_slice(&crc.to_le_bytes());
+
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    let mut loaded = Keymap::default();
+    store.load(&mut loaded).unwrap();
+    keymap.set_keycode(0, 0, 0, 0x0005);
+    store.save(&keymap, &Settings::default()).unwrap();
+    assert_eq!(store.current_slot(), Some(1));
+
+    // 0に戻った方が新しい
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    store.load(&mut loaded).unwrap();
+    assert_eq!(store.current_slot(), Some(1));
+    assert_eq!(loaded.keycode(0, 0, 0), 0x0005);
+}
+
+#[test]
+fn switch_actions_are_saved() {
+    let mut memory = memory(2);
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    let hold_tap = Action::HoldTap {
+        timeout: 200,
+        hold: &Action::KeyCode(LShift),
+        tap: &Action::KeyCode(Space),
+        config: HoldTapConfig::PermissiveHold,
+        tap_hold_interval: 0
+    };
+    let mut keymap = Keymap::default();
+    let a = keymap.switches_mut().add(KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(k(A)))).unwrap();
+    let space = keymap.switches_mut().add(KeySwitch::new(1.0, 0.0).apply(|s| s.append_action(hold_tap))).unwrap();
+    store.save(&keymap, &Settings::default()).unwrap();
+
+    // 実行中に書き換えたアクション
+    let switch = keymap.switches_mut().get_mut(a).unwrap();
+    switch.actions[0] = k(B);
+    switch.append_action(l(1));
+    assert_eq!(store.save(&keymap, &Settings::default()), Ok(true));
+
+    let mut loaded = Keymap::default();
+    loaded.switches_mut().add(KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(k(A))));
+    loaded.switches_mut().add(KeySwitch::new(1.0, 0.0).apply(|s| s.append_action(hold_tap)));
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    store.load(&mut loaded).unwrap();
+    assert_eq!(&loaded.switches().get(a).unwrap().actions[..], &[k(B), l(1)]);
+    // キーコードで表せないものは、ファームウェアのまま
+    assert_eq!(&loaded.switches().get(space).unwrap().actions[..], &[hold_tap]);
+}
+
+#[test]
+fn only_changed_switch_actions_are_saved() {
+    let mut memory = memory(2);
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    let mut keymap = Keymap::default();
+    keymap.switches_mut().add(KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(k(A)).append_action(k(F1))));
+    let b = keymap.switches_mut().add(KeySwitch::new(1.0, 0.0).apply(|s| s.append_action(k(B)))).unwrap();
+    keymap.switches_mut().get_mut(b).unwrap().actions[0] = k(C);
+    store.save(&keymap, &Settings::default()).unwrap();
+
+    // 設定、書き換えたキーの数、スイッチのアクションの数と、書き換えた1つだけ
+    assert_eq!(u16::from_le_bytes([memory[8], memory[9]]), 7 + 2 + 5);
+}
+
+#[test]
+fn switch_actions_of_another_layout_are_ignored() {
+    let mut memory = memory(2);
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    let mut keymap = Keymap::default();
+    let a = keymap.switches_mut().add(KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(k(A)))).unwrap();
+    keymap.switches_mut().add(KeySwitch::new(1.0, 0.0).apply(|s| s.append_action(k(B))));
+    keymap.switches_mut().get_mut(a).unwrap().actions[0] = k(C);
+    keymap.set_keycode(0, 0, 0, 0x0004);
+    store.save(&keymap, &Settings::default()).unwrap();
+
+    // ファームウェアを更新して、スイッチの並びが変わった
+    let mut loaded = Keymap::default();
+    let b = loaded.switches_mut().add(KeySwitch::new(1.0, 0.0).apply(|s| s.append_action(k(B)))).unwrap();
+    let a = loaded.switches_mut().add(KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(k(A)))).unwrap();
+    let mut store = KeymapStore::new(MemoryStorage::new(&mut memory, PAGE_SIZE)).unwrap();
+    store.load(&mut loaded).unwrap();
+    assert_eq!(&loaded.switches().get(a).unwrap().actions[..], &[k(A)]);
+    assert_eq!(&loaded.switches().get(b).unwrap().actions[..], &[k(B)]);
+    // VIAで書き換えたキーは読む
+    assert_eq!(loaded.keycode(0, 0, 0), 0x0004);
+
+    // 次に保存するときは、今のレイアウトで書き直す
+    assert_eq!(store.save(&loaded, &Settings::default()), Ok(true));
+}
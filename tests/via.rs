@@ -0,0 +1,159 @@
+// Copyright 2021 Kazuyuki HIDA <kazhida@abplus.com>
+// All right reserved.
+//
+
+use makbe_ff::action::{k, l, Action};
+use keyberon::key_code::KeyCode::*;
+use makbe_ff::consumer::ConsumerCode;
+use makbe_ff::device::{Device, DeviceHolder};
+use makbe_ff::devices::tca9555::TCA9555;
+use makbe_ff::evaluator::Evaluator;
+use makbe_ff::event::KeyEvent::{Pressed, Released};
+use makbe_ff::key_switch::{KeySwitch, KeySwitches, SwitchId};
+use makbe_ff::keymap::{from_keycode, to_keycode, Keymap, VirtualMatrix, UNSUPPORTED_KEYCODE};
+use makbe_ff::mock::{MockBus, MockError, RecordingReporter};
+use makbe_ff::via::{Via, VIA_PACKET_SIZE};
+
+/// 2つのデバイスの(row, col)にスイッチを割り付けたキーマップ
+fn keymap(actions: &[(usize, usize, Action)]) -> (Keymap, Vec<SwitchId>) {
+    let mut switches = KeySwitches::new();
+    let ids: Vec<SwitchId> = actions.iter().map(|(_, _, a)| {
+        switches.add(KeySwitch::new(0.0, 0.0).apply(|s| s.append_action(*a))).unwrap()
+    }).collect();
+    let mut holder: DeviceHolder<MockBus, MockError> = DeviceHolder::new();
+    for row in 0..2 {
+        let mut device: TCA9555<MockBus, MockError> = TCA9555::new(row as u8, 2);
+        for ((r, col, _), id) in actions.iter().zip(ids.iter()) {
+            if *r == row {
+                device.assign(*col, *id).unwrap();
+            }
+        }
+        let device: &'static dyn Device<MockBus, MockError> = Box::leak(Box::new(device));
+        holder.devices.push(device).ok().unwrap();
+    }
+    (Keymap::new(switches, VirtualMatrix::from_devices(&holder)), ids)
+}
+
+fn packet(bytes: &[u8]) -> [u8; VIA_PACKET_SIZE] {
+    let mut data = [0; VIA_PACKET_SIZE];
+    data[..bytes.len()].copy_from_slice(bytes);
+    data
+}
+
+#[test]
+fn keycodes_round_trip() {
+    for action in [k(A), k(ExSel), k(LCtrl), k(RGui), Action::NoOp, Action::Trans, l(3),
+                   Action::ToggleLayer(1), Action::ToLayer(2), Action::DefaultLayer(1),
+                   Action::Consumer(ConsumerCode::VolumeUp)].iter() {
+        assert_eq!(from_keycode(to_keycode(action)), Some(*action));
+    }
+    assert_eq!(to_keycode(&k(A)), 0x0004);
+    assert_eq!(to_keycode(&l(1)), 0x5101);
+    assert_eq!(to_keycode(&Action::Macro(&[])), UNSUPPORTED_KEYCODE);
+    assert_eq!(from_keycode(0x7E00), None);
+}
+
+#[test]
+fn virtual_matrix_follows_devices_and_pins() {
+    let (keymap, ids) = keymap(&[(0, 3, k(A)), (1, 15, k(B))]);
+    let (a, b) = (ids[0], ids[1]);
+
+    assert_eq!(keymap.matrix().rows(), 2);
+    assert_eq!(keymap.matrix().position_of(a), Some((0, 3)));
+    assert_eq!(keymap.matrix().position_of(b), Some((1, 15)));
+    assert_eq!(keymap.keycode(0, 0, 3), 0x0004);
+    assert_eq!(keymap.keycode(0, 1, 15), 0x0005);
+    assert_eq!(keymap.keycode(0, 1, 0), 0x0000);
+}
+
+#[test]
+fn via_commands_get_and_set_keycodes() {
+    let (mut keymap, ids) = keymap(&[(1, 2, k(A))]);
+    let a = ids[0];
+    let mut via = Via::new();
+
+    let mut data = packet(&[0x01]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(&data[..3], &[0x01, 0x00, 0x09]);
+
+    let mut data = packet(&[0x11]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(data[1], 4);
+
+    let mut data = packet(&[0x04, 0, 1, 2]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(&data[4..6], &[0x00, 0x04]);
+
+    let mut data = packet(&[0x05, 0, 1, 2, 0x00, 0x29]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(keymap.keycode(0, 1, 2), 0x0029);
+    assert_eq!(keymap.action(a, 0), k(Escape));
+
+    // バッファではレイヤ、row、colの順に並んでいる（row 1, col 2は(16 + 2) * 2バイト目）
+    let mut data = packet(&[0x12, 0, 36, 4]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(&data[4..8], &[0x00, 0x29, 0x00, 0x00]);
+
+    let mut data = packet(&[0x13, 0, 36, 2, 0x51, 0x01]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(keymap.action(a, 0), l(1));
+
+    let mut data = packet(&[0x06]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(keymap.remapped_action(a, 0), None);
+    assert_eq!(keymap.action(a, 0), k(A));
+
+    let mut data = packet(&[0x0B]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(data[0], 0xFF);
+}
+
+#[test]
+fn via_macro_buffer_is_kept() {
+    let (mut keymap, _) = keymap(&[]);
+    let mut via = Via::new();
+
+    let mut data = packet(&[0x0D]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(&data[1..3], &[0x01, 0x00]);
+
+    let mut data = packet(&[0x0F, 0, 10, 3, b'a', b'b', 0]);
+    via.handle(&mut keymap, &mut data);
+    let mut data = packet(&[0x0E, 0, 10, 3]);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(&data[4..7], b"ab\0");
+
+    // はみ出すところは読み書きしない
+    let mut data = packet(&[0x0F, 0, 255, 28, b'x', b'y']);
+    via.handle(&mut keymap, &mut data);
+    assert_eq!(via.macro_buffer()[255], b'x');
+
+    let mut data = packet(&[0x10]);
+    via.handle(&mut keymap, &mut data);
+    assert!(via.macro_buffer().iter().all(|b| *b == 0));
+}
+
+#[test]
+fn evaluator_uses_remapped_keycodes() {
+    let (mut keymap, ids) = keymap(&[(0, 0, k(A)), (0, 1, k(B))]);
+    let (a, b) = (ids[0], ids[1]);
+    keymap.set_keycode(0, 0, 0, 0x5101);
+    keymap.set_keycode(1, 0, 1, 0x001B);
+    let mut evaluator = Evaluator::new();
+    evaluator.set_keymap(keymap);
+    let mut reporter = RecordingReporter::new();
+
+    evaluator.eval(Pressed(b), &mut reporter);
+    evaluator.tick(&mut reporter);
+    evaluator.eval(Released(b), &mut reporter);
+    evaluator.tick(&mut reporter);
+    evaluator.eval(Pressed(a), &mut reporter);
+    evaluator.tick(&mut reporter);
+    evaluator.eval(Pressed(b), &mut reporter);
+    evaluator.tick(&mut reporter);
+    evaluator.eval(Released(b), &mut reporter);
+    evaluator.tick(&mut reporter);
+    evaluator.eval(Released(a), &mut reporter);
+    evaluator.tick(&mut reporter);
+    assert_eq!(reporter.changes(), vec![vec![B], vec![], vec![X], vec![]]);
+}